@@ -0,0 +1,450 @@
+//! A harness for running several academy PoW nodes in-process and watching them fork.
+//!
+//! Each node gets its own chain spec file, base path and ports, and starts at a very low
+//! difficulty so sealing a block only takes a few hashes. The first node started acts as the
+//! bootnode for the rest.
+//!
+//! Nodes started with `--manual-seal` only author the blocks a test asks for, with the
+//! algorithm it chooses. So a test decides exactly which node extends which chain, instead of
+//! racing miners. The tests only drive and observe the nodes through their RPC, just like a
+//! user would.
+
+use std::{net::TcpListener, time::Duration};
+
+use academy_pow::{
+    chain_spec::{self, ForkingExtensions},
+    cli::Cli,
+    command,
+    double_spend::{DoubleSpendParams, DoubleSpendReport},
+    selfish::WithholdingStatus,
+};
+use academy_pow_runtime::PreDigest;
+use jsonrpsee::{
+    core::client::ClientT,
+    rpc_params,
+    ws_client::{WsClient, WsClientBuilder},
+};
+use multi_pow::SupportedHashes;
+use parity_scale_codec::Decode;
+use sc_cli::{clap::Parser, SubstrateCli};
+use sc_service::TaskManager;
+use sp_consensus_pow::POW_ENGINE_ID;
+use sp_core::H256;
+use sp_runtime::DigestItem;
+use tempfile::TempDir;
+
+/// The difficulty every algorithm starts at. Low enough that blocks come almost immediately,
+/// until the difficulty adjustment catches up.
+pub const INITIAL_DIFFICULTY: u32 = 1_000;
+
+/// How long to wait for a node to reach a height or import a block before giving up.
+pub const HEIGHT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The arguments that make a node author blocks only when a test asks it to, with seals its
+/// peers accept.
+pub const MANUAL_SEAL: &[&str] = &["--manual-seal", "--mine-seals"];
+
+/// The forks of the development chain, which the tests step through.
+pub fn dev_forks() -> ForkingExtensions {
+    chain_spec::development_forks()
+}
+
+/// The forks as seen by a node that never upgraded past the soft fork.
+/// It still accepts md5 blocks after the hard fork, and never splits.
+pub fn old_forks() -> ForkingExtensions {
+    ForkingExtensions {
+        remove_md5: u32::MAX,
+        split_sha3_keccak: u32::MAX,
+        ..dev_forks()
+    }
+}
+
+/// How to run a single node.
+pub struct NodeConfig {
+    /// A name for the node, used in its logs and in assertion messages.
+    pub name: &'static str,
+    /// The algorithm the node mines with, as given to `--mining-algo`.
+    pub mining_algo: &'static str,
+    /// The node's position at the contentious fork, as given to `--maxi-position`.
+    pub maxi_position: &'static str,
+    /// The forks in the node's chain spec.
+    pub forks: ForkingExtensions,
+    /// Any further command line arguments.
+    pub extra_args: &'static [&'static str],
+}
+
+/// A node running in this process.
+pub struct Node {
+    pub name: &'static str,
+    rpc: WsClient,
+    p2p_port: u16,
+    // Dropping these stops the node and deletes its data.
+    _task_manager: TaskManager,
+    _base_path: TempDir,
+}
+
+/// Find a port that is free right now.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("Can bind to some local port")
+        .port()
+}
+
+impl Node {
+    /// Start a node, optionally connecting to a bootnode.
+    pub async fn start(config: NodeConfig, bootnode: Option<String>) -> Node {
+        let base_path = tempfile::tempdir().expect("Can create a temporary directory");
+
+        let spec = chain_spec::development_config_with(config.forks, INITIAL_DIFFICULTY, false)
+            .expect("The development chain spec builds");
+        let spec_path = base_path.path().join("spec.json");
+        std::fs::write(
+            &spec_path,
+            spec.as_json(false).expect("The chain spec serializes"),
+        )
+        .expect("Can write the chain spec");
+
+        let p2p_port = free_port();
+        let rpc_port = free_port();
+
+        let mut args = vec![
+            "academy-pow".to_string(),
+            "--chain".to_string(),
+            spec_path.display().to_string(),
+            "--base-path".to_string(),
+            base_path.path().display().to_string(),
+            "--name".to_string(),
+            config.name.to_string(),
+            "--validator".to_string(),
+            "--database".to_string(),
+            "paritydb".to_string(),
+            "--port".to_string(),
+            p2p_port.to_string(),
+            "--rpc-port".to_string(),
+            rpc_port.to_string(),
+            "--no-mdns".to_string(),
+            "--no-prometheus".to_string(),
+            "--no-telemetry".to_string(),
+            "--mining-algo".to_string(),
+            config.mining_algo.to_string(),
+            "--maxi-position".to_string(),
+            config.maxi_position.to_string(),
+        ];
+        args.extend(config.extra_args.iter().map(|arg| arg.to_string()));
+        if let Some(bootnode) = bootnode {
+            args.push("--bootnodes".to_string());
+            args.push(bootnode);
+        }
+
+        let cli = Cli::try_parse_from(args).expect("The node arguments are valid");
+        let node_config = cli
+            .create_configuration(&cli.run, tokio::runtime::Handle::current())
+            .expect("Can create the node configuration");
+        let task_manager = command::new_full_node(&cli, node_config).expect("The node starts up");
+
+        // Some RPCs, like the double spend, only return once they have mined several blocks.
+        let rpc = WsClientBuilder::default()
+            .request_timeout(HEIGHT_TIMEOUT)
+            .build(format!("ws://127.0.0.1:{rpc_port}"))
+            .await
+            .expect("Can connect to the node's RPC");
+
+        Node {
+            name: config.name,
+            rpc,
+            p2p_port,
+            _task_manager: task_manager,
+            _base_path: base_path,
+        }
+    }
+
+    /// The address other nodes can use to connect to this one.
+    pub async fn multiaddr(&self) -> String {
+        let peer_id: String = self
+            .rpc
+            .request("system_localPeerId", rpc_params![])
+            .await
+            .expect("Can get the local peer id");
+
+        format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", self.p2p_port, peer_id)
+    }
+
+    /// The header of the given block, or of the best block if no hash is given.
+    async fn header(&self, hash: Option<H256>) -> serde_json::Value {
+        self.rpc
+            .request("chain_getHeader", rpc_params![hash])
+            .await
+            .expect("Can get a header")
+    }
+
+    /// The height of the node's best block.
+    pub async fn best_number(&self) -> u32 {
+        let header = self.header(None).await;
+        let number = header["number"].as_str().expect("Headers have a number");
+
+        u32::from_str_radix(number.trim_start_matches("0x"), 16).expect("Numbers are hex")
+    }
+
+    /// The hash of the block at the given height in the node's best chain.
+    pub async fn block_hash(&self, number: u32) -> H256 {
+        let hash: Option<H256> = self
+            .rpc
+            .request("chain_getBlockHash", rpc_params![number])
+            .await
+            .expect("Can get a block hash");
+
+        hash.unwrap_or_else(|| panic!("{} has no block at height {}", self.name, number))
+    }
+
+    /// The hash of the genesis block, which signed transactions commit to.
+    pub async fn genesis_hash(&self) -> H256 {
+        self.block_hash(0).await
+    }
+
+    /// The extrinsics of the block at the given height in the node's best chain, encoded.
+    pub async fn block_extrinsics(&self, number: u32) -> Vec<Vec<u8>> {
+        let hash = self.block_hash(number).await;
+        let block: serde_json::Value = self
+            .rpc
+            .request("chain_getBlock", rpc_params![hash])
+            .await
+            .expect("Can get a block");
+
+        block["block"]["extrinsics"]
+            .as_array()
+            .expect("Blocks have extrinsics")
+            .iter()
+            .map(|extrinsic| {
+                extrinsic
+                    .as_str()
+                    .and_then(from_hex)
+                    .expect("Extrinsics are hex")
+            })
+            .collect()
+    }
+
+    /// The height of the first block above `from` in the node's best chain that contains the
+    /// given encoded extrinsic, if any.
+    pub async fn find_extrinsic(&self, extrinsic: &[u8], from: u32) -> Option<u32> {
+        for number in from + 1..=self.best_number().await {
+            let extrinsics = self.block_extrinsics(number).await;
+            if extrinsics.iter().any(|e| e == extrinsic) {
+                return Some(number);
+            }
+        }
+        None
+    }
+
+    /// Submit an encoded extrinsic to the node's transaction pool.
+    pub async fn submit_extrinsic(&self, extrinsic: &[u8]) -> H256 {
+        self.rpc
+            .request("author_submitExtrinsic", rpc_params![to_hex(extrinsic)])
+            .await
+            .expect("The node accepts the extrinsic")
+    }
+
+    /// Whether the node has imported the block with the given hash, on any branch.
+    pub async fn has_block(&self, hash: H256) -> bool {
+        !self.header(Some(hash)).await.is_null()
+    }
+
+    /// Seal a block with the given algorithm on top of `parent`, or of the best block if no
+    /// parent is given. The node must run with manual seal.
+    pub async fn create_block(&self, algo: &str, parent: Option<H256>) -> H256 {
+        let created: serde_json::Value = self
+            .rpc
+            .request(
+                "engine_createBlockWithAlgo",
+                rpc_params![true, false, parent, algo],
+            )
+            .await
+            .unwrap_or_else(|e| panic!("{} could not seal a {} block: {}", self.name, algo, e));
+
+        serde_json::from_value(created["hash"].clone()).expect("Created blocks have a hash")
+    }
+
+    /// Seal `count` blocks in a row on top of the best block, and return their hashes.
+    pub async fn create_blocks(&self, algo: &str, count: u32) -> Vec<H256> {
+        let mut hashes = Vec::new();
+        for _ in 0..count {
+            hashes.push(self.create_block(algo, None).await);
+        }
+        hashes
+    }
+
+    /// Stop the node's miner.
+    pub async fn stop_mining(&self) {
+        let () = self
+            .rpc
+            .request("mining_stop", rpc_params![])
+            .await
+            .expect("Can stop mining");
+    }
+
+    /// Run a double spend attack from this node, which must allow it.
+    pub async fn double_spend(&self, params: DoubleSpendParams) -> DoubleSpendReport {
+        self.rpc
+            .request("attack_doubleSpend", rpc_params![params])
+            .await
+            .expect("The double spend runs")
+    }
+
+    /// How many blocks the node is withholding. The node must withhold blocks.
+    pub async fn withholding_status(&self) -> WithholdingStatus {
+        self.rpc
+            .request("withholding_status", rpc_params![])
+            .await
+            .expect("Can get the withholding status")
+    }
+
+    /// Publish every block the node is withholding, and return how many there were.
+    pub async fn release_withheld(&self) -> u32 {
+        self.rpc
+            .request("withholding_release", rpc_params![])
+            .await
+            .expect("Can release the withheld blocks")
+    }
+
+    /// The algorithm the block at the given height in the node's best chain was mined with.
+    pub async fn block_algo(&self, number: u32) -> SupportedHashes {
+        let hash = self.block_hash(number).await;
+        let header = self.header(Some(hash)).await;
+        let logs = header["digest"]["logs"]
+            .as_array()
+            .expect("Headers have digest logs");
+
+        logs.iter()
+            .filter_map(|log| {
+                let bytes = from_hex(log.as_str()?)?;
+                match DigestItem::decode(&mut &bytes[..]).ok()? {
+                    DigestItem::PreRuntime(POW_ENGINE_ID, pre_digest) => {
+                        PreDigest::decode(&mut &pre_digest[..]).ok()
+                    }
+                    _ => None,
+                }
+            })
+            .map(|(_, algo)| algo)
+            .next()
+            .unwrap_or_else(|| panic!("Block {} has no PoW pre-digest", number))
+    }
+
+    /// Wait until the given extrinsic is in the node's best chain above `from`, and return
+    /// the height of its block.
+    pub async fn wait_for_extrinsic(&self, extrinsic: &[u8], from: u32) -> u32 {
+        let waiting = async {
+            loop {
+                if let Some(number) = self.find_extrinsic(extrinsic, from).await {
+                    return number;
+                }
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+
+        tokio::time::timeout(HEIGHT_TIMEOUT, waiting)
+            .await
+            .unwrap_or_else(|_| panic!("{} did not include the extrinsic in time", self.name))
+    }
+
+    /// Wait until the block with the given hash is in the node's best chain at that height.
+    pub async fn wait_for_block(&self, number: u32, hash: H256) {
+        let waiting = async {
+            while self.best_number().await < number || self.block_hash(number).await != hash {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+
+        tokio::time::timeout(HEIGHT_TIMEOUT, waiting)
+            .await
+            .unwrap_or_else(|_| panic!("{} did not switch to {:?} in time", self.name, hash));
+    }
+
+    /// Wait until the node has imported the block with the given hash, on any branch.
+    pub async fn wait_for_import(&self, hash: H256) {
+        let waiting = async {
+            while !self.has_block(hash).await {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+
+        tokio::time::timeout(HEIGHT_TIMEOUT, waiting)
+            .await
+            .unwrap_or_else(|_| panic!("{} did not import {:?} in time", self.name, hash));
+    }
+
+    /// Wait until the node is withholding at least the given number of blocks.
+    pub async fn wait_for_withheld(&self, count: u32) {
+        let waiting = async {
+            while self.withholding_status().await.withheld < count {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+
+        tokio::time::timeout(HEIGHT_TIMEOUT, waiting)
+            .await
+            .unwrap_or_else(|_| panic!("{} did not withhold {} blocks in time", self.name, count));
+    }
+
+    /// Wait until the node's best block is at least the given height.
+    pub async fn wait_for_height(&self, number: u32) {
+        let waiting = async {
+            while self.best_number().await < number {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        };
+
+        tokio::time::timeout(HEIGHT_TIMEOUT, waiting)
+            .await
+            .unwrap_or_else(|_| panic!("{} did not reach height {} in time", self.name, number));
+    }
+}
+
+/// A handful of nodes connected to each other through the first one.
+pub struct Network {
+    pub nodes: Vec<Node>,
+}
+
+impl Network {
+    /// Start all the nodes. The first one is the bootnode for the rest.
+    pub async fn start(configs: Vec<NodeConfig>) -> Network {
+        let mut nodes: Vec<Node> = Vec::new();
+
+        for config in configs {
+            let bootnode = match nodes.first() {
+                Some(bootnode) => Some(bootnode.multiaddr().await),
+                None => None,
+            };
+            nodes.push(Node::start(config, bootnode).await);
+        }
+
+        Network { nodes }
+    }
+
+    /// The node with the given name.
+    pub fn node(&self, name: &str) -> &Node {
+        self.nodes
+            .iter()
+            .find(|node| node.name == name)
+            .unwrap_or_else(|| panic!("There is no node called {}", name))
+    }
+
+    /// Wait until every node's best block is at least the given height.
+    pub async fn wait_for_height(&self, number: u32) {
+        for node in &self.nodes {
+            node.wait_for_height(number).await;
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let digits: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("0x{digits}")
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.strip_prefix("0x")?;
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,110 @@
+//! Run a double spend attack against a small network and check that the victim's payment
+//! disappears.
+//!
+//! Like the fork tests, this mines real blocks, so it takes a while.
+
+use academy_pow::double_spend::{signed_transfer, DoubleSpendParams, RevertedTransaction};
+use academy_pow_runtime::{AccountId, TOKEN};
+use integration_tests::{dev_forks, Network, NodeConfig};
+use parity_scale_codec::Encode;
+use sp_core::{crypto::Ss58Codec, sr25519, Pair};
+use sp_runtime::traits::{BlakeTwo256, Hash};
+
+fn account(seed: &str) -> AccountId {
+    sr25519::Pair::from_string(seed, None)
+        .expect("Dev seeds are valid")
+        .public()
+        .into()
+}
+
+/// Alice pays Bob, then mines a heavier private branch from before the payment that pays
+/// Charlie instead. Once she publishes it, Bob's payment is gone from the best chain.
+#[tokio::test(flavor = "multi_thread")]
+async fn double_spend_reverts_the_honest_transfer() {
+    let network = Network::start(vec![
+        NodeConfig {
+            name: "honest",
+            mining_algo: "md5",
+            maxi_position: "no-maxi",
+            forks: dev_forks(),
+            extra_args: &[],
+        },
+        NodeConfig {
+            name: "attacker",
+            mining_algo: "md5",
+            maxi_position: "no-maxi",
+            forks: dev_forks(),
+            extra_args: &["--allow-double-spend"],
+        },
+    ])
+    .await;
+    let honest = network.node("honest");
+    let attacker = network.node("attacker");
+
+    // The attacker only mines the private branch.
+    attacker.stop_mining().await;
+    honest.wait_for_height(2).await;
+    let ancestor_number = honest.best_number().await;
+    let ancestor = honest.block_hash(ancestor_number).await;
+    attacker.wait_for_block(ancestor_number, ancestor).await;
+
+    // Alice hasn't sent anything yet, so her first transaction has nonce 0.
+    let alice = sr25519::Pair::from_string("//Alice", None).expect("Dev seeds are valid");
+    let payment = signed_transfer(
+        honest.genesis_hash().await,
+        &alice,
+        account("//Bob"),
+        100 * TOKEN,
+        0,
+    )
+    .encode();
+    honest.submit_extrinsic(&payment).await;
+    let included = honest.wait_for_extrinsic(&payment, ancestor_number).await;
+
+    // Bob waits for a confirmation. Then the honest miner stops too, so that the attacker's
+    // branch only has to beat a chain that stands still.
+    honest.wait_for_height(included + 1).await;
+    honest.stop_mining().await;
+    let public_height = honest.best_number().await;
+    attacker
+        .wait_for_block(public_height, honest.block_hash(public_height).await)
+        .await;
+
+    let report = attacker
+        .double_spend(DoubleSpendParams {
+            ancestor,
+            from: "//Alice".into(),
+            to: account("//Charlie").to_ss58check(),
+            amount: 100 * TOKEN,
+            max_blocks: None,
+            boost: None,
+        })
+        .await;
+
+    assert!(
+        report.published,
+        "The private branch should overtake the public chain"
+    );
+    assert_eq!(
+        report.reverted,
+        vec![RevertedTransaction {
+            hash: BlakeTwo256::hash(&payment),
+            conflicts: true,
+        }],
+        "The report should list the payment to Bob as the only reverted transaction, and as \
+         conflicting",
+    );
+
+    let branch_tip = ancestor_number + report.branch.len() as u32;
+    honest
+        .wait_for_block(
+            branch_tip,
+            *report.branch.last().expect("The branch has blocks"),
+        )
+        .await;
+    assert_eq!(
+        honest.find_extrinsic(&payment, ancestor_number).await,
+        None,
+        "The payment to Bob should disappear from the honest node's best chain",
+    );
+}
@@ -0,0 +1,180 @@
+//! Step a small network through the development chain's forks and check the resulting chains.
+//!
+//! The nodes use manual seal, so each test decides which node seals every block and with which
+//! algorithm. Nothing mines in the background, so once a block has propagated the chains stay
+//! put until the test extends them.
+
+use integration_tests::{dev_forks, old_forks, Network, NodeConfig, MANUAL_SEAL};
+use multi_pow::SupportedHashes;
+
+/// Adding sha3 and keccak only loosens the rules, so everyone stays on one chain.
+#[tokio::test(flavor = "multi_thread")]
+async fn soft_fork_keeps_one_chain() {
+    let add_sha3_keccak = dev_forks().add_sha3_keccak;
+
+    let network = Network::start(vec![
+        NodeConfig {
+            name: "md5",
+            mining_algo: "md5",
+            maxi_position: "no-maxi",
+            forks: dev_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+        NodeConfig {
+            name: "sha3",
+            mining_algo: "sha3",
+            maxi_position: "no-maxi",
+            forks: dev_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+    ])
+    .await;
+    let md5 = network.node("md5");
+    let sha3 = network.node("sha3");
+
+    let before_fork = md5.create_blocks("md5", add_sha3_keccak).await;
+    let fork_block = *before_fork.last().expect("The fork is above genesis");
+    sha3.wait_for_block(add_sha3_keccak, fork_block).await;
+
+    // Each node builds on the other's block, so they must have accepted it.
+    let sha3_block = sha3.create_block("sha3", None).await;
+    md5.wait_for_block(add_sha3_keccak + 1, sha3_block).await;
+    let md5_block = md5.create_block("md5", None).await;
+    sha3.wait_for_block(add_sha3_keccak + 2, md5_block).await;
+
+    for number in 1..=add_sha3_keccak {
+        assert_eq!(sha3.block_algo(number).await, SupportedHashes::Md5);
+    }
+    assert_eq!(
+        md5.block_algo(add_sha3_keccak + 1).await,
+        SupportedHashes::Sha3
+    );
+}
+
+/// Removing md5 strands nodes that didn't upgrade. They keep extending a chain of md5 blocks
+/// that the upgraded nodes reject.
+#[tokio::test(flavor = "multi_thread")]
+async fn hard_fork_strands_old_nodes() {
+    let remove_md5 = dev_forks().remove_md5;
+
+    let network = Network::start(vec![
+        NodeConfig {
+            name: "old",
+            mining_algo: "md5",
+            maxi_position: "no-maxi",
+            forks: old_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+        NodeConfig {
+            name: "upgraded",
+            mining_algo: "sha3",
+            maxi_position: "no-maxi",
+            forks: dev_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+    ])
+    .await;
+    let old = network.node("old");
+    let upgraded = network.node("upgraded");
+
+    let before_fork = old.create_blocks("md5", remove_md5).await;
+    let fork_block = *before_fork.last().expect("The fork is above genesis");
+    upgraded.wait_for_block(remove_md5, fork_block).await;
+
+    // The old node's chain is longer, so it stays on it once it sees the upgraded chain.
+    let old_chain = old.create_blocks("md5", 3).await;
+    let upgraded_chain = vec![
+        upgraded.create_block("sha3", Some(fork_block)).await,
+        upgraded.create_block("sha3", None).await,
+    ];
+    old.wait_for_import(upgraded_chain[1]).await;
+
+    // The old node announced its blocks before the upgraded node sealed its own, so they have
+    // reached the upgraded node by now. It must have rejected them.
+    for hash in &old_chain {
+        assert!(
+            !upgraded.has_block(*hash).await,
+            "The upgraded node should reject md5 blocks after the hard fork",
+        );
+    }
+    for (offset, hash) in old_chain.iter().enumerate() {
+        assert_eq!(old.block_hash(remove_md5 + 1 + offset as u32).await, *hash);
+    }
+    for (offset, hash) in upgraded_chain.iter().enumerate() {
+        assert_eq!(
+            upgraded.block_hash(remove_md5 + 1 + offset as u32).await,
+            *hash
+        );
+    }
+}
+
+/// At the contentious fork, sha3 maxis and keccak maxis each reject the other's blocks,
+/// so the chain splits in two.
+#[tokio::test(flavor = "multi_thread")]
+async fn contentious_fork_splits_the_chain() {
+    let forks = dev_forks();
+
+    let network = Network::start(vec![
+        // Follows both sides, so every block reaches it.
+        NodeConfig {
+            name: "neutral",
+            mining_algo: "md5",
+            maxi_position: "no-maxi",
+            forks: dev_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+        NodeConfig {
+            name: "sha3",
+            mining_algo: "sha3",
+            maxi_position: "sha3-maxi",
+            forks: dev_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+        NodeConfig {
+            name: "keccak",
+            mining_algo: "keccak",
+            maxi_position: "keccak-maxi",
+            forks: dev_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+    ])
+    .await;
+    let neutral = network.node("neutral");
+    let sha3 = network.node("sha3");
+    let keccak = network.node("keccak");
+
+    // Md5 until the soft fork, then sha3 until the split.
+    neutral.create_blocks("md5", forks.add_sha3_keccak).await;
+    let before_split = neutral
+        .create_blocks("sha3", forks.split_sha3_keccak - forks.add_sha3_keccak)
+        .await;
+    let split_block = *before_split
+        .last()
+        .expect("The split is above the soft fork");
+    sha3.wait_for_block(forks.split_sha3_keccak, split_block)
+        .await;
+    keccak
+        .wait_for_block(forks.split_sha3_keccak, split_block)
+        .await;
+
+    let sha3_chain = sha3.create_blocks("sha3", 2).await;
+    let keccak_chain = keccak.create_blocks("keccak", 2).await;
+    for hash in sha3_chain.iter().chain(&keccak_chain) {
+        neutral.wait_for_import(*hash).await;
+    }
+
+    // The neutral node has both branches, but each maxi only follows its own.
+    for (offset, (sha3_hash, keccak_hash)) in sha3_chain.iter().zip(&keccak_chain).enumerate() {
+        let number = forks.split_sha3_keccak + 1 + offset as u32;
+        assert_eq!(sha3.block_hash(number).await, *sha3_hash);
+        assert_eq!(keccak.block_hash(number).await, *keccak_hash);
+        assert!(
+            !sha3.has_block(*keccak_hash).await,
+            "The sha3 maxi should reject keccak blocks after the split",
+        );
+        assert!(
+            !keccak.has_block(*sha3_hash).await,
+            "The keccak maxi should reject sha3 blocks after the split",
+        );
+    }
+}
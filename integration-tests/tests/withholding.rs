@@ -0,0 +1,52 @@
+//! Withhold blocks on one node and check that they stay private until it releases them, even
+//! from peers that connect while it is withholding.
+//!
+//! The selfish node mines real blocks, so this takes a while.
+
+use std::time::Duration;
+
+use integration_tests::{dev_forks, Network, Node, NodeConfig, MANUAL_SEAL};
+
+/// A peer learns the best block of the nodes it connects to from the sync handshake. The
+/// withheld blocks must not be part of it.
+#[tokio::test(flavor = "multi_thread")]
+async fn newly_connected_peers_do_not_sync_withheld_blocks() {
+    let network = Network::start(vec![NodeConfig {
+        name: "selfish",
+        mining_algo: "md5",
+        maxi_position: "no-maxi",
+        forks: dev_forks(),
+        extra_args: &["--withhold-blocks", "manual"],
+    }])
+    .await;
+    let selfish = network.node("selfish");
+
+    selfish.wait_for_withheld(3).await;
+    assert_eq!(selfish.best_number().await, 0);
+
+    // The newcomer only seals blocks when asked to, so anything it has came from the selfish
+    // node.
+    let newcomer = Node::start(
+        NodeConfig {
+            name: "newcomer",
+            mining_algo: "md5",
+            maxi_position: "no-maxi",
+            forks: dev_forks(),
+            extra_args: MANUAL_SEAL,
+        },
+        Some(selfish.multiaddr().await),
+    )
+    .await;
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    assert_eq!(newcomer.best_number().await, 0);
+
+    // Once released, the blocks reach the newcomer like any others.
+    let private_height = selfish
+        .withholding_status()
+        .await
+        .private_height
+        .expect("The selfish node is withholding blocks");
+    assert!(selfish.release_withheld().await > 0);
+    newcomer.wait_for_height(private_height).await;
+    assert!(selfish.best_number().await >= private_height);
+}
@@ -0,0 +1,342 @@
+//! Verify a chain of PoW headers without a client or the runtime.
+//!
+//! `MultiPow::verify` asks the runtime for the difficulty, so it needs a full node. Light
+//! clients and bridges only have headers. They can still check that every header is sealed with
+//! genuine work by an algorithm the fork rules allow, as long as they know the difficulty. It is
+//! either taken from the seal and checked against a minimum, or proven from the parent's state
+//! with a storage proof, like the ones the `state_getReadProof` RPC returns. A proof covers each
+//! algorithm's stored difficulty along with what its idle decay depends on, but not the shared
+//! difficulty.
+//!
+//! Everything here works without std, so it can also run inside another chain's runtime.
+
+use parity_scale_codec::Decode;
+use sp_consensus_pow::{TotalDifficulty, POW_ENGINE_ID};
+use sp_core::{hashing::twox_128, Hasher, H256, U256};
+use sp_runtime::{
+    traits::{Header as HeaderT, One},
+    DigestItem,
+};
+use sp_std::vec::Vec;
+use sp_trie::{read_trie_value, LayoutV1, MemoryDB, StorageProof};
+
+use crate::{check_sealed_block, ForkingConfig, RejectReason, Seal, Threshold};
+
+/// Where the difficulty a header was mined at comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DifficultyCheck {
+    /// Trust the difficulty the seal claims, if it is at least this much for the seal's algorithm.
+    ///
+    /// Cheap, but anyone can mine at the minimum. Only the total difficulty of competing chains
+    /// tells an attacker's chain apart from the real one.
+    Claimed { minimum: Threshold },
+    /// Prove the difficulty from the parent's state at these keys, like the runtime reads it.
+    StorageProof(DifficultyKeys),
+}
+
+/// The storage keys of one algorithm's difficulty adjustment pallet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AlgoKeys {
+    /// The difficulty before any idle decay, a SCALE encoded `U256`.
+    pub current_difficulty: Vec<u8>,
+    /// When the algorithm was last mined, a SCALE encoded `u64` if it ever was.
+    pub last_mined: Vec<u8>,
+    /// The runtime's SCALE encoded `DifficultyAdjustmentParams`.
+    pub params: Vec<u8>,
+}
+
+impl AlgoKeys {
+    fn new(pallet: &str) -> Self {
+        Self {
+            current_difficulty: storage_key(pallet, "CurrentDifficulty"),
+            last_mined: storage_key(pallet, "LastMined"),
+            params: storage_key(pallet, "Params"),
+        }
+    }
+}
+
+/// The storage keys the runtime reads the difficulty from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DifficultyKeys {
+    pub md5: AlgoKeys,
+    pub sha3: AlgoKeys,
+    pub keccak: AlgoKeys,
+    /// The parent's timestamp, a SCALE encoded `u64`, which idle algorithms decay up to.
+    pub now: Vec<u8>,
+    /// Whether the shared difficulty is enabled, a SCALE encoded `bool`.
+    pub shared_difficulty_enabled: Vec<u8>,
+}
+
+impl DifficultyKeys {
+    /// The keys of the given difficulty adjustment, timestamp and shared difficulty pallets.
+    pub fn new(
+        md5_pallet: &str,
+        sha3_pallet: &str,
+        keccak_pallet: &str,
+        timestamp_pallet: &str,
+        shared_difficulty_pallet: &str,
+    ) -> Self {
+        Self {
+            md5: AlgoKeys::new(md5_pallet),
+            sha3: AlgoKeys::new(sha3_pallet),
+            keccak: AlgoKeys::new(keccak_pallet),
+            now: storage_key(timestamp_pallet, "Now"),
+            shared_difficulty_enabled: storage_key(shared_difficulty_pallet, "Enabled"),
+        }
+    }
+
+    /// Read the difficulty out of a proof of the state with the given root.
+    ///
+    /// Idle algorithms decay the way the runtime's `difficulty::decay` does. The shared
+    /// difficulty derives every algorithm's difficulty from other storage, which is not covered,
+    /// so the proof must show it is disabled.
+    fn prove<Hashing: Hasher<Out = H256>>(
+        &self,
+        state_root: &H256,
+        proof: StorageProof,
+    ) -> Result<Threshold, HeaderError> {
+        let db = proof.into_memory_db::<Hashing>();
+        let state = ProvenState::<Hashing> {
+            db: &db,
+            root: state_root,
+        };
+
+        if state
+            .read::<bool>(&self.shared_difficulty_enabled)?
+            .unwrap_or(false)
+        {
+            return Err(HeaderError::SharedDifficulty);
+        }
+        let now = state.read::<u64>(&self.now)?.unwrap_or_default();
+
+        let difficulty = |keys: &AlgoKeys| -> Result<U256, HeaderError> {
+            // The runtime sets the difficulty and params at genesis, so missing ones mean a bad
+            // proof.
+            let difficulty = state
+                .read::<U256>(&keys.current_difficulty)?
+                .ok_or(HeaderError::BadDifficultyProof)?;
+            let Some(last_mined) = state.read::<u64>(&keys.last_mined)? else {
+                return Ok(difficulty);
+            };
+            let params = state
+                .read::<DecayParams>(&keys.params)?
+                .ok_or(HeaderError::BadDifficultyProof)?;
+
+            Ok(params.decay(difficulty, now.saturating_sub(last_mined).into()))
+        };
+
+        Ok(Threshold {
+            md5: difficulty(&self.md5)?,
+            sha3: difficulty(&self.sha3)?,
+            keccak: difficulty(&self.keccak)?,
+        })
+    }
+}
+
+/// A storage proof, checked against a state root as it is read.
+struct ProvenState<'a, Hashing: Hasher<Out = H256>> {
+    db: &'a MemoryDB<Hashing>,
+    root: &'a H256,
+}
+
+impl<Hashing: Hasher<Out = H256>> ProvenState<'_, Hashing> {
+    /// The value at `key`, if there is one. A complete proof also proves a value's absence, an
+    /// incomplete one fails.
+    fn read<T: Decode>(&self, key: &[u8]) -> Result<Option<T>, HeaderError> {
+        read_trie_value::<LayoutV1<Hashing>, _>(self.db, self.root, key, None, None)
+            .map_err(|_| HeaderError::BadDifficultyProof)?
+            .map(|value| T::decode(&mut &value[..]))
+            .transpose()
+            .map_err(|_| HeaderError::BadDifficultyProof)
+    }
+}
+
+fn storage_key(pallet: &str, item: &str) -> Vec<u8> {
+    [twox_128(pallet.as_bytes()), twox_128(item.as_bytes())].concat()
+}
+
+/// The runtime's `DifficultyAdjustmentParams`, of which only the idle decay matters here.
+#[derive(Decode)]
+struct DecayParams {
+    _target_block_time: u128,
+    _damp_factor: u128,
+    _clamp_factor: u128,
+    _max_difficulty: U256,
+    min_difficulty: U256,
+    idle_decay_after: u128,
+    idle_decay_time: u128,
+}
+
+impl DecayParams {
+    /// The same as the runtime's `difficulty::decay`.
+    fn decay(&self, difficulty: U256, idle: u128) -> U256 {
+        if self.idle_decay_time == 0 {
+            return difficulty;
+        }
+        let Some(overdue) = idle.checked_sub(self.idle_decay_after) else {
+            return difficulty;
+        };
+
+        let decayed = difficulty.saturating_mul(U256::from(self.idle_decay_time))
+            / U256::from(self.idle_decay_time.saturating_add(overdue));
+        difficulty.min(self.min_difficulty.max(decayed))
+    }
+}
+
+/// A header, along with a proof of its parent's difficulty if the verifier needs one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProvenHeader<H> {
+    pub header: H,
+    /// Proves the values at the difficulty keys, or their absence, against the parent's state root.
+    pub difficulty_proof: Option<StorageProof>,
+}
+
+/// The reasons a header may fail verification.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeaderError {
+    /// The header's parent hash is not the hash of the header before it.
+    NotAChild,
+    /// The header's number does not follow its parent's.
+    BadNumber,
+    /// The header does not end in a PoW seal.
+    MissingSeal,
+    /// The seal claims less difficulty than the minimum.
+    DifficultyTooLow,
+    /// The difficulty has to be proven from storage, but there is no proof.
+    MissingDifficultyProof,
+    /// The proof does not prove the difficulty against the parent's state root.
+    BadDifficultyProof,
+    /// The parent's state uses the shared difficulty, which storage proofs don't cover.
+    SharedDifficulty,
+    /// `MultiPow::verify` would reject the block.
+    Rejected(RejectReason),
+}
+
+/// Which header in a chain failed verification, and why.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChainError {
+    /// The header's position in the chain that was verified.
+    pub index: usize,
+    pub error: HeaderError,
+}
+
+/// Verifies headers the way `MultiPow::verify` verifies blocks, without executing the runtime.
+#[derive(Clone)]
+pub struct HeaderVerifier {
+    fork_config: ForkingConfig,
+    difficulty: DifficultyCheck,
+    require_signed_seals: bool,
+}
+
+impl HeaderVerifier {
+    pub fn new(fork_config: ForkingConfig, difficulty: DifficultyCheck) -> Self {
+        Self {
+            fork_config,
+            difficulty,
+            require_signed_seals: false,
+        }
+    }
+
+    /// Whether seals must be signed by the miner named in the pre-digest.
+    pub fn with_signed_seals(mut self, require_signed_seals: bool) -> Self {
+        self.require_signed_seals = require_signed_seals;
+        self
+    }
+
+    /// Verify that `proven` is a child of `parent`, sealed with genuine work.
+    ///
+    /// Returns the difficulty the header was mined at.
+    pub fn verify_header<H>(
+        &self,
+        parent: &H,
+        proven: &ProvenHeader<H>,
+    ) -> Result<Threshold, HeaderError>
+    where
+        H: HeaderT<Hash = H256>,
+    {
+        let mut header = proven.header.clone();
+        if *header.parent_hash() != parent.hash() {
+            return Err(HeaderError::NotAChild);
+        }
+        if *header.number() != *parent.number() + One::one() {
+            return Err(HeaderError::BadNumber);
+        }
+        let parent_number: u32 = (*parent.number())
+            .try_into()
+            .map_err(|_| HeaderError::BadNumber)?;
+
+        // Like the import queue, take the seal off and hash what is left to get the pre-hash.
+        let seal = match header.digest_mut().pop() {
+            Some(DigestItem::Seal(id, seal)) if id == POW_ENGINE_ID => seal,
+            _ => return Err(HeaderError::MissingSeal),
+        };
+        let pre_hash = header.hash();
+        let seal = Seal::decode(&mut &seal[..])
+            .map_err(|_| HeaderError::Rejected(RejectReason::BadSeal))?;
+
+        // The import queue refuses blocks with more than one PoW pre-digest, and so do we.
+        let mut pre_digests = header.digest().logs().iter().filter_map(|log| match log {
+            DigestItem::PreRuntime(id, pre_digest) if *id == POW_ENGINE_ID => Some(&pre_digest[..]),
+            _ => None,
+        });
+        let pre_digest = pre_digests.next();
+        if pre_digests.next().is_some() {
+            return Err(HeaderError::Rejected(RejectReason::BadPreDigest));
+        }
+
+        let difficulty = match &self.difficulty {
+            DifficultyCheck::Claimed { minimum } => {
+                let algo = seal.work.algo;
+                if seal.difficulty.for_algo(algo) < minimum.for_algo(algo) {
+                    return Err(HeaderError::DifficultyTooLow);
+                }
+                seal.difficulty
+            }
+            DifficultyCheck::StorageProof(keys) => {
+                let proof = proven
+                    .difficulty_proof
+                    .clone()
+                    .ok_or(HeaderError::MissingDifficultyProof)?;
+                keys.prove::<H::Hashing>(parent.state_root(), proof)?
+            }
+        };
+
+        check_sealed_block(
+            self.fork_config,
+            self.require_signed_seals,
+            parent_number,
+            &pre_hash,
+            pre_digest,
+            &seal,
+            difficulty,
+        )
+        .map_err(HeaderError::Rejected)?;
+
+        Ok(difficulty)
+    }
+
+    /// Verify a sequence of headers on top of one that is already trusted, eg. a checkpoint.
+    ///
+    /// Returns the total difficulty of the new headers, to choose between competing chains.
+    pub fn verify_chain<H>(
+        &self,
+        trusted: &H,
+        headers: &[ProvenHeader<H>],
+    ) -> Result<Threshold, ChainError>
+    where
+        H: HeaderT<Hash = H256>,
+    {
+        let mut total = Threshold::default();
+        let mut parent = trusted;
+
+        for (index, proven) in headers.iter().enumerate() {
+            let difficulty = self
+                .verify_header(parent, proven)
+                .map_err(|error| ChainError { index, error })?;
+            total.increment(difficulty);
+            parent = &proven.header;
+        }
+
+        Ok(total)
+    }
+}
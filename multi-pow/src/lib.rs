@@ -20,10 +20,9 @@ use core::str::FromStr;
 #[cfg(feature = "std")]
 use std::sync::Arc;
 
-use parity_scale_codec::{Decode, Encode};
+use parity_scale_codec::{Decode, Encode, Input, Output};
 #[cfg(feature = "std")]
 use sc_consensus_pow::{Error, PowAlgorithm};
-#[cfg(feature = "std")]
 use sha3::{Digest, Keccak256, Sha3_256};
 #[cfg(feature = "std")]
 use sp_api::ProvideRuntimeApi;
@@ -32,12 +31,15 @@ use sp_consensus_pow::DifficultyApi;
 #[cfg(feature = "std")]
 use sp_consensus_pow::Seal as RawSeal;
 use sp_consensus_pow::TotalDifficulty;
-use sp_core::{H256, U256};
+use sp_core::{crypto::AccountId32, sr25519, H256, U256};
 #[cfg(feature = "std")]
 use sp_runtime::generic::BlockId;
+use sp_runtime::traits::Verify;
 #[cfg(feature = "std")]
 use sp_runtime::traits::{Block as BlockT, Header as HeaderT};
 
+pub mod headers;
+
 /// A struct that represents a difficulty threshold.
 /// Unlike a normal PoW algorithm this struct has a separate threshold for each hash
 #[derive(
@@ -70,14 +72,58 @@ impl TotalDifficulty for Threshold {
     }
 }
 
+impl Threshold {
+    /// The difficulty threshold that applies to the given hashing algorithm.
+    pub fn for_algo(&self, algo: SupportedHashes) -> U256 {
+        match algo {
+            SupportedHashes::Md5 => self.md5,
+            SupportedHashes::Sha3 => self.sha3,
+            SupportedHashes::Keccak => self.keccak,
+        }
+    }
+}
+
 /// An enum that represents the supported hash types
-#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Encode, Decode, Debug, scale_info::TypeInfo)]
 pub enum SupportedHashes {
     Md5,
     Sha3,
     Keccak,
 }
 
+impl FromStr for SupportedHashes {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "md" | "Md" | "md5" | "Md5" => Self::Md5,
+            "sha" | "sha3" | "Sha" | "Sha3" => Self::Sha3,
+            "keccak" | "Keccak" => Self::Keccak,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl SupportedHashes {
+    /// Hash arbitrary data with this algorithm.
+    pub fn hash(&self, data: &[u8]) -> H256 {
+        match self {
+            SupportedHashes::Md5 => {
+                // The md5 is only 16 byte output, so we just concatenate it twice to
+                // get an H256
+                let bytes = *md5::compute(data);
+                let mut doubled = [0u8; 32];
+                doubled[0..16].copy_from_slice(&bytes[0..16]);
+                doubled[16..32].copy_from_slice(&bytes[0..16]);
+
+                H256::from(doubled)
+            }
+            SupportedHashes::Sha3 => H256::from_slice(Sha3_256::digest(data).as_slice()),
+            SupportedHashes::Keccak => H256::from_slice(Keccak256::digest(data).as_slice()),
+        }
+    }
+}
+
 impl Default for SupportedHashes {
     fn default() -> Self {
         Self::Sha3
@@ -104,20 +150,60 @@ pub fn simple_hash_meets_difficulty(hash: &H256, difficulty: U256) -> bool {
 }
 
 pub fn multi_hash_meets_difficulty(hash: &MultiHash, difficulty: Threshold) -> bool {
-    match hash.algo {
-        SupportedHashes::Md5 => simple_hash_meets_difficulty(&hash.value, difficulty.md5),
-        SupportedHashes::Sha3 => simple_hash_meets_difficulty(&hash.value, difficulty.sha3),
-        SupportedHashes::Keccak => simple_hash_meets_difficulty(&hash.value, difficulty.keccak),
-    }
+    simple_hash_meets_difficulty(&hash.value, difficulty.for_algo(hash.algo))
 }
 
 /// A Seal struct that will be encoded to a Vec<u8> as used as the
 /// `RawSeal` type.
-#[derive(Clone, PartialEq, Eq, Encode, Decode, Debug)]
+///
+/// The signature is optional and comes last, encoded without an `Option` tag. So unsigned seals
+/// encode exactly as they did before seals could be signed, and nodes that don't know about
+/// signatures still decode signed seals, ignoring the trailing signature.
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Seal {
     pub work: MultiHash,
     pub difficulty: Threshold,
     pub nonce: U256,
+    /// Part of the search space alongside the nonce. Lets a pool hand each of its miners
+    /// a separate slice of the work, each searching every nonce.
+    pub extra_nonce: u64,
+    /// The miner's signature over the pre-hash, proving they hold the key of the
+    /// account in the pre-digest. Only required when the node demands signed seals.
+    pub signature: Option<sr25519::Signature>,
+}
+
+impl Encode for Seal {
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        self.work.encode_to(dest);
+        self.difficulty.encode_to(dest);
+        self.nonce.encode_to(dest);
+        self.extra_nonce.encode_to(dest);
+        if let Some(signature) = &self.signature {
+            signature.encode_to(dest);
+        }
+    }
+}
+
+impl Decode for Seal {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, parity_scale_codec::Error> {
+        let work = Decode::decode(input)?;
+        let difficulty = Decode::decode(input)?;
+        let nonce = Decode::decode(input)?;
+        let extra_nonce = Decode::decode(input)?;
+        // Anything after the unsigned part must be a signature.
+        let signature = match input.remaining_len()? {
+            Some(0) => None,
+            _ => Some(Decode::decode(input)?),
+        };
+
+        Ok(Seal {
+            work,
+            difficulty,
+            nonce,
+            extra_nonce,
+            signature,
+        })
+    }
 }
 
 /// A not-yet-computed attempt to solve the proof of work. Calling the
@@ -127,44 +213,114 @@ pub struct Compute {
     pub difficulty: Threshold,
     pub pre_hash: H256,
     pub nonce: U256,
+    pub extra_nonce: u64,
 }
 
-#[cfg(feature = "std")]
 impl Compute {
     pub fn compute(self, algo: SupportedHashes) -> Seal {
-        let value = match algo {
-            SupportedHashes::Md5 => {
-                // The md5 is only 16 byte output, so we just concatenate it twice to
-                // get an H256
-                let bytes = *md5::compute(&self.encode()[..]);
-                let mut doubled = [0u8; 32];
-                doubled[0..16].copy_from_slice(&bytes[0..16]);
-                doubled[16..32].copy_from_slice(&bytes[0..16]);
-
-                H256::from(doubled)
-            }
-            SupportedHashes::Sha3 => {
-                H256::from_slice(Sha3_256::digest(&self.encode()[..]).as_slice())
-            }
-            SupportedHashes::Keccak => {
-                H256::from_slice(Keccak256::digest(&self.encode()[..]).as_slice())
-            }
-        };
+        let value = algo.hash(&self.encode()[..]);
 
         Seal {
             nonce: self.nonce,
+            extra_nonce: self.extra_nonce,
             difficulty: self.difficulty,
             work: MultiHash { algo, value },
+            signature: None,
         }
     }
 }
 
+/// The reasons a seal may fail verification.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SealError {
+    /// The seal's algorithm is not the one announced in the pre-digest.
+    AlgoMismatch,
+    /// The seal is not signed by the miner named in the pre-digest.
+    BadSignature,
+    /// The hash does not meet the difficulty threshold.
+    InsufficientWork,
+    /// The claimed work was not actually computed from this pre-hash, difficulty and nonces.
+    WorkMismatch,
+}
+
+/// Check that the seal is a genuine solution to the puzzle posed by `pre_hash` and `difficulty`.
+///
+/// This is everything `MultiPow::verify` does except for decoding and the fork rules.
+/// It does not need a client so it can also be used from inside the runtime.
+pub fn check_seal(
+    pre_hash: &H256,
+    algo: SupportedHashes,
+    seal: &Seal,
+    difficulty: Threshold,
+) -> Result<(), SealError> {
+    // Check that the pre-digest algo matches the seal algo
+    if seal.work.algo != algo {
+        return Err(SealError::AlgoMismatch);
+    }
+
+    // See whether the hash meets the difficulty requirement. If not, fail fast.
+    if !multi_hash_meets_difficulty(&seal.work, difficulty) {
+        return Err(SealError::InsufficientWork);
+    }
+
+    // Make sure the provided work actually comes from the correct pre_hash
+    let compute = Compute {
+        difficulty,
+        pre_hash: *pre_hash,
+        nonce: seal.nonce,
+        extra_nonce: seal.extra_nonce,
+    };
+
+    let computed = compute.compute(seal.work.algo);
+    if computed.work != seal.work || computed.difficulty != seal.difficulty {
+        return Err(SealError::WorkMismatch);
+    }
+
+    Ok(())
+}
+
+/// Check that the seal is signed by the miner named in the pre-digest.
+///
+/// The signature is over the pre-hash, which already commits to the pre-digest.
+/// So only the holder of the miner's key can produce a valid signed seal.
+pub fn check_seal_signature(
+    pre_hash: &H256,
+    author: &AccountId32,
+    seal: &Seal,
+) -> Result<(), SealError> {
+    let public = sr25519::Public::from_raw(author.clone().into());
+
+    match &seal.signature {
+        Some(signature) if signature.verify(&pre_hash[..], &public) => Ok(()),
+        _ => Err(SealError::BadSignature),
+    }
+}
+
+/// The reasons `MultiPow::verify` may reject a block.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RejectReason {
+    /// The seal could not be decoded.
+    BadSeal,
+    /// There is no pre-digest, or it could not be decoded.
+    BadPreDigest,
+    /// The fork rules don't allow the seal's algorithm at this height.
+    ForkRules,
+    /// The seal itself is not valid.
+    Seal(SealError),
+}
+
+/// Called with the reason every time `MultiPow::verify` rejects a block.
+#[cfg(feature = "std")]
+pub type RejectHook = Arc<dyn Fn(RejectReason) + Send + Sync>;
+
 #[cfg(feature = "std")]
 /// A complete PoW Algorithm that uses multiple hashing algorithms.
 /// Needs a reference to the client so it can grab the difficulty from the runtime.
 pub struct MultiPow<C> {
     client: Arc<C>,
     fork_config: ForkingConfig,
+    require_signed_seals: bool,
+    on_reject: Option<RejectHook>,
 }
 
 #[cfg(feature = "std")]
@@ -173,15 +329,34 @@ impl<C> MultiPow<C> {
         Self {
             client,
             fork_config,
+            require_signed_seals: false,
+            on_reject: None,
         }
     }
+
+    /// Whether seals must be signed by the miner named in the pre-digest.
+    pub fn with_signed_seals(mut self, require_signed_seals: bool) -> Self {
+        self.require_signed_seals = require_signed_seals;
+        self
+    }
+
+    /// Call `on_reject` every time a block is rejected, eg. to count rejections by reason.
+    pub fn with_reject_hook(mut self, on_reject: RejectHook) -> Self {
+        self.on_reject = Some(on_reject);
+        self
+    }
 }
 
 //TODO could maybe derive clone_no_bound
 #[cfg(feature = "std")]
 impl<C> Clone for MultiPow<C> {
     fn clone(&self) -> Self {
-        Self::new(self.client.clone(), self.fork_config)
+        Self {
+            client: self.client.clone(),
+            fork_config: self.fork_config,
+            require_signed_seals: self.require_signed_seals,
+            on_reject: self.on_reject.clone(),
+        }
     }
 }
 
@@ -218,23 +393,34 @@ where
         seal: &RawSeal,
         difficulty: Self::Difficulty,
     ) -> Result<bool, Error<B>> {
-        // Try to construct a seal object by decoding the raw seal given
-        let seal = match Seal::decode(&mut &seal[..]) {
-            Ok(seal) => seal,
-            Err(_) => return Ok(false),
-        };
-
-        let Some(encoded_pre_digest) = pre_digest else { return Ok(false) };
-        let algo_from_predigest = match SupportedHashes::decode(&mut &encoded_pre_digest[..]) {
-            Ok(algo) => algo,
-            Err(_) => return Ok(false),
-        };
-
-        // Check that the pre-digest algo matches the seal algo
-        // TODO it shouldn't be necessary to have both.
-        if seal.work.algo != algo_from_predigest {
-            return Ok(false);
+        match self.check(parent_id, pre_hash, pre_digest, seal, difficulty) {
+            Ok(()) => Ok(true),
+            Err(reason) => {
+                if let Some(on_reject) = &self.on_reject {
+                    on_reject(reason);
+                }
+                Ok(false)
+            }
         }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C> MultiPow<C> {
+    /// Everything `verify` checks, with the reason a block fails.
+    fn check<B: BlockT<Hash = H256>>(
+        &self,
+        parent_id: &BlockId<B>,
+        pre_hash: &H256,
+        pre_digest: Option<&[u8]>,
+        seal: &RawSeal,
+        difficulty: Threshold,
+    ) -> Result<(), RejectReason>
+    where
+        C: sc_client_api::HeaderBackend<B>,
+    {
+        // Try to construct a seal object by decoding the raw seal given
+        let seal = Seal::decode(&mut &seal[..]).map_err(|_| RejectReason::BadSeal)?;
 
         // This is where we handle forks on the verification side.
         // We will still need to handle it in the mining algorithm somewhere.
@@ -252,36 +438,49 @@ where
         .map_err(|_| ())
         .expect("Block numbers can be converted to u32 (because they are u32)");
 
-        // Here we handle the forking logic according the the node operator's request.
-        let valid_algorithm = match self.fork_config {
-            ForkingConfig::Manual => manual_fork_validation(parent_number, seal.work.algo),
-            ForkingConfig::Automatic(fork_heights, maxi_position) => {
-                auto_fork_validation(parent_number, seal.work.algo, fork_heights, maxi_position)
-            }
-        };
-
-        if !valid_algorithm {
-            return Ok(false);
-        }
-
-        // See whether the hash meets the difficulty requirement. If not, fail fast.
-        if !multi_hash_meets_difficulty(&seal.work, difficulty) {
-            return Ok(false);
-        }
-
-        // Make sure the provided work actually comes from the correct pre_hash
-        let compute = Compute {
+        check_sealed_block(
+            self.fork_config,
+            self.require_signed_seals,
+            parent_number,
+            pre_hash,
+            pre_digest,
+            &seal,
             difficulty,
-            pre_hash: *pre_hash,
-            nonce: seal.nonce,
-        };
+        )
+    }
+}
 
-        if compute.compute(seal.work.algo) != seal {
-            return Ok(false);
-        }
+/// Everything `MultiPow::verify` checks once the seal is decoded and the parent's number known.
+///
+/// Shared with the header verifier, which has no client to look the parent up in.
+fn check_sealed_block(
+    fork_config: ForkingConfig,
+    require_signed_seals: bool,
+    parent_number: u32,
+    pre_hash: &H256,
+    pre_digest: Option<&[u8]>,
+    seal: &Seal,
+    difficulty: Threshold,
+) -> Result<(), RejectReason> {
+    // The pre-digest contains the miner's account followed by the algorithm.
+    let encoded_pre_digest = pre_digest.ok_or(RejectReason::BadPreDigest)?;
+    let (author, algo_from_predigest) =
+        <(AccountId32, SupportedHashes)>::decode(&mut &encoded_pre_digest[..])
+            .map_err(|_| RejectReason::BadPreDigest)?;
+
+    // Here we handle the forking logic according the the node operator's request.
+    if !fork_config.allows(parent_number, seal.work.algo) {
+        return Err(RejectReason::ForkRules);
+    }
 
-        Ok(true)
+    // Optionally make sure the miner actually holds the key they are mining to.
+    if require_signed_seals {
+        check_seal_signature(pre_hash, &author, seal).map_err(RejectReason::Seal)?;
     }
+
+    // The pre-digest algo must match the seal algo, and the work must be genuine.
+    // TODO it shouldn't be necessary to have the algo in both.
+    check_seal(pre_hash, algo_from_predigest, seal, difficulty).map_err(RejectReason::Seal)
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -318,6 +517,18 @@ pub enum ForkingConfig {
     Automatic(ForkHeights, MaxiPosition),
 }
 
+impl ForkingConfig {
+    /// Whether a block sealed with `algo` may be built on a parent at `parent_number`.
+    pub fn allows(&self, parent_number: u32, algo: SupportedHashes) -> bool {
+        match *self {
+            ForkingConfig::Manual => manual_fork_validation(parent_number, algo),
+            ForkingConfig::Automatic(fork_heights, maxi_position) => {
+                auto_fork_validation(parent_number, algo, fork_heights, maxi_position)
+            }
+        }
+    }
+}
+
 impl FromStr for MaxiPosition {
     type Err = ();
 
@@ -387,3 +598,6 @@ fn auto_fork_validation(
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
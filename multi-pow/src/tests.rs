@@ -0,0 +1,791 @@
+use std::collections::HashMap;
+
+use proptest::{collection::vec, prelude::*};
+use sp_consensus_pow::POW_ENGINE_ID;
+use sp_core::Pair;
+use sp_runtime::{
+    testing::{Block as TestBlock, ExtrinsicWrapper, Header},
+    traits::BlakeTwo256,
+    DigestItem,
+};
+use sp_trie::{LayoutV1, MemoryDB, StorageProof, TrieDBMutBuilder, TrieMut};
+
+use super::{headers::*, *};
+
+type Block = TestBlock<ExtrinsicWrapper<()>>;
+
+fn threshold(difficulty: u64) -> Threshold {
+    Threshold {
+        md5: difficulty.into(),
+        sha3: difficulty.into(),
+        keccak: difficulty.into(),
+    }
+}
+
+fn arb_algo() -> impl Strategy<Value = SupportedHashes> {
+    prop_oneof![
+        Just(SupportedHashes::Md5),
+        Just(SupportedHashes::Sha3),
+        Just(SupportedHashes::Keccak),
+    ]
+}
+
+fn arb_u256() -> impl Strategy<Value = U256> {
+    any::<[u8; 32]>().prop_map(|bytes| U256::from_big_endian(&bytes))
+}
+
+fn arb_threshold() -> impl Strategy<Value = Threshold> {
+    (arb_u256(), arb_u256(), arb_u256()).prop_map(|(md5, sha3, keccak)| Threshold {
+        md5,
+        sha3,
+        keccak,
+    })
+}
+
+fn arb_seal() -> impl Strategy<Value = Seal> {
+    (
+        arb_algo(),
+        any::<[u8; 32]>(),
+        arb_threshold(),
+        arb_u256(),
+        any::<u64>(),
+        proptest::option::of(vec(any::<u8>(), 64)),
+    )
+        .prop_map(
+            |(algo, value, difficulty, nonce, extra_nonce, signature)| Seal {
+                work: MultiHash {
+                    algo,
+                    value: H256::from(value),
+                },
+                difficulty,
+                nonce,
+                extra_nonce,
+                signature: signature.map(|bytes| {
+                    sr25519::Signature::from_slice(&bytes).expect("Signatures are 64 bytes")
+                }),
+            },
+        )
+}
+
+proptest! {
+    #[test]
+    fn acceptance_rate_tracks_inverse_difficulty(
+        difficulty in 1u64..=64,
+        hashes in vec(any::<[u8; 32]>(), 2_000),
+    ) {
+        let accepted = hashes
+            .iter()
+            .filter(|hash| simple_hash_meets_difficulty(&H256::from(**hash), difficulty.into()))
+            .count();
+
+        // Allow six standard deviations, so a correct implementation practically never fails.
+        let expected = 1.0 / difficulty as f64;
+        let n = hashes.len() as f64;
+        let tolerance = 6.0 * (expected * (1.0 - expected) / n).sqrt() + 1.0 / n;
+        let rate = accepted as f64 / n;
+        prop_assert!(
+            (rate - expected).abs() <= tolerance,
+            "accepted {} of {} hashes at difficulty {}",
+            accepted,
+            hashes.len(),
+            difficulty,
+        );
+    }
+
+    #[test]
+    fn meeting_a_difficulty_meets_every_lower_one(
+        hash in any::<[u8; 32]>(),
+        a in arb_u256(),
+        b in arb_u256(),
+    ) {
+        let hash = H256::from(hash);
+        let (lower, higher) = if a <= b { (a, b) } else { (b, a) };
+
+        if simple_hash_meets_difficulty(&hash, higher) {
+            prop_assert!(simple_hash_meets_difficulty(&hash, lower));
+        }
+    }
+
+    #[test]
+    fn threshold_is_exactly_max_over_difficulty(difficulty in arb_u256()) {
+        prop_assume!(!difficulty.is_zero());
+        let boundary = U256::MAX / difficulty;
+
+        let mut bytes = [0u8; 32];
+        boundary.to_big_endian(&mut bytes);
+        prop_assert!(simple_hash_meets_difficulty(&H256::from(bytes), difficulty));
+
+        if boundary < U256::MAX {
+            (boundary + 1).to_big_endian(&mut bytes);
+            prop_assert!(!simple_hash_meets_difficulty(&H256::from(bytes), difficulty));
+        }
+    }
+
+    #[test]
+    fn seal_round_trips(seal in arb_seal()) {
+        prop_assert_eq!(Seal::decode(&mut &seal.encode()[..]).ok(), Some(seal));
+    }
+
+    #[test]
+    fn unsigned_seals_encode_without_a_signature(seal in arb_seal()) {
+        let unsigned = (seal.work, seal.difficulty, seal.nonce, seal.extra_nonce).encode();
+        let signed = match &seal.signature {
+            Some(signature) => [unsigned.clone(), signature.encode()].concat(),
+            None => unsigned.clone(),
+        };
+
+        prop_assert_eq!(seal.encode(), signed);
+        prop_assert_eq!(Seal { signature: None, ..seal }.encode(), unsigned);
+    }
+
+    #[test]
+    fn compute_round_trips(
+        difficulty in arb_threshold(),
+        pre_hash in any::<[u8; 32]>(),
+        nonce in arb_u256(),
+        extra_nonce in any::<u64>(),
+    ) {
+        let compute = Compute {
+            difficulty,
+            pre_hash: H256::from(pre_hash),
+            nonce,
+            extra_nonce,
+        };
+        prop_assert_eq!(Compute::decode(&mut &compute.encode()[..]).ok(), Some(compute));
+    }
+
+    #[test]
+    fn threshold_round_trips(threshold in arb_threshold()) {
+        prop_assert_eq!(Threshold::decode(&mut &threshold.encode()[..]).ok(), Some(threshold));
+    }
+}
+
+const FORK_HEIGHTS: ForkHeights = ForkHeights {
+    add_sha3_keccak: 10,
+    remove_md5: 20,
+    split_sha3_keccak: 30,
+};
+
+const ALGOS: [SupportedHashes; 3] = [
+    SupportedHashes::Md5,
+    SupportedHashes::Sha3,
+    SupportedHashes::Keccak,
+];
+
+const MAXI_POSITIONS: [MaxiPosition; 4] = [
+    MaxiPosition::NoMaxi,
+    MaxiPosition::Sha3Maxi,
+    MaxiPosition::KeccakMaxi,
+    MaxiPosition::FollowMining,
+];
+
+/// Which algos are valid in each era, indexed by era, then algo, then maxi position.
+/// The columns are `NoMaxi`, `Sha3Maxi`, `KeccakMaxi` and `FollowMining`.
+/// Nodes resolve `FollowMining` to a concrete position before validating anything.
+const VALIDITY: [[[bool; 4]; 3]; 4] = [
+    // Before sha3 and keccak are added
+    [
+        [true, true, true, true],
+        [false, false, false, false],
+        [false, false, false, false],
+    ],
+    // After sha3 and keccak are added
+    [
+        [true, true, true, true],
+        [true, true, true, true],
+        [true, true, true, true],
+    ],
+    // After md5 is removed
+    [
+        [false, false, false, false],
+        [true, true, true, true],
+        [true, true, true, true],
+    ],
+    // After the contentious split
+    [
+        [false, false, false, false],
+        [true, true, false, false],
+        [true, false, true, false],
+    ],
+];
+
+#[test]
+fn auto_fork_validation_matches_table() {
+    for parent_number in 0..=40 {
+        let era = match parent_number {
+            n if n < FORK_HEIGHTS.add_sha3_keccak => 0,
+            n if n < FORK_HEIGHTS.remove_md5 => 1,
+            n if n < FORK_HEIGHTS.split_sha3_keccak => 2,
+            _ => 3,
+        };
+
+        for (a, algo) in ALGOS.iter().enumerate() {
+            for (m, maxi_position) in MAXI_POSITIONS.iter().enumerate() {
+                assert_eq!(
+                    auto_fork_validation(parent_number, *algo, FORK_HEIGHTS, *maxi_position),
+                    VALIDITY[era][a][m],
+                    "parent {parent_number}, {algo:?}, {maxi_position:?}",
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn auto_fork_validation_far_past_the_split() {
+    for (a, algo) in ALGOS.iter().enumerate() {
+        for (m, maxi_position) in MAXI_POSITIONS.iter().enumerate() {
+            assert_eq!(
+                auto_fork_validation(u32::MAX, *algo, FORK_HEIGHTS, *maxi_position),
+                VALIDITY[3][a][m],
+            );
+        }
+    }
+}
+
+#[test]
+fn manual_fork_validation_only_allows_md5() {
+    for parent_number in [0, 1, 1_000, u32::MAX] {
+        assert!(manual_fork_validation(parent_number, SupportedHashes::Md5));
+        assert!(!manual_fork_validation(
+            parent_number,
+            SupportedHashes::Sha3
+        ));
+        assert!(!manual_fork_validation(
+            parent_number,
+            SupportedHashes::Keccak
+        ));
+    }
+}
+
+/// Just enough of a client for `MultiPow`: a fixed difficulty and some headers.
+#[derive(Clone, Default)]
+struct MockClient {
+    difficulty: Threshold,
+    headers: HashMap<H256, Header>,
+}
+
+impl MockClient {
+    fn with_parent(difficulty: Threshold, parent_number: u64) -> (Self, H256) {
+        let parent = Header::new_from_number(parent_number);
+        let parent_hash = parent.hash();
+        let client = MockClient {
+            difficulty,
+            headers: [(parent_hash, parent)].into_iter().collect(),
+        };
+
+        (client, parent_hash)
+    }
+}
+
+sp_api::mock_impl_runtime_apis! {
+    impl DifficultyApi<Block, Threshold> for MockClient {
+        fn difficulty(&self) -> Threshold {
+            self.difficulty
+        }
+    }
+}
+
+impl sp_blockchain::HeaderBackend<Block> for MockClient {
+    fn header(&self, hash: H256) -> sp_blockchain::Result<Option<Header>> {
+        Ok(self.headers.get(&hash).cloned())
+    }
+
+    // MultiPow only looks up headers, so the rest describes an empty chain.
+    fn info(&self) -> sp_blockchain::Info<Block> {
+        sp_blockchain::Info {
+            best_hash: H256::zero(),
+            best_number: 0,
+            genesis_hash: H256::zero(),
+            finalized_hash: H256::zero(),
+            finalized_number: 0,
+            finalized_state: None,
+            number_leaves: 0,
+            block_gap: None,
+        }
+    }
+
+    fn status(&self, _hash: H256) -> sp_blockchain::Result<sp_blockchain::BlockStatus> {
+        Ok(sp_blockchain::BlockStatus::Unknown)
+    }
+
+    fn number(&self, hash: H256) -> sp_blockchain::Result<Option<u64>> {
+        Ok(self.headers.get(&hash).map(|header| header.number))
+    }
+
+    fn hash(&self, _number: u64) -> sp_blockchain::Result<Option<H256>> {
+        Ok(None)
+    }
+}
+
+/// Everything needed to call `verify` on a mined block.
+struct Mined {
+    pow: MultiPow<MockClient>,
+    parent: BlockId<Block>,
+    pre_hash: H256,
+    pre_digest: Vec<u8>,
+    seal: Seal,
+    difficulty: Threshold,
+}
+
+impl Mined {
+    fn verify(&self) -> bool {
+        PowAlgorithm::<Block>::verify(
+            &self.pow,
+            &self.parent,
+            &self.pre_hash,
+            Some(&self.pre_digest[..]),
+            &self.seal.encode(),
+            self.difficulty,
+        )
+        .expect("Verification itself doesn't fail")
+    }
+}
+
+fn author() -> sr25519::Pair {
+    sr25519::Pair::from_seed(&[7u8; 32])
+}
+
+/// Mine a block with the given algo on top of a parent at the given height.
+fn mine(algo: SupportedHashes, parent_number: u64, fork_config: ForkingConfig) -> Mined {
+    // Low enough that we find a solution within a few tries.
+    let difficulty = threshold(4);
+    let (client, parent_hash) = MockClient::with_parent(difficulty, parent_number);
+    let pre_hash = H256::repeat_byte(42);
+    let pre_digest = (AccountId32::from(author().public()), algo).encode();
+
+    Mined {
+        pow: MultiPow::new(Arc::new(client), fork_config),
+        parent: BlockId::Hash(parent_hash),
+        pre_hash,
+        pre_digest,
+        seal: solve(pre_hash, algo, difficulty),
+        difficulty,
+    }
+}
+
+/// Find a nonce that solves the puzzle. Only feasible for low difficulties.
+fn solve(pre_hash: H256, algo: SupportedHashes, difficulty: Threshold) -> Seal {
+    (0u64..)
+        .map(|nonce| {
+            Compute {
+                difficulty,
+                pre_hash,
+                nonce: nonce.into(),
+                extra_nonce: 0,
+            }
+            .compute(algo)
+        })
+        .find(|seal| multi_hash_meets_difficulty(&seal.work, difficulty))
+        .expect("Some nonce meets such a low difficulty")
+}
+
+#[test]
+fn difficulty_comes_from_the_runtime() {
+    let (client, parent_hash) = MockClient::with_parent(threshold(1234), 0);
+    let pow = MultiPow::new(Arc::new(client), ForkingConfig::Manual);
+
+    assert_eq!(
+        PowAlgorithm::<Block>::difficulty(&pow, parent_hash).ok(),
+        Some(threshold(1234))
+    );
+}
+
+#[test]
+fn verify_accepts_a_genuine_seal() {
+    assert!(mine(SupportedHashes::Md5, 0, ForkingConfig::Manual).verify());
+}
+
+#[test]
+fn verify_rejects_a_tampered_nonce() {
+    let mut mined = mine(SupportedHashes::Md5, 0, ForkingConfig::Manual);
+    mined.seal.nonce += U256::one();
+
+    assert!(!mined.verify());
+}
+
+#[test]
+fn verify_rejects_a_tampered_extra_nonce() {
+    let mut mined = mine(SupportedHashes::Md5, 0, ForkingConfig::Manual);
+    mined.seal.extra_nonce += 1;
+
+    assert!(!mined.verify());
+}
+
+#[test]
+fn verify_rejects_a_higher_difficulty() {
+    let mut mined = mine(SupportedHashes::Md5, 0, ForkingConfig::Manual);
+    mined.difficulty = threshold(u64::MAX);
+
+    assert!(!mined.verify());
+}
+
+#[test]
+fn verify_rejects_a_mismatched_pre_digest() {
+    let mut mined = mine(SupportedHashes::Md5, 0, ForkingConfig::Manual);
+    mined.pre_digest = (AccountId32::from(author().public()), SupportedHashes::Sha3).encode();
+
+    assert!(!mined.verify());
+}
+
+#[test]
+fn verify_rejects_a_missing_pre_digest() {
+    let mined = mine(SupportedHashes::Md5, 0, ForkingConfig::Manual);
+    let verified = PowAlgorithm::<Block>::verify(
+        &mined.pow,
+        &mined.parent,
+        &mined.pre_hash,
+        None,
+        &mined.seal.encode(),
+        mined.difficulty,
+    );
+
+    assert!(matches!(verified, Ok(false)));
+}
+
+#[test]
+fn verify_applies_the_fork_rules_at_the_parent_height() {
+    let auto = ForkingConfig::Automatic(FORK_HEIGHTS, MaxiPosition::Sha3Maxi);
+
+    for parent_number in [0, 9, 10, 19, 20, 29, 30, 100] {
+        for (a, algo) in ALGOS.iter().enumerate() {
+            let era = match parent_number {
+                n if n < 10 => 0,
+                n if n < 20 => 1,
+                n if n < 30 => 2,
+                _ => 3,
+            };
+            assert_eq!(
+                mine(*algo, parent_number, auto).verify(),
+                VALIDITY[era][a][1],
+                "parent {parent_number}, {algo:?}",
+            );
+        }
+    }
+}
+
+#[test]
+fn verify_can_require_signed_seals() {
+    let mut mined = mine(SupportedHashes::Md5, 0, ForkingConfig::Manual);
+    mined.pow = mined.pow.clone().with_signed_seals(true);
+    assert!(!mined.verify(), "Unsigned seals are rejected");
+
+    mined.seal.signature = Some(sr25519::Pair::from_seed(&[8u8; 32]).sign(&mined.pre_hash[..]));
+    assert!(!mined.verify(), "Seals signed by someone else are rejected");
+
+    mined.seal.signature = Some(author().sign(&mined.pre_hash[..]));
+    assert!(mined.verify(), "Seals signed by the author are accepted");
+}
+
+/// A child of `parent` with a pre-digest for `algo`, not sealed yet.
+fn child_of(parent: &Header, algo: SupportedHashes) -> Header {
+    let mut header = Header::new_from_number(parent.number + 1);
+    header.parent_hash = parent.hash();
+    header.digest.push(DigestItem::PreRuntime(
+        POW_ENGINE_ID,
+        (AccountId32::from(author().public()), algo).encode(),
+    ));
+    header
+}
+
+/// Mine the header at the given difficulty, and seal it the way the import queue expects.
+fn seal_header(
+    mut header: Header,
+    algo: SupportedHashes,
+    difficulty: Threshold,
+) -> ProvenHeader<Header> {
+    let seal = solve(header.hash(), algo, difficulty);
+    header
+        .digest
+        .push(DigestItem::Seal(POW_ENGINE_ID, seal.encode()));
+
+    ProvenHeader {
+        header,
+        difficulty_proof: None,
+    }
+}
+
+/// Mine a chain of md5 headers on top of `parent`.
+fn mine_headers(parent: &Header, length: usize) -> Vec<ProvenHeader<Header>> {
+    let mut headers: Vec<ProvenHeader<Header>> = Vec::new();
+    for _ in 0..length {
+        let child = child_of(
+            headers.last().map_or(parent, |proven| &proven.header),
+            SupportedHashes::Md5,
+        );
+        headers.push(seal_header(child, SupportedHashes::Md5, threshold(4)));
+    }
+    headers
+}
+
+fn trust_claimed(minimum: u64) -> HeaderVerifier {
+    HeaderVerifier::new(
+        ForkingConfig::Manual,
+        DifficultyCheck::Claimed {
+            minimum: threshold(minimum),
+        },
+    )
+}
+
+fn difficulty_keys() -> DifficultyKeys {
+    DifficultyKeys::new(
+        "Md5Difficulty",
+        "Sha3Difficulty",
+        "KeccakDifficulty",
+        "Timestamp",
+        "SharedDifficulty",
+    )
+}
+
+/// The runtime's difficulty adjustment params, encoded. Only the idle decay is set.
+fn decay_params(idle_decay_after: u128, idle_decay_time: u128) -> Vec<u8> {
+    let (target_block_time, damp_factor, clamp_factor) = (5_000u128, 3u128, 2u128);
+    let (max_difficulty, min_difficulty) = (U256::from(u128::MAX), U256::one());
+    (
+        target_block_time,
+        damp_factor,
+        clamp_factor,
+        max_difficulty,
+        min_difficulty,
+        idle_decay_after,
+        idle_decay_time,
+    )
+        .encode()
+}
+
+/// The root of a state holding the given values, and a proof of all of it.
+fn proven_state(values: Vec<(Vec<u8>, Vec<u8>)>) -> (H256, StorageProof) {
+    let mut db = MemoryDB::<BlakeTwo256>::default();
+    let mut root = H256::default();
+    {
+        let mut trie = TrieDBMutBuilder::<LayoutV1<BlakeTwo256>>::new(&mut db, &mut root).build();
+        for (key, value) in values {
+            trie.insert(&key, &value)
+                .expect("Inserting into an in-memory trie works");
+        }
+    }
+    let proof = StorageProof::new(db.drain().into_values().map(|(node, _)| node));
+
+    (root, proof)
+}
+
+/// The values of a state where no algorithm has been mined yet.
+fn difficulty_values(difficulty: Threshold) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let keys = difficulty_keys();
+    let mut values = Vec::new();
+    for (algo_keys, value) in [
+        (&keys.md5, difficulty.md5),
+        (&keys.sha3, difficulty.sha3),
+        (&keys.keccak, difficulty.keccak),
+    ] {
+        values.push((algo_keys.current_difficulty.clone(), value.encode()));
+        values.push((algo_keys.params.clone(), decay_params(0, 0)));
+    }
+    values.push((keys.shared_difficulty_enabled, false.encode()));
+    values
+}
+
+/// The root of a state holding the given difficulty, and a proof of all of it.
+fn difficulty_state(difficulty: Threshold) -> (H256, StorageProof) {
+    proven_state(difficulty_values(difficulty))
+}
+
+/// Verify an md5 header mined at `difficulty` on top of a genesis with the given state.
+fn verify_proven(
+    values: Vec<(Vec<u8>, Vec<u8>)>,
+    difficulty: Threshold,
+) -> Result<Threshold, HeaderError> {
+    let verifier = HeaderVerifier::new(
+        ForkingConfig::Manual,
+        DifficultyCheck::StorageProof(difficulty_keys()),
+    );
+    let (state_root, proof) = proven_state(values);
+    let mut genesis = Header::new_from_number(0);
+    genesis.state_root = state_root;
+    let child = child_of(&genesis, SupportedHashes::Md5);
+    let mut proven = seal_header(child, SupportedHashes::Md5, difficulty);
+    proven.difficulty_proof = Some(proof);
+
+    verifier.verify_header(&genesis, &proven)
+}
+
+#[test]
+fn header_chain_adds_up_the_difficulty() {
+    let genesis = Header::new_from_number(0);
+    let headers = mine_headers(&genesis, 3);
+
+    assert_eq!(
+        trust_claimed(4).verify_chain(&genesis, &headers),
+        Ok(threshold(12))
+    );
+}
+
+#[test]
+fn header_chain_reports_the_first_bad_header() {
+    let genesis = Header::new_from_number(0);
+    let mut headers = mine_headers(&genesis, 3);
+    headers[1].header.parent_hash = H256::repeat_byte(1);
+
+    assert_eq!(
+        trust_claimed(4).verify_chain(&genesis, &headers),
+        Err(ChainError {
+            index: 1,
+            error: HeaderError::NotAChild
+        })
+    );
+}
+
+#[test]
+fn header_verification_rejects_a_wrong_number() {
+    let genesis = Header::new_from_number(0);
+    let mut child = child_of(&genesis, SupportedHashes::Md5);
+    child.number = 2;
+    let proven = seal_header(child, SupportedHashes::Md5, threshold(4));
+
+    assert_eq!(
+        trust_claimed(4).verify_header(&genesis, &proven),
+        Err(HeaderError::BadNumber)
+    );
+}
+
+#[test]
+fn header_verification_rejects_an_unsealed_header() {
+    let genesis = Header::new_from_number(0);
+    let proven = ProvenHeader {
+        header: child_of(&genesis, SupportedHashes::Md5),
+        difficulty_proof: None,
+    };
+
+    assert_eq!(
+        trust_claimed(4).verify_header(&genesis, &proven),
+        Err(HeaderError::MissingSeal)
+    );
+}
+
+#[test]
+fn header_verification_rejects_a_tampered_header() {
+    let genesis = Header::new_from_number(0);
+    let mut proven = mine_headers(&genesis, 1).remove(0);
+    proven.header.extrinsics_root = H256::repeat_byte(1);
+
+    assert_eq!(
+        trust_claimed(4).verify_header(&genesis, &proven),
+        Err(HeaderError::Rejected(RejectReason::Seal(
+            SealError::WorkMismatch
+        )))
+    );
+}
+
+#[test]
+fn header_verification_applies_the_fork_rules() {
+    let genesis = Header::new_from_number(0);
+    let child = child_of(&genesis, SupportedHashes::Sha3);
+    let proven = seal_header(child, SupportedHashes::Sha3, threshold(4));
+
+    assert_eq!(
+        trust_claimed(4).verify_header(&genesis, &proven),
+        Err(HeaderError::Rejected(RejectReason::ForkRules))
+    );
+}
+
+#[test]
+fn header_verification_enforces_the_minimum_difficulty() {
+    let genesis = Header::new_from_number(0);
+    let headers = mine_headers(&genesis, 1);
+
+    assert_eq!(
+        trust_claimed(5).verify_header(&genesis, &headers[0]),
+        Err(HeaderError::DifficultyTooLow)
+    );
+}
+
+#[test]
+fn header_difficulty_can_be_proven_from_storage() {
+    let verifier = HeaderVerifier::new(
+        ForkingConfig::Manual,
+        DifficultyCheck::StorageProof(difficulty_keys()),
+    );
+    let (state_root, proof) = difficulty_state(threshold(4));
+    let mut genesis = Header::new_from_number(0);
+    genesis.state_root = state_root;
+    let mut proven = mine_headers(&genesis, 1).remove(0);
+
+    assert_eq!(
+        verifier.verify_header(&genesis, &proven),
+        Err(HeaderError::MissingDifficultyProof)
+    );
+
+    proven.difficulty_proof = Some(proof);
+    assert_eq!(verifier.verify_header(&genesis, &proven), Ok(threshold(4)));
+
+    let other_keys = HeaderVerifier::new(
+        ForkingConfig::Manual,
+        DifficultyCheck::StorageProof(DifficultyKeys::new(
+            "Md5",
+            "Sha3",
+            "Keccak",
+            "Timestamp",
+            "SharedDifficulty",
+        )),
+    );
+    assert_eq!(
+        other_keys.verify_header(&genesis, &proven),
+        Err(HeaderError::BadDifficultyProof)
+    );
+}
+
+#[test]
+fn header_difficulty_must_match_the_proven_state() {
+    let verifier = HeaderVerifier::new(
+        ForkingConfig::Manual,
+        DifficultyCheck::StorageProof(difficulty_keys()),
+    );
+    let (state_root, proof) = difficulty_state(threshold(8));
+    let mut genesis = Header::new_from_number(0);
+    genesis.state_root = state_root;
+    let mut proven = mine_headers(&genesis, 1).remove(0);
+    proven.difficulty_proof = Some(proof);
+
+    assert!(matches!(
+        verifier.verify_header(&genesis, &proven),
+        Err(HeaderError::Rejected(RejectReason::Seal(_)))
+    ));
+}
+
+#[test]
+fn header_difficulty_decays_for_idle_algorithms() {
+    let keys = difficulty_keys();
+    // Md5 was last mined 3 seconds before the parent, so 2 seconds overdue, and decays to a third.
+    let mut values = difficulty_values(Threshold {
+        md5: 12.into(),
+        ..threshold(4)
+    });
+    values.retain(|(key, _)| *key != keys.md5.params);
+    values.push((keys.md5.params.clone(), decay_params(1_000, 1_000)));
+    values.push((keys.md5.last_mined.clone(), 1_000u64.encode()));
+
+    let mut decayed = values.clone();
+    decayed.push((keys.now.clone(), 4_000u64.encode()));
+    assert_eq!(verify_proven(decayed, threshold(4)), Ok(threshold(4)));
+
+    // Without the time that passed, md5 is still at its stored difficulty.
+    let mut fresh = values;
+    fresh.push((keys.now, 1_000u64.encode()));
+    assert!(matches!(
+        verify_proven(fresh, threshold(4)),
+        Err(HeaderError::Rejected(RejectReason::Seal(_)))
+    ));
+}
+
+#[test]
+fn header_difficulty_cannot_be_proven_with_shared_difficulty() {
+    let keys = difficulty_keys();
+    let mut values = difficulty_values(threshold(4));
+    values.retain(|(key, _)| *key != keys.shared_difficulty_enabled);
+    values.push((keys.shared_difficulty_enabled, true.encode()));
+
+    assert_eq!(
+        verify_proven(values, threshold(4)),
+        Err(HeaderError::SharedDifficulty)
+    );
+}
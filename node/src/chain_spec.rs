@@ -8,9 +8,11 @@ use multi_pow::{ForkHeights, ForkingConfig, MaxiPosition};
 use sc_chain_spec::{ChainSpecExtension, ChainSpecGroup};
 use sc_service::ChainType;
 use serde::{Deserialize, Serialize};
-use sp_core::{sr25519, Pair, Public};
+use sp_core::{sr25519, Pair, Public, U256};
 use sp_runtime::traits::{IdentifyAccount, Verify};
 
+use crate::checkpoints::Checkpoint;
+
 /// Specialized `ChainSpec`. This is a specialization of the general Substrate ChainSpec type.
 pub type ChainSpec = sc_service::GenericChainSpec<RuntimeGenesisConfig, ForkingExtensions>;
 
@@ -41,6 +43,13 @@ pub struct ForkingExtensions {
     // Let alone that time I tried to use the forked value feature.
     /// The political position that this node will take at the contentious fork.
     pub maxi_position: String,
+    /// Blocks the chain must contain. Blocks that conflict with them are never imported.
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+    /// Finalize blocks once they have this many blocks' worth of work built on top of them.
+    /// Zero means never finalize anything.
+    #[serde(default)]
+    pub confirmation_depth: u32,
 }
 
 impl From<&ForkingExtensions> for ForkingConfig {
@@ -86,21 +95,47 @@ where
     AccountPublic::from(get_from_seed::<TPublic>(seed)).into_account()
 }
 
+/// The forks of the development chain.
+pub fn development_forks() -> ForkingExtensions {
+    ForkingExtensions {
+        manual_mode: 0,
+        add_sha3_keccak: 10,
+        remove_md5: 20,
+        split_sha3_keccak: 30,
+        maxi_position: String::from("follow-mining"),
+        checkpoints: Vec::new(),
+        confirmation_depth: 0,
+    }
+}
+
 pub fn development_config() -> Result<ChainSpec, String> {
+    development_config_with(development_forks(), 4_000_000, false)
+}
+
+/// The development chain, but with a single difficulty shared by all algorithms.
+pub fn shared_difficulty_development_config() -> Result<ChainSpec, String> {
+    development_config_with(development_forks(), 4_000_000, true)
+}
+
+/// The development chain, but with custom forks, initial difficulty and difficulty mode.
+///
+/// The forks are client-side configuration, so nodes using different forks here still agree
+/// on the genesis block. The difficulty mode is part of the genesis state.
+pub fn development_config_with(
+    forks: ForkingExtensions,
+    initial_difficulty: u32,
+    shared_difficulty: bool,
+) -> Result<ChainSpec, String> {
     Ok(ChainSpec::builder(
         WASM_BINARY.ok_or_else(|| "Development wasm not available".to_string())?,
-        ForkingExtensions {
-            manual_mode: 0,
-            add_sha3_keccak: 10,
-            remove_md5: 20,
-            split_sha3_keccak: 30,
-            maxi_position: String::from("follow-mining"),
-        },
+        forks,
     )
     .with_name("Development")
     .with_id("dev")
     .with_chain_type(ChainType::Development)
     .with_genesis_config_patch(genesis(
+        // Sudo account
+        get_account_id_from_seed::<sr25519::Public>("Alice"),
         // Pre-funded accounts
         vec![
             get_account_id_from_seed::<sr25519::Public>("Alice"),
@@ -109,7 +144,8 @@ pub fn development_config() -> Result<ChainSpec, String> {
             get_account_id_from_seed::<sr25519::Public>("Bob//stash"),
         ],
         // Initial Difficulty
-        4_000_000,
+        initial_difficulty,
+        shared_difficulty,
     ))
     .with_properties(system_properties())
     .build())
@@ -124,32 +160,63 @@ pub fn testnet_config() -> Result<ChainSpec, String> {
             remove_md5: 0,
             split_sha3_keccak: 0,
             maxi_position: String::new(),
+            checkpoints: Vec::new(),
+            confirmation_depth: 0,
         },
     )
     .with_name("Testnet")
     .with_id("testnet")
     .with_chain_type(ChainType::Local)
     .with_genesis_config_patch(genesis(
+        // Sudo account
+        get_account_id_from_seed::<sr25519::Public>("Alice"),
         // Pre-funded accounts
         vec![
             get_account_id_from_seed::<sr25519::Public>("Alice"),
         ],
         4_000_000,
+        false,
     ))
     .with_properties(system_properties())
     .build())
 }
 
-fn genesis(endowed_accounts: Vec<AccountId>, _initial_difficulty: u32) -> serde_json::Value {
+fn genesis(
+    root_key: AccountId,
+    endowed_accounts: Vec<AccountId>,
+    initial_difficulty: u32,
+    shared_difficulty: bool,
+) -> serde_json::Value {
+    // Every algorithm starts out at the same difficulty.
+    let initial_difficulty = U256::from(initial_difficulty);
+
     serde_json::json!({
         "balances": {
             // Configure endowed accounts with initial balance of 1 << 50.
             "balances": endowed_accounts.iter().cloned().map(|k| (k, 1u64 << 50)).collect::<Vec<_>>(),
         },
-        //TODO Figure out how to convert a u32 into a proper json value here.
-        // "difficultyAdjustment": {
-        //     "initialDifficulty": serde_json::json!(initial_difficulty),
-        // },
+        // Root may change the difficulty adjustment parameters through sudo.
+        "sudo": {
+            "key": Some(root_key),
+        },
+        // Start the faucet's pot off with as much as an endowed account.
+        "faucet": {
+            "initialFunds": 1u64 << 50,
+        },
+        "md5DifficultyAdjustment": {
+            "initialDifficulty": initial_difficulty,
+        },
+        "sha3DifficultyAdjustment": {
+            "initialDifficulty": initial_difficulty,
+        },
+        "keccakDifficultyAdjustment": {
+            "initialDifficulty": initial_difficulty,
+        },
+        // Whether the runtime uses the shared difficulty instead of one per algorithm.
+        "sharedDifficulty": {
+            "enabled": shared_difficulty,
+            "initialDifficulty": initial_difficulty,
+        },
     })
 }
 
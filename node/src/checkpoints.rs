@@ -0,0 +1,193 @@
+//! Checkpoints protect a long-lived network against deep reorgs.
+//!
+//! A checkpoint pins the block at a given height. The chain spec carries a list of them, and
+//! node operators can add more with `--checkpoint`. Blocks that conflict with a checkpoint are
+//! rejected at import, no matter how much work is behind them. So once the classroom chain is
+//! checkpointed, nobody can rewrite its history from before the checkpoint by outmining it.
+//!
+//! The `checkpoint-create` subcommand prints the current best block in the format the chain
+//! spec expects, ready to be pasted into its `checkpoints` list.
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use academy_pow_runtime::opaque::Block;
+use sc_consensus::{BlockCheckParams, BlockImport, BlockImportParams, ImportResult};
+use serde::{Deserialize, Serialize};
+use sp_blockchain::{HeaderBackend, HeaderMetadata};
+use sp_consensus::Error as ConsensusError;
+use sp_core::H256;
+use sp_runtime::traits::Header as HeaderT;
+
+/// The block that the chain must contain at a given height.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub hash: H256,
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{:?}", self.height, self.hash)
+    }
+}
+
+impl FromStr for Checkpoint {
+    type Err = String;
+
+    /// Parse a checkpoint given as `HEIGHT:HASH`, eg. `1000:0x1234...`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (height, hash) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected HEIGHT:HASH, got {s}"))?;
+
+        Ok(Checkpoint {
+            height: height
+                .parse()
+                .map_err(|e| format!("Invalid checkpoint height {height}: {e}"))?,
+            hash: hash
+                .parse()
+                .map_err(|e| format!("Invalid checkpoint hash {hash}: {e:?}"))?,
+        })
+    }
+}
+
+/// A block import that rejects blocks conflicting with any of the checkpoints.
+pub struct CheckpointBlockImport<I, C> {
+    inner: I,
+    client: Arc<C>,
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl<I, C> CheckpointBlockImport<I, C> {
+    pub fn new(inner: I, client: Arc<C>, checkpoints: Vec<Checkpoint>) -> Self {
+        Self {
+            inner,
+            client,
+            checkpoints,
+        }
+    }
+}
+
+impl<I: Clone, C> Clone for CheckpointBlockImport<I, C> {
+    fn clone(&self) -> Self {
+        Self::new(
+            self.inner.clone(),
+            self.client.clone(),
+            self.checkpoints.clone(),
+        )
+    }
+}
+
+impl<I, C> CheckpointBlockImport<I, C>
+where
+    C: HeaderBackend<Block> + HeaderMetadata<Block, Error = sp_blockchain::Error>,
+{
+    /// Make sure the block with the given number, hash and parent stays on the chain of the
+    /// checkpoints.
+    ///
+    /// A block at or above a checkpoint must descend from it. Checkpoints are expected to lie on
+    /// one chain, so descending from the highest one below the block means descending from all
+    /// the others too. A block below a checkpoint must be its ancestor, which we can only tell
+    /// once the checkpoint itself has been imported.
+    fn check(&self, number: u32, hash: H256, parent: H256) -> Result<(), ConsensusError> {
+        let conflict = |checkpoint: &Checkpoint| {
+            ConsensusError::ClientImport(format!(
+                "Block {hash:?} at height {number} conflicts with checkpoint {checkpoint}"
+            ))
+        };
+
+        if let Some(checkpoint) = self
+            .checkpoints
+            .iter()
+            .filter(|checkpoint| checkpoint.height <= number)
+            .max_by_key(|checkpoint| checkpoint.height)
+        {
+            let ancestor = if checkpoint.height == number {
+                Some(hash)
+            } else {
+                self.ancestor_at(parent, number - 1, checkpoint.height)?
+            };
+
+            // An unknown parent is the inner import's problem.
+            if ancestor.is_some_and(|ancestor| ancestor != checkpoint.hash) {
+                return Err(conflict(checkpoint));
+            }
+        }
+
+        for checkpoint in self
+            .checkpoints
+            .iter()
+            .filter(|checkpoint| checkpoint.height > number)
+        {
+            // Checkpoints we haven't imported yet don't tell us anything.
+            let ancestor = self.ancestor_at(checkpoint.hash, checkpoint.height, number)?;
+            if ancestor.is_some_and(|ancestor| ancestor != hash) {
+                return Err(conflict(checkpoint));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The hash of the ancestor at `height` of `block`, which is at `block_number`, if we know
+    /// `block`.
+    fn ancestor_at(
+        &self,
+        block: H256,
+        block_number: u32,
+        height: u32,
+    ) -> Result<Option<H256>, ConsensusError> {
+        let client_error = |e: sp_blockchain::Error| ConsensusError::ClientImport(e.to_string());
+
+        // Most blocks build on the best chain, where the database can tell us right away.
+        if self.client.hash(block_number).map_err(client_error)? == Some(block) {
+            return self.client.hash(height).map_err(client_error);
+        }
+
+        // Otherwise walk back along the fork. Forks are short, and the headers are cached.
+        let mut current = block;
+        loop {
+            let header = match self.client.header_metadata(current) {
+                Ok(header) => header,
+                Err(sp_blockchain::Error::UnknownBlock(_)) => return Ok(None),
+                Err(e) => return Err(client_error(e)),
+            };
+            if header.number <= height {
+                return Ok(Some(header.hash));
+            }
+            current = header.parent;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<I, C> BlockImport<Block> for CheckpointBlockImport<I, C>
+where
+    I: BlockImport<Block, Error = ConsensusError> + Send + Sync,
+    C: HeaderBackend<Block> + HeaderMetadata<Block, Error = sp_blockchain::Error> + Send + Sync,
+{
+    type Error = ConsensusError;
+
+    async fn check_block(
+        &mut self,
+        block: BlockCheckParams<Block>,
+    ) -> Result<ImportResult, Self::Error> {
+        self.check(block.number, block.hash, block.parent_hash)?;
+        self.inner.check_block(block).await
+    }
+
+    async fn import_block(
+        &mut self,
+        block: BlockImportParams<Block>,
+    ) -> Result<ImportResult, Self::Error> {
+        self.check(
+            *block.header.number(),
+            block.post_hash(),
+            *block.header.parent_hash(),
+        )?;
+        self.inner.import_block(block).await
+    }
+}
+
+#[cfg(test)]
+mod tests;
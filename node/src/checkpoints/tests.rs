@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use academy_pow_runtime::opaque::Header;
+use sp_blockchain::{BlockStatus, CachedHeaderMetadata, Info};
+use sp_runtime::Digest;
+
+use super::*;
+
+/// A chain of headers, some of them on forks off the best chain.
+#[derive(Default)]
+struct MockChain {
+    headers: HashMap<H256, Header>,
+    best: Vec<H256>,
+}
+
+impl MockChain {
+    /// A best chain of `length` blocks, counting genesis.
+    fn new(length: u32) -> Self {
+        let mut chain = Self::default();
+        let genesis = Header::new(
+            0,
+            H256::zero(),
+            H256::zero(),
+            H256::zero(),
+            Digest::default(),
+        );
+        let mut parent = chain.import(genesis, true);
+        for _ in 1..length {
+            let header = child(&chain.headers[&parent], 0);
+            parent = chain.import(header, true);
+        }
+        chain
+    }
+
+    fn import(&mut self, header: Header, best: bool) -> H256 {
+        let hash = header.hash();
+        if best {
+            self.best.truncate(header.number as usize);
+            self.best.push(hash);
+        }
+        self.headers.insert(hash, header);
+        hash
+    }
+
+    fn best_at(&self, height: u32) -> H256 {
+        self.best[height as usize]
+    }
+}
+
+/// A child of `parent`. Children with different `fork`s are different blocks.
+fn child(parent: &Header, fork: u8) -> Header {
+    Header::new(
+        parent.number + 1,
+        H256::zero(),
+        H256::repeat_byte(fork),
+        parent.hash(),
+        Digest::default(),
+    )
+}
+
+impl HeaderBackend<Block> for MockChain {
+    fn header(&self, hash: H256) -> sp_blockchain::Result<Option<Header>> {
+        Ok(self.headers.get(&hash).cloned())
+    }
+
+    fn info(&self) -> Info<Block> {
+        unimplemented!("Not needed for checking checkpoints")
+    }
+
+    fn status(&self, hash: H256) -> sp_blockchain::Result<BlockStatus> {
+        Ok(match self.headers.contains_key(&hash) {
+            true => BlockStatus::InChain,
+            false => BlockStatus::Unknown,
+        })
+    }
+
+    fn number(&self, hash: H256) -> sp_blockchain::Result<Option<u32>> {
+        Ok(self.headers.get(&hash).map(|header| header.number))
+    }
+
+    fn hash(&self, number: u32) -> sp_blockchain::Result<Option<H256>> {
+        Ok(self.best.get(number as usize).copied())
+    }
+}
+
+impl HeaderMetadata<Block> for MockChain {
+    type Error = sp_blockchain::Error;
+
+    fn header_metadata(&self, hash: H256) -> sp_blockchain::Result<CachedHeaderMetadata<Block>> {
+        self.headers
+            .get(&hash)
+            .map(CachedHeaderMetadata::from)
+            .ok_or_else(|| sp_blockchain::Error::UnknownBlock(format!("{hash:?}")))
+    }
+
+    fn insert_header_metadata(&self, _: H256, _: CachedHeaderMetadata<Block>) {}
+
+    fn remove_header_metadata(&self, _: H256) {}
+}
+
+fn checkpoint_import(
+    chain: MockChain,
+    checkpoints: Vec<Checkpoint>,
+) -> CheckpointBlockImport<(), MockChain> {
+    CheckpointBlockImport::new((), Arc::new(chain), checkpoints)
+}
+
+/// Check `header` against the checkpoints.
+fn check(
+    import: &CheckpointBlockImport<(), MockChain>,
+    header: &Header,
+) -> Result<(), ConsensusError> {
+    import.check(header.number, header.hash(), header.parent_hash)
+}
+
+#[test]
+fn parses_checkpoints() {
+    let hash = H256::repeat_byte(0xab);
+
+    assert_eq!(
+        format!("1000:{hash:?}").parse::<Checkpoint>(),
+        Ok(Checkpoint { height: 1000, hash })
+    );
+    assert!("1000".parse::<Checkpoint>().is_err());
+    assert!(format!("-1:{hash:?}").parse::<Checkpoint>().is_err());
+    assert!("1000:0x1234".parse::<Checkpoint>().is_err());
+}
+
+#[test]
+fn checkpoints_round_trip_through_their_display() {
+    let checkpoint = Checkpoint {
+        height: 42,
+        hash: H256::repeat_byte(0xcd),
+    };
+
+    assert_eq!(checkpoint.to_string().parse::<Checkpoint>(), Ok(checkpoint));
+}
+
+#[test]
+fn accepts_the_checkpointed_chain() {
+    let chain = MockChain::new(10);
+    let checkpoint = Checkpoint {
+        height: 5,
+        hash: chain.best_at(5),
+    };
+    let tip = child(&chain.headers[&chain.best_at(9)], 0);
+    let import = checkpoint_import(chain, vec![checkpoint]);
+
+    assert!(check(&import, &tip).is_ok());
+}
+
+#[test]
+fn rejects_a_fork_from_before_the_checkpoint() {
+    let mut chain = MockChain::new(10);
+    let checkpoint = Checkpoint {
+        height: 5,
+        hash: chain.best_at(5),
+    };
+
+    // A fork off block 3 that has already overtaken the checkpoint.
+    let mut fork = chain.headers[&chain.best_at(3)].clone();
+    for _ in 0..5 {
+        fork = child(&fork, 1);
+        chain.import(fork.clone(), false);
+    }
+    let fork_tip = child(&fork, 1);
+    let import = checkpoint_import(chain, vec![checkpoint]);
+
+    assert!(check(&import, &fork_tip).is_err());
+}
+
+#[test]
+fn rejects_a_fork_below_the_checkpoint() {
+    let chain = MockChain::new(10);
+    let checkpoint = Checkpoint {
+        height: 5,
+        hash: chain.best_at(5),
+    };
+
+    // The fork hasn't reached the checkpoint's height, but it has left its chain.
+    let fork = child(&chain.headers[&chain.best_at(2)], 1);
+    let import = checkpoint_import(chain, vec![checkpoint]);
+
+    assert!(check(&import, &fork).is_err());
+}
+
+#[test]
+fn accepts_a_fork_after_the_checkpoint() {
+    let chain = MockChain::new(10);
+    let checkpoint = Checkpoint {
+        height: 5,
+        hash: chain.best_at(5),
+    };
+    let fork = child(&chain.headers[&chain.best_at(7)], 1);
+    let import = checkpoint_import(chain, vec![checkpoint]);
+
+    assert!(check(&import, &fork).is_ok());
+}
+
+#[test]
+fn accepts_blocks_below_a_checkpoint_it_does_not_know_yet() {
+    let chain = MockChain::new(3);
+    let checkpoint = Checkpoint {
+        height: 5,
+        hash: H256::repeat_byte(0xef),
+    };
+    let block = child(&chain.headers[&chain.best_at(2)], 0);
+    let fork = child(&chain.headers[&chain.best_at(1)], 1);
+    let import = checkpoint_import(chain, vec![checkpoint]);
+
+    assert!(check(&import, &block).is_ok());
+    assert!(check(&import, &fork).is_ok());
+}
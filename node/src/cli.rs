@@ -1,11 +1,25 @@
-use academy_pow_runtime::AccountId;
-use multi_pow::SupportedHashes;
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use academy_pow_runtime::{
+    difficulty::DifficultyAdjustmentParams, opaque::Block, AccountId, Balance,
+};
+use jsonrpsee::ws_client::WsClientBuilder;
+use multi_pow::{MaxiPosition, SupportedHashes};
 use sc_cli::{
     clap::{ArgGroup, Parser},
-    RunCmd,
+    CliConfiguration, DatabaseParams, RunCmd, SharedParams,
 };
 use sc_service::ChainType;
-use sp_core::{crypto::Ss58Codec, sr25519};
+use sp_blockchain::HeaderBackend;
+use sp_core::{crypto::Ss58Codec, sr25519, Pair, H256};
+
+use crate::{
+    checkpoints::Checkpoint,
+    difficulty_simulator::{self, Hashrates, ScheduleEvent},
+    double_spend::{DoubleSpendApiClient, DoubleSpendParams},
+    selfish::WithholdingStrategy,
+    service::{ImportConfig, Sealing},
+};
 
 #[derive(Debug, Parser)]
 #[clap(subcommand_negates_reqs(true), version(env!("SUBSTRATE_CLI_IMPL_VERSION")))]
@@ -22,6 +36,7 @@ pub struct Cli {
 
 #[derive(Debug, Parser, Clone)]
 #[clap(group(ArgGroup::new("backup")))]
+#[clap(group(ArgGroup::new("seal_on_demand").args(["instant_seal", "manual_seal"])))]
 pub struct AcademyPowCli {
     /// Miner's AccountId (base58 encoding of an SR25519 public key) for the block rewards
     #[clap(long,
@@ -37,25 +52,140 @@ pub struct AcademyPowCli {
     )]
     pub mining_public_key: Option<sr25519::Public>,
 
+    /// File holding the miner's secret key URI (eg a mnemonic or `//Alice`). The block rewards
+    /// go to the corresponding account and every seal is signed with this key. The key is read
+    /// from a file so that it doesn't show up in the process list or the shell history.
+    #[clap(
+        long = "mining-key-file",
+        value_name = "PATH",
+        value_parser = parse_mining_key_file,
+        conflicts_with_all = ["mining_account_id", "mining_public_key"]
+    )]
+    pub mining_key: Option<MiningKey>,
+
     /// The mining algorithm to use
     #[clap(long, value_parser = parse_algo, default_value = "md5")]
     pub mining_algo: multi_pow::SupportedHashes,
 
+    /// Keep switching to whichever algorithm the fork rules allow that promises the most blocks
+    /// per second, given this node's hashrate with each and their current difficulties.
+    /// `--mining-algo` is where it starts, and what `follow-mining` follows.
+    #[clap(long, default_value = "false", conflicts_with_all = ["instant_seal", "manual_seal"])]
+    pub profit_switching: bool,
+
+    /// The extra nonce to put in every seal, so that the miners of a pool can each search their
+    /// own part of the nonce space. Random, and new whenever the miner restarts, if not given
+    #[clap(long, conflicts_with_all = ["instant_seal", "manual_seal"])]
+    pub extra_nonce: Option<u64>,
+
+    /// When the fork rules forbid `--mining-algo`, switch to an allowed algorithm instead of
+    /// stopping the miner
+    #[clap(long, default_value = "false", conflicts_with_all = ["instant_seal", "manual_seal"])]
+    pub fork_fallback: bool,
+
+    /// Withhold mined blocks instead of announcing them, to demonstrate selfish mining. Only
+    /// allowed on development chains. Possible values: selfish (publish by the selfish mining
+    /// strategy), manual (publish only through the `withholding_release` RPC)
+    #[clap(
+        long,
+        value_parser = parse_withholding_strategy,
+        conflicts_with_all = ["instant_seal", "manual_seal"]
+    )]
+    pub withhold_blocks: Option<WithholdingStrategy>,
+
+    /// Offer the `attack_doubleSpend` RPC, which mines a private branch that double spends and
+    /// publishes it once it wins. Only allowed on development chains
+    #[clap(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["instant_seal", "manual_seal", "withhold_blocks"]
+    )]
+    pub allow_double_spend: bool,
+
+    /// The political position to take at the contentious fork, overriding the chain spec.
+    /// Possible values: no-maxi, sha3-maxi, keccak-maxi, follow-mining
+    #[clap(long, value_parser = parse_maxi_position)]
+    pub maxi_position: Option<MaxiPosition>,
+
     /// whether to use instant seal
-    #[clap(long, default_value = "false")]
+    #[clap(long, default_value = "false", conflicts_with = "manual_seal")]
     pub instant_seal: bool,
+
+    /// whether to seal blocks only when asked to through the `engine_createBlock` RPC
+    #[clap(long, default_value = "false", conflicts_with = "instant_seal")]
+    pub manual_seal: bool,
+
+    /// With instant or manual seal, mine a genuine seal for every block at the runtime's
+    /// difficulty, so that peers running the regular PoW import accept the blocks. Otherwise
+    /// they carry a dummy seal that only this node accepts
+    #[clap(long, default_value = "false", requires = "seal_on_demand")]
+    pub mine_seals: bool,
+
+    /// Count the work of included uncles towards fork choice, in addition to the main chain's work
+    #[clap(long, default_value = "false")]
+    pub count_uncle_work: bool,
+
+    /// Only accept blocks whose seal is signed by the miner named in the pre-digest
+    #[clap(long, default_value = "false")]
+    pub require_signed_seals: bool,
+
+    /// How far in the future, in seconds, an imported block's timestamp may be
+    #[clap(long, default_value = "30")]
+    pub max_timestamp_drift: u64,
+
+    /// A block the chain must contain, as `HEIGHT:HASH`, in addition to the chain spec's
+    /// checkpoints. Blocks that conflict with it are never imported. May be given repeatedly
+    #[clap(long = "checkpoint", value_parser = parse_checkpoint)]
+    pub checkpoints: Vec<Checkpoint>,
 }
 
 impl AcademyPowCli {
     pub fn public_key_bytes(&self, keyring: Option<sp_keyring::Sr25519Keyring>) -> [u8; 32] {
         match &self.mining_account_id {
             Some(account_id) => *account_id.as_ref(),
-            None => match self.mining_public_key {
-                Some(public_key) => public_key.0,
-                None => keyring.map(|k| k.to_raw_public()).unwrap_or([0u8; 32]),
+            None => match (self.mining_public_key, self.mining_pair()) {
+                (Some(public_key), _) => public_key.0,
+                (None, Some(key)) => key.public().0,
+                (None, None) => keyring.map(|k| k.to_raw_public()).unwrap_or([0u8; 32]),
             },
         }
     }
+
+    /// How the node should seal the blocks it authors
+    pub fn sealing(&self) -> Sealing {
+        if self.instant_seal {
+            Sealing::Instant
+        } else if self.manual_seal {
+            Sealing::Manual
+        } else {
+            Sealing::Pow
+        }
+    }
+
+    /// The rules for which blocks to import, beyond the fork rules
+    pub fn import_config(&self) -> ImportConfig {
+        ImportConfig {
+            count_uncle_work: self.count_uncle_work,
+            require_signed_seals: self.require_signed_seals,
+            max_timestamp_drift: Duration::from_secs(self.max_timestamp_drift),
+            checkpoints: self.checkpoints.clone(),
+        }
+    }
+
+    /// The miner's key pair, if a key file was given
+    pub fn mining_pair(&self) -> Option<sr25519::Pair> {
+        self.mining_key.as_ref().map(|key| key.0.clone())
+    }
+}
+
+/// The miner's key pair. Only its public half is ever printed.
+#[derive(Clone)]
+pub struct MiningKey(sr25519::Pair);
+
+impl std::fmt::Debug for MiningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MiningKey").field(&self.0.public()).finish()
+    }
 }
 
 #[derive(Debug, Parser)]
@@ -83,18 +213,248 @@ pub struct BuildSpecCmd {
     pub initial_difficulty: u32,
 }
 
+#[derive(Debug, Parser)]
+pub struct SimulateDifficultyCmd {
+    /// How many blocks to mine
+    #[arg(long, default_value = "1000")]
+    pub blocks: u64,
+
+    /// The block time the DAA tries to maintain, in milliseconds
+    #[arg(long, default_value_t = DifficultyAdjustmentParams::default().target_block_time)]
+    pub target_block_time: u128,
+
+    /// How slowly the DAA moves toward the observed block time
+    #[arg(long, default_value_t = DifficultyAdjustmentParams::default().damp_factor)]
+    pub damp_factor: u128,
+
+    /// The most the DAA may move away from the target block time in a single adjustment
+    #[arg(long, default_value_t = DifficultyAdjustmentParams::default().clamp_factor)]
+    pub clamp_factor: u128,
+
+    /// The lowest difficulty the DAA may set. Defaults to the damp factor
+    #[arg(long)]
+    pub min_difficulty: Option<u128>,
+
+    /// How long, in milliseconds, an algorithm may go unmined before its difficulty decays
+    #[arg(long, default_value_t = DifficultyAdjustmentParams::default().idle_decay_after)]
+    pub idle_decay_after: u128,
+
+    /// How long, in milliseconds, an idle algorithm takes to halve its difficulty once it starts
+    /// decaying. Zero disables the decay
+    #[arg(long, default_value_t = DifficultyAdjustmentParams::default().idle_decay_time)]
+    pub idle_decay_time: u128,
+
+    /// The difficulty every algorithm starts at
+    #[arg(long, default_value = "4000000")]
+    pub initial_difficulty: u128,
+
+    /// Hashes per second mining with md5 at genesis
+    #[arg(long, default_value = "1000000")]
+    pub md5_hashrate: u64,
+
+    /// Hashes per second mining with sha3 at genesis
+    #[arg(long, default_value = "0")]
+    pub sha3_hashrate: u64,
+
+    /// Hashes per second mining with keccak at genesis
+    #[arg(long, default_value = "0")]
+    pub keccak_hashrate: u64,
+
+    /// Changes to the hashrate, applied just before the given block is mined.
+    /// `BLOCK:ALGO=RATE` steps an algorithm's hashrate to RATE, `BLOCK:ALGO+RATE` and
+    /// `BLOCK:ALGO-RATE` add or remove a miner, and `BLOCK:FROM>TO` moves all of one algorithm's
+    /// hashrate to another. Eg `--event 200:sha3+500000 --event 400:md5>keccak`
+    #[arg(long = "event", value_parser = parse_schedule_event)]
+    pub schedule: Vec<ScheduleEvent>,
+
+    /// Seed for the random block times, so runs can be reproduced
+    #[arg(long, default_value = "0")]
+    pub seed: u64,
+
+    /// Where to write the CSV. Defaults to stdout
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+impl SimulateDifficultyCmd {
+    pub fn run(&self) -> sc_cli::Result<()> {
+        let params = DifficultyAdjustmentParams {
+            target_block_time: self.target_block_time,
+            damp_factor: self.damp_factor,
+            clamp_factor: self.clamp_factor,
+            min_difficulty: self.min_difficulty.unwrap_or(self.damp_factor).into(),
+            idle_decay_after: self.idle_decay_after,
+            idle_decay_time: self.idle_decay_time,
+            ..Default::default()
+        };
+        let mut hashrates = Hashrates::default();
+        hashrates.set(SupportedHashes::Md5, self.md5_hashrate);
+        hashrates.set(SupportedHashes::Sha3, self.sha3_hashrate);
+        hashrates.set(SupportedHashes::Keccak, self.keccak_hashrate);
+
+        let blocks = difficulty_simulator::simulate(
+            params,
+            self.initial_difficulty.into(),
+            hashrates,
+            &self.schedule,
+            self.blocks,
+            self.seed,
+        )
+        .map_err(|e| sc_cli::Error::Input(e.to_string()))?;
+
+        match &self.output {
+            Some(path) => {
+                let mut file = std::fs::File::create(path)?;
+                difficulty_simulator::write_csv(&mut file, &blocks)?;
+            }
+            None => difficulty_simulator::write_csv(&mut std::io::stdout().lock(), &blocks)?,
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct CheckpointCreateCmd {
+    #[allow(missing_docs)]
+    #[clap(flatten)]
+    pub shared_params: SharedParams,
+
+    #[allow(missing_docs)]
+    #[clap(flatten)]
+    pub database_params: DatabaseParams,
+}
+
+impl CliConfiguration for CheckpointCreateCmd {
+    fn shared_params(&self) -> &SharedParams {
+        &self.shared_params
+    }
+
+    fn database_params(&self) -> Option<&DatabaseParams> {
+        Some(&self.database_params)
+    }
+}
+
+impl CheckpointCreateCmd {
+    /// Print the best block as a checkpoint for the chain spec's `checkpoints` list.
+    pub fn run<C: HeaderBackend<Block>>(&self, client: &C) -> sc_cli::Result<()> {
+        let info = client.info();
+        let checkpoint = Checkpoint {
+            height: info.best_number,
+            hash: info.best_hash,
+        };
+
+        let json = serde_json::to_string_pretty(&checkpoint)
+            .map_err(|e| sc_cli::Error::Application(e.into()))?;
+        println!("{json}");
+        eprintln!("Or on the command line: --checkpoint {checkpoint}");
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct DoubleSpendCmd {
+    /// The RPC endpoint of the attacking node
+    #[arg(long, default_value = "ws://127.0.0.1:9944")]
+    pub url: String,
+
+    /// The block to fork from. Everything on top of it is reverted if the attack succeeds
+    #[arg(long)]
+    pub ancestor: H256,
+
+    /// The secret key URI of the account that spends its funds twice
+    #[arg(long, default_value = "//Alice")]
+    pub from: String,
+
+    /// The SS58 address that receives the conflicting transfer
+    #[arg(long)]
+    pub to: String,
+
+    /// How much to transfer, in the smallest unit
+    #[arg(long)]
+    pub amount: Balance,
+
+    /// The most blocks to mine before giving up. Defaults to ten more than the public chain
+    /// has on top of the ancestor
+    #[arg(long)]
+    pub max_blocks: Option<u32>,
+
+    /// How many threads the attacking node mines with, at most one per CPU. Defaults to one
+    /// per CPU
+    #[arg(long)]
+    pub boost: Option<u32>,
+}
+
+impl DoubleSpendCmd {
+    /// Run the attack and print the report.
+    pub fn run(&self) -> sc_cli::Result<()> {
+        let params = DoubleSpendParams {
+            ancestor: self.ancestor,
+            from: self.from.clone(),
+            to: self.to.clone(),
+            amount: self.amount,
+            max_blocks: self.max_blocks,
+            boost: self.boost,
+        };
+        let application_error = |e: jsonrpsee::core::Error| sc_cli::Error::Application(e.into());
+
+        let report = tokio::runtime::Runtime::new()?.block_on(async {
+            // Mining the private branch can take a while.
+            let client = WsClientBuilder::default()
+                .request_timeout(Duration::from_secs(3600))
+                .build(&self.url)
+                .await
+                .map_err(application_error)?;
+            client.double_spend(params).await.map_err(application_error)
+        })?;
+
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|e| sc_cli::Error::Application(e.into()))?;
+        println!("{json}");
+        if !report.published {
+            eprintln!("The private branch never overtook the public chain");
+        }
+
+        Ok(())
+    }
+}
+
 fn parse_algo(s: &str) -> Result<SupportedHashes, String> {
-    Ok(match s {
-        "md" | "Md" | "md5" | "Md5" => SupportedHashes::Md5,
-        "sha" | "sha3" | "Sha" | "Sha3" => SupportedHashes::Sha3,
-        "keccak" | "Keccak" => SupportedHashes::Keccak,
-        s => panic!(
+    s.parse().map_err(|()| {
+        format!(
             "Wrong mining algo: {}. Possible values: md5, sha3, keccak",
             s
-        ),
+        )
     })
 }
 
+fn parse_maxi_position(s: &str) -> Result<MaxiPosition, String> {
+    // `MaxiPosition::from_str` reads anything it doesn't know as following mining, which would
+    // hide typos on the command line. So we only follow mining when asked to.
+    let follow_mining = matches!(&s.to_lowercase()[..], "follow-mining" | "followmining");
+    match MaxiPosition::from_str(s) {
+        Ok(position) if position != MaxiPosition::FollowMining || follow_mining => Ok(position),
+        _ => Err(format!(
+            "Wrong maxi position: {}. Possible values: no-maxi, sha3-maxi, keccak-maxi, \
+             follow-mining",
+            s
+        )),
+    }
+}
+
+fn parse_checkpoint(s: &str) -> Result<Checkpoint, String> {
+    s.parse()
+}
+
+fn parse_withholding_strategy(s: &str) -> Result<WithholdingStrategy, String> {
+    s.parse()
+}
+
+fn parse_schedule_event(s: &str) -> Result<ScheduleEvent, String> {
+    s.parse()
+}
+
 fn parse_chaintype(s: &str) -> Result<ChainType, String> {
     Ok(match s {
         "dev" => ChainType::Development,
@@ -116,6 +476,15 @@ fn parse_sr25519_public_key(s: &str) -> Result<sr25519::Public, String> {
         .expect("Passed string is not a hex encoding of a sr25519 public key"))
 }
 
+/// Read an sr25519 key pair from a file holding its secret URI.
+fn parse_mining_key_file(path: &str) -> Result<MiningKey, String> {
+    let uri = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read the mining key file {path}: {e}"))?;
+    sr25519::Pair::from_string(uri.trim(), None)
+        .map(MiningKey)
+        .map_err(|_| format!("The mining key file {path} does not hold a valid sr25519 secret URI"))
+}
+
 #[derive(Debug, clap::Subcommand)]
 pub enum Subcommand {
     /// Key management cli utilities
@@ -145,4 +514,16 @@ pub enum Subcommand {
 
     /// Db meta columns information.
     ChainInfo(sc_cli::ChainInfoCmd),
+
+    /// Run the difficulty adjustment offline against a simulated hashrate and print a CSV.
+    SimulateDifficulty(SimulateDifficultyCmd),
+
+    /// Print the best block as a checkpoint, in the format of the chain spec.
+    CheckpointCreate(CheckpointCreateCmd),
+
+    /// Double spend on a development chain, through a node started with `--allow-double-spend`.
+    DoubleSpend(DoubleSpendCmd),
 }
+
+#[cfg(test)]
+mod tests;
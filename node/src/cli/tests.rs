@@ -0,0 +1,47 @@
+use super::*;
+
+#[test]
+fn maxi_positions_parse() {
+    assert_eq!(parse_maxi_position("sha3-maxi"), Ok(MaxiPosition::Sha3Maxi));
+    assert_eq!(
+        parse_maxi_position("KeccakMaxi"),
+        Ok(MaxiPosition::KeccakMaxi)
+    );
+    assert_eq!(parse_maxi_position("no-maxi"), Ok(MaxiPosition::NoMaxi));
+    assert_eq!(
+        parse_maxi_position("follow-mining"),
+        Ok(MaxiPosition::FollowMining)
+    );
+}
+
+#[test]
+fn unknown_maxi_positions_are_rejected() {
+    assert!(parse_maxi_position("sha3-maxxi").is_err());
+    assert!(parse_maxi_position("").is_err());
+}
+
+#[test]
+fn extra_nonce_is_optional() {
+    let cli = Cli::try_parse_from(["academy-pow"]).expect("No flags are required");
+    assert_eq!(cli.pow.extra_nonce, None);
+
+    let cli =
+        Cli::try_parse_from(["academy-pow", "--extra-nonce", "7"]).expect("The extra nonce parses");
+    assert_eq!(cli.pow.extra_nonce, Some(7));
+
+    // Only the miner puts it in seals.
+    assert!(Cli::try_parse_from(["academy-pow", "--extra-nonce", "7", "--manual-seal"]).is_err());
+}
+
+#[test]
+fn mined_seals_are_opt_in() {
+    let cli = Cli::try_parse_from(["academy-pow", "--manual-seal"]).expect("Manual seal parses");
+    assert!(!cli.pow.mine_seals);
+
+    let cli = Cli::try_parse_from(["academy-pow", "--instant-seal", "--mine-seals"])
+        .expect("Instant seal can mine its seals");
+    assert!(cli.pow.mine_seals);
+
+    // The miner always mines its seals.
+    assert!(Cli::try_parse_from(["academy-pow", "--mine-seals"]).is_err());
+}
@@ -17,13 +17,13 @@
 use academy_pow_runtime::Block;
 use multi_pow::{ForkingConfig, MaxiPosition};
 use sc_cli::SubstrateCli;
-use sc_service::PartialComponents;
+use sc_service::{error::Error as ServiceError, Configuration, PartialComponents, TaskManager};
 use sp_core::sr25519::Public;
 
 use crate::{
     chain_spec::{self, ForkingExtensions},
     cli::{Cli, Subcommand},
-    service,
+    service::{self, MiningConfig},
 };
 
 impl SubstrateCli for Cli {
@@ -61,6 +61,9 @@ impl SubstrateCli for Cli {
                 &include_bytes!("../../spec.json")[..],
             )?),
             "dev" => Box::new(chain_spec::development_config()?),
+            "dev-shared-difficulty" => {
+                Box::new(chain_spec::shared_difficulty_development_config()?)
+            }
             "local" => Box::new(chain_spec::testnet_config()?),
             path => Box::new(chain_spec::ChainSpec::from_json_file(
                 std::path::PathBuf::from(path),
@@ -87,7 +90,7 @@ pub fn run() -> sc_cli::Result<()> {
                     task_manager,
                     import_queue,
                     ..
-                } = service::new_partial(&config, ForkingConfig::Manual)?;
+                } = service::new_partial(&config, ForkingConfig::Manual, cli.pow.import_config())?;
                 Ok((cmd.run(client, import_queue), task_manager))
             })
         }
@@ -98,7 +101,7 @@ pub fn run() -> sc_cli::Result<()> {
                     client,
                     task_manager,
                     ..
-                } = service::new_partial(&config, ForkingConfig::Manual)?;
+                } = service::new_partial(&config, ForkingConfig::Manual, cli.pow.import_config())?;
                 Ok((cmd.run(client, config.database), task_manager))
             })
         }
@@ -109,7 +112,7 @@ pub fn run() -> sc_cli::Result<()> {
                     client,
                     task_manager,
                     ..
-                } = service::new_partial(&config, ForkingConfig::Manual)?;
+                } = service::new_partial(&config, ForkingConfig::Manual, cli.pow.import_config())?;
                 Ok((cmd.run(client, config.chain_spec), task_manager))
             })
         }
@@ -121,7 +124,7 @@ pub fn run() -> sc_cli::Result<()> {
                     task_manager,
                     import_queue,
                     ..
-                } = service::new_partial(&config, ForkingConfig::Manual)?;
+                } = service::new_partial(&config, ForkingConfig::Manual, cli.pow.import_config())?;
                 Ok((cmd.run(client, import_queue), task_manager))
             })
         }
@@ -137,7 +140,7 @@ pub fn run() -> sc_cli::Result<()> {
                     task_manager,
                     backend,
                     ..
-                } = service::new_partial(&config, ForkingConfig::Manual)?;
+                } = service::new_partial(&config, ForkingConfig::Manual, cli.pow.import_config())?;
                 Ok((cmd.run(client, backend, None), task_manager))
             })
         }
@@ -145,39 +148,77 @@ pub fn run() -> sc_cli::Result<()> {
             let runner = cli.create_runner(cmd)?;
             runner.sync_run(|config| cmd.run::<Block>(&config))
         }
+        Some(Subcommand::SimulateDifficulty(cmd)) => cmd.run(),
+        Some(Subcommand::DoubleSpend(cmd)) => cmd.run(),
+        Some(Subcommand::CheckpointCreate(cmd)) => {
+            let runner = cli.create_runner(cmd)?;
+            runner.sync_run(|config| {
+                let PartialComponents { client, .. } =
+                    service::new_partial(&config, ForkingConfig::Manual, cli.pow.import_config())?;
+                cmd.run(&*client)
+            })
+        }
         None => {
-            // Get the mining account from the cli
-            let bytes: [u8; 32] = cli.pow.public_key_bytes(cli.run.get_keyring());
-            let sr25519_public_key = Public(bytes);
-
             let runner = cli.create_runner(&cli.run)?;
             runner.run_node_until_exit(|config| async move {
-                // Get the forking information from the chain spec extension.
-                // Convert it to a strong type, and fill in the proper maxi position if they are following mining.
-                let forking_extension = ForkingExtensions::try_get(&*config.chain_spec)
-                    .expect("Should be able to get the fork config from the extension");
-                let forking_config = match ForkingConfig::from(forking_extension) {
-                    ForkingConfig::Automatic(fork_heights, MaxiPosition::FollowMining) => {
-                        let maxi_position = match cli.pow.mining_algo {
-                            multi_pow::SupportedHashes::Md5 => MaxiPosition::NoMaxi,
-                            multi_pow::SupportedHashes::Sha3 => MaxiPosition::Sha3Maxi,
-                            multi_pow::SupportedHashes::Keccak => MaxiPosition::KeccakMaxi,
-                        };
-                        ForkingConfig::Automatic(fork_heights, maxi_position)
-                    }
-                    old_config => old_config,
-                };
-
-                service::new_full(
-                    config,
-                    forking_config,
-                    //TODO Combine the following three fields into a MiningConfig analogous to the ForkingConfig
-                    sr25519_public_key,
-                    cli.pow.instant_seal,
-                    cli.pow.mining_algo,
-                )
-                .map_err(sc_cli::Error::Service)
+                new_full_node(&cli, config).map_err(sc_cli::Error::Service)
             })
         }
     }
 }
+
+/// Start a full node as described by the command line.
+///
+/// This is what running the node without a subcommand does. It is public so that tests can
+/// start nodes in-process.
+pub fn new_full_node(cli: &Cli, config: Configuration) -> Result<TaskManager, ServiceError> {
+    // Get the mining account from the cli
+    let bytes: [u8; 32] = cli.pow.public_key_bytes(cli.run.get_keyring());
+    let sr25519_public_key = Public(bytes);
+
+    // Get the forking information from the chain spec extension.
+    // Convert it to a strong type, and fill in the proper maxi position if they are following mining.
+    let forking_extension = ForkingExtensions::try_get(&*config.chain_spec)
+        .expect("Should be able to get the fork config from the extension");
+    let mut followed_algo = None;
+    let forking_config = match ForkingConfig::from(forking_extension) {
+        ForkingConfig::Automatic(fork_heights, maxi_position) => {
+            // The command line may override the position from the chain spec.
+            let maxi_position = match cli.pow.maxi_position.unwrap_or(maxi_position) {
+                MaxiPosition::FollowMining => {
+                    // The position is fixed from here on, so the miner must stick to the algo.
+                    followed_algo = Some(cli.pow.mining_algo);
+                    match cli.pow.mining_algo {
+                        multi_pow::SupportedHashes::Md5 => MaxiPosition::NoMaxi,
+                        multi_pow::SupportedHashes::Sha3 => MaxiPosition::Sha3Maxi,
+                        multi_pow::SupportedHashes::Keccak => MaxiPosition::KeccakMaxi,
+                    }
+                }
+                maxi_position => maxi_position,
+            };
+            ForkingConfig::Automatic(fork_heights, maxi_position)
+        }
+        old_config => old_config,
+    };
+
+    let mining_config = MiningConfig {
+        author: sr25519_public_key,
+        key: cli.pow.mining_pair(),
+        algo: cli.pow.mining_algo,
+        profit_switching: cli.pow.profit_switching,
+        fork_fallback: cli.pow.fork_fallback,
+        followed_algo,
+        extra_nonce: cli.pow.extra_nonce,
+        withholding: cli.pow.withhold_blocks,
+        double_spend: cli.pow.allow_double_spend,
+        sealing: cli.pow.sealing(),
+        mine_seals: cli.pow.mine_seals,
+    };
+
+    service::new_full(
+        config,
+        forking_config,
+        mining_config,
+        cli.pow.import_config(),
+    )
+}
@@ -0,0 +1,174 @@
+//! RPC methods to inspect the difficulty adjustment of each mining algorithm.
+//!
+//! The runtime keeps a window of past difficulties and timestamps for every algorithm. Rather than
+//! having dashboards decode that storage layout themselves, these methods ask the runtime through
+//! its `DifficultyHistoryApi` and `DifficultyAdjustmentApi` and return plain JSON.
+//!
+//! When the runtime uses the shared difficulty, these describe the global window instead, with
+//! the current and next difficulties scaled to the requested algorithm.
+
+use std::sync::Arc;
+
+use academy_pow_runtime::{
+    difficulty::{self, DifficultyAdjustmentApi, DifficultyHistoryApi},
+    opaque::Block,
+};
+use jsonrpsee::{
+    core::RpcResult,
+    proc_macros::rpc,
+    types::error::{CallError, ErrorObject},
+};
+use multi_pow::SupportedHashes;
+use serde::{Deserialize, Serialize};
+use sp_api::ProvideRuntimeApi;
+use sp_blockchain::HeaderBackend;
+use sp_core::{H256, U256};
+use sp_runtime::SaturatedConversion;
+
+/// The difficulty of a single block, and when it was mined.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DifficultyPoint {
+    pub difficulty: U256,
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+/// The difficulty adjustment window of one algorithm, and what the DAA makes of it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DifficultyHistory {
+    /// The last blocks mined with this algorithm, earliest first. With the shared difficulty,
+    /// the last blocks of every algorithm and their global difficulties.
+    pub window: Vec<DifficultyPoint>,
+    /// The difficulty assumed for blocks before the window filled up.
+    pub initial_difficulty: U256,
+    /// The difficulty the next block mined with this algorithm must meet.
+    pub current_difficulty: U256,
+    /// The average block time over the window, in milliseconds.
+    pub average_block_time: u64,
+    /// The difficulty the DAA would set if the next block were mined right on target.
+    pub next_difficulty: U256,
+}
+
+impl From<difficulty::DifficultyHistory<u64>> for DifficultyHistory {
+    fn from(history: difficulty::DifficultyHistory<u64>) -> Self {
+        DifficultyHistory {
+            window: history
+                .window
+                .into_iter()
+                .map(|point| DifficultyPoint {
+                    difficulty: point.difficulty,
+                    timestamp: point.timestamp,
+                })
+                .collect(),
+            initial_difficulty: history.initial_difficulty,
+            current_difficulty: history.current_difficulty,
+            average_block_time: history.average_block_time.saturated_into(),
+            next_difficulty: history.next_difficulty,
+        }
+    }
+}
+
+/// The parameters one algorithm's difficulty is adjusted with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DifficultyAdjustmentParams {
+    /// In milliseconds.
+    pub target_block_time: u64,
+    pub damp_factor: u64,
+    pub clamp_factor: u64,
+    pub max_difficulty: U256,
+    pub min_difficulty: U256,
+    /// In milliseconds.
+    pub idle_decay_after: u64,
+    /// In milliseconds. Zero means idle algorithms don't decay.
+    pub idle_decay_time: u64,
+}
+
+impl From<difficulty::DifficultyAdjustmentParams> for DifficultyAdjustmentParams {
+    fn from(params: difficulty::DifficultyAdjustmentParams) -> Self {
+        DifficultyAdjustmentParams {
+            target_block_time: params.target_block_time.saturated_into(),
+            damp_factor: params.damp_factor.saturated_into(),
+            clamp_factor: params.clamp_factor.saturated_into(),
+            max_difficulty: params.max_difficulty,
+            min_difficulty: params.min_difficulty,
+            idle_decay_after: params.idle_decay_after.saturated_into(),
+            idle_decay_time: params.idle_decay_time.saturated_into(),
+        }
+    }
+}
+
+/// RPC methods to inspect the difficulty adjustment.
+#[rpc(server)]
+pub trait DifficultyApi<Hash> {
+    /// The given algorithm's recent difficulties and block times, and the predicted next
+    /// difficulty, as of the given block or the best block.
+    #[method(name = "difficulty_history")]
+    fn history(&self, algo: String, at: Option<Hash>) -> RpcResult<DifficultyHistory>;
+
+    /// The parameters the given algorithm's difficulty is adjusted with, as of the given block
+    /// or the best block.
+    #[method(name = "difficulty_params")]
+    fn params(&self, algo: String, at: Option<Hash>) -> RpcResult<DifficultyAdjustmentParams>;
+}
+
+/// Implements the `DifficultyApi` by calling into the runtime.
+pub struct Difficulty<C> {
+    client: Arc<C>,
+}
+
+impl<C> Difficulty<C> {
+    pub fn new(client: Arc<C>) -> Self {
+        Self { client }
+    }
+}
+
+fn parse_algo(algo: &str) -> RpcResult<SupportedHashes> {
+    algo.parse().map_err(|()| {
+        CallError::Custom(ErrorObject::owned(
+            jsonrpsee::types::error::INVALID_PARAMS_CODE,
+            format!("Unknown mining algo: {algo}. Possible values: md5, sha3, keccak"),
+            None::<()>,
+        ))
+        .into()
+    })
+}
+
+fn runtime_error(e: impl std::fmt::Display) -> jsonrpsee::core::Error {
+    CallError::Custom(ErrorObject::owned(
+        jsonrpsee::types::error::INTERNAL_ERROR_CODE,
+        "Unable to query the difficulty from the runtime",
+        Some(e.to_string()),
+    ))
+    .into()
+}
+
+impl<C> DifficultyApiServer<H256> for Difficulty<C>
+where
+    C: ProvideRuntimeApi<Block> + HeaderBackend<Block> + Send + Sync + 'static,
+    C::Api: DifficultyHistoryApi<Block, u64> + DifficultyAdjustmentApi<Block>,
+{
+    fn history(&self, algo: String, at: Option<H256>) -> RpcResult<DifficultyHistory> {
+        let algo = parse_algo(&algo)?;
+        let at = at.unwrap_or_else(|| self.client.info().best_hash);
+
+        self.client
+            .runtime_api()
+            .difficulty_history(at, algo)
+            .map(Into::into)
+            .map_err(runtime_error)
+    }
+
+    fn params(&self, algo: String, at: Option<H256>) -> RpcResult<DifficultyAdjustmentParams> {
+        let algo = parse_algo(&algo)?;
+        let at = at.unwrap_or_else(|| self.client.info().best_hash);
+
+        self.client
+            .runtime_api()
+            .params(at, algo)
+            .map(Into::into)
+            .map_err(runtime_error)
+    }
+}
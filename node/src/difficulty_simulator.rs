@@ -0,0 +1,401 @@
+//! An offline simulator for the difficulty adjustment algorithm.
+//!
+//! The runtime's `difficulty` pallet is plugged into a tiny mock runtime with one instance per
+//! hashing algorithm, just like the real runtime. Blocks are then "mined" against a simulated
+//! hashrate schedule: every algorithm's miners race to find the next block, and whoever wins
+//! decides which instance adjusts its difficulty. This makes it cheap to try out different
+//! target block time, damp factor and clamp factor before setting them on a real network.
+
+use std::{fmt, io::Write, str::FromStr};
+
+use academy_pow_runtime::difficulty::{self, DifficultyAdjustmentParams};
+use frame_support::{
+    construct_runtime,
+    instances::{Instance1, Instance2, Instance3},
+    parameter_types,
+    traits::{ConstU32, ConstU64, Everything, OnFinalize, Time},
+};
+use multi_pow::SupportedHashes;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use sp_core::{H256, U256};
+use sp_runtime::{
+    traits::{BlakeTwo256, IdentityLookup},
+    BuildStorage,
+};
+
+type Block = frame_system::mocking::MockBlock<Sim>;
+
+construct_runtime!(
+    pub enum Sim {
+        System: frame_system,
+        Md5Difficulty: difficulty::<Instance1>,
+        Sha3Difficulty: difficulty::<Instance2>,
+        KeccakDifficulty: difficulty::<Instance3>,
+    }
+);
+
+parameter_types! {
+    /// The simulated wall clock, in milliseconds.
+    pub static Now: u64 = 0;
+    /// The algorithm the block being finalized was mined with.
+    pub static MinedWith: SupportedHashes = SupportedHashes::Md5;
+}
+
+impl frame_system::Config for Sim {
+    type BaseCallFilter = Everything;
+    type BlockWeights = ();
+    type BlockLength = ();
+    type AccountId = u64;
+    type RuntimeCall = RuntimeCall;
+    type Lookup = IdentityLookup<u64>;
+    type Hash = H256;
+    type Hashing = BlakeTwo256;
+    type RuntimeEvent = RuntimeEvent;
+    type RuntimeOrigin = RuntimeOrigin;
+    type BlockHashCount = ConstU64<250>;
+    type DbWeight = ();
+    type Version = ();
+    type PalletInfo = PalletInfo;
+    type OnNewAccount = ();
+    type OnKilledAccount = ();
+    type AccountData = ();
+    type SystemWeightInfo = ();
+    type SS58Prefix = ();
+    type OnSetCode = ();
+    type MaxConsumers = ConstU32<16>;
+    type Nonce = u64;
+    type Block = Block;
+}
+
+/// Reads the simulated wall clock instead of the timestamp pallet.
+pub struct SimTime;
+
+impl Time for SimTime {
+    type Moment = u64;
+
+    fn now() -> u64 {
+        Now::get()
+    }
+}
+
+impl difficulty::Config<Instance1> for Sim {
+    type RuntimeEvent = RuntimeEvent;
+    type TimeProvider = SimTime;
+
+    fn relevant_to_this_instance() -> bool {
+        MinedWith::get() == SupportedHashes::Md5
+    }
+}
+
+impl difficulty::Config<Instance2> for Sim {
+    type RuntimeEvent = RuntimeEvent;
+    type TimeProvider = SimTime;
+
+    fn relevant_to_this_instance() -> bool {
+        MinedWith::get() == SupportedHashes::Sha3
+    }
+}
+
+impl difficulty::Config<Instance3> for Sim {
+    type RuntimeEvent = RuntimeEvent;
+    type TimeProvider = SimTime;
+
+    fn relevant_to_this_instance() -> bool {
+        MinedWith::get() == SupportedHashes::Keccak
+    }
+}
+
+const ALGOS: [SupportedHashes; 3] = [
+    SupportedHashes::Md5,
+    SupportedHashes::Sha3,
+    SupportedHashes::Keccak,
+];
+
+fn algo_name(algo: SupportedHashes) -> &'static str {
+    match algo {
+        SupportedHashes::Md5 => "md5",
+        SupportedHashes::Sha3 => "sha3",
+        SupportedHashes::Keccak => "keccak",
+    }
+}
+
+fn algo_index(algo: SupportedHashes) -> usize {
+    match algo {
+        SupportedHashes::Md5 => 0,
+        SupportedHashes::Sha3 => 1,
+        SupportedHashes::Keccak => 2,
+    }
+}
+
+/// How much hashrate, in hashes per second, is mining with each algorithm.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hashrates([u64; 3]);
+
+impl Hashrates {
+    pub fn get(&self, algo: SupportedHashes) -> u64 {
+        self.0[algo_index(algo)]
+    }
+
+    pub fn set(&mut self, algo: SupportedHashes, hashrate: u64) {
+        self.0[algo_index(algo)] = hashrate;
+    }
+}
+
+/// A change to the hashrate that happens before a given block is mined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScheduleEvent {
+    pub block: u64,
+    pub change: HashrateChange,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashrateChange {
+    /// The algorithm's hashrate steps to exactly this value.
+    Set(SupportedHashes, u64),
+    /// A miner with this hashrate starts mining with the algorithm.
+    Join(SupportedHashes, u64),
+    /// A miner with this hashrate stops mining with the algorithm.
+    Leave(SupportedHashes, u64),
+    /// All the hashrate on the first algorithm moves to the second.
+    Switch(SupportedHashes, SupportedHashes),
+}
+
+impl HashrateChange {
+    fn apply(&self, hashrates: &mut Hashrates) {
+        match *self {
+            HashrateChange::Set(algo, hashrate) => hashrates.set(algo, hashrate),
+            HashrateChange::Join(algo, hashrate) => {
+                hashrates.set(algo, hashrates.get(algo).saturating_add(hashrate))
+            }
+            HashrateChange::Leave(algo, hashrate) => {
+                hashrates.set(algo, hashrates.get(algo).saturating_sub(hashrate))
+            }
+            HashrateChange::Switch(from, to) => {
+                let moved = hashrates.get(from);
+                hashrates.set(from, 0);
+                hashrates.set(to, hashrates.get(to).saturating_add(moved));
+            }
+        }
+    }
+}
+
+/// Parses `BLOCK:ALGO=RATE`, `BLOCK:ALGO+RATE`, `BLOCK:ALGO-RATE` and `BLOCK:FROM>TO`.
+impl FromStr for ScheduleEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_algo = |algo: &str| {
+            SupportedHashes::from_str(algo).map_err(|()| format!("Unknown algorithm: {}", algo))
+        };
+        let parse_hashrate = |hashrate: &str| {
+            hashrate
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid hashrate {}: {}", hashrate, e))
+        };
+
+        let (block, change) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected BLOCK:CHANGE, got {}", s))?;
+        let block = block
+            .parse()
+            .map_err(|e| format!("Invalid block number {}: {}", block, e))?;
+
+        let change = if let Some((from, to)) = change.split_once('>') {
+            HashrateChange::Switch(parse_algo(from)?, parse_algo(to)?)
+        } else if let Some((algo, hashrate)) = change.split_once('=') {
+            HashrateChange::Set(parse_algo(algo)?, parse_hashrate(hashrate)?)
+        } else if let Some((algo, hashrate)) = change.split_once('+') {
+            HashrateChange::Join(parse_algo(algo)?, parse_hashrate(hashrate)?)
+        } else if let Some((algo, hashrate)) = change.split_once('-') {
+            HashrateChange::Leave(parse_algo(algo)?, parse_hashrate(hashrate)?)
+        } else {
+            return Err(format!(
+                "Expected ALGO=RATE, ALGO+RATE, ALGO-RATE or FROM>TO, got {}",
+                change
+            ));
+        };
+
+        Ok(ScheduleEvent { block, change })
+    }
+}
+
+/// One simulated block.
+#[derive(Clone, Copy, Debug)]
+pub struct SimulatedBlock {
+    pub number: u64,
+    pub algo: SupportedHashes,
+    /// Milliseconds since the previous block.
+    pub block_time: u64,
+    pub timestamp: u64,
+    /// Every algorithm's difficulty after this block was finalized.
+    pub difficulties: [U256; 3],
+    /// The hashrate that was mining while this block was found.
+    pub hashrates: Hashrates,
+}
+
+#[derive(Debug)]
+pub enum SimulationError {
+    /// The parameters would be rejected by the pallet.
+    InvalidParams(&'static str),
+    /// Nobody is mining, so the next block would never be found.
+    NoHashrate(u64),
+}
+
+impl fmt::Display for SimulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SimulationError::InvalidParams(reason) => {
+                write!(f, "Invalid difficulty adjustment params: {}", reason)
+            }
+            SimulationError::NoHashrate(block) => {
+                write!(f, "There is no hashrate left to mine block {}", block)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SimulationError {}
+
+fn difficulties() -> [U256; 3] {
+    [
+        Md5Difficulty::difficulty(),
+        Sha3Difficulty::difficulty(),
+        KeccakDifficulty::difficulty(),
+    ]
+}
+
+/// Mine `blocks` blocks on top of genesis, changing the hashrate as the schedule says.
+///
+/// A hash meets a difficulty `d` with probability `1/d`, so miners with hashrate `h` find blocks
+/// at a rate of `h/d` per second. The time to the next block is exponentially distributed with
+/// the sum of those rates, and each algorithm wins in proportion to its own rate.
+pub fn simulate(
+    params: DifficultyAdjustmentParams,
+    initial_difficulty: U256,
+    initial: Hashrates,
+    schedule: &[ScheduleEvent],
+    blocks: u64,
+    seed: u64,
+) -> Result<Vec<SimulatedBlock>, SimulationError> {
+    difficulty::Pallet::<Sim, Instance1>::ensure_params_valid(&params)
+        .map_err(|e| SimulationError::InvalidParams(e.into()))?;
+    Now::set(&0);
+
+    let genesis = || difficulty::GenesisConfig {
+        _ph_data: Default::default(),
+        initial_difficulty,
+        target_block_time: params.target_block_time,
+        damp_factor: params.damp_factor,
+        clamp_factor: params.clamp_factor,
+        max_difficulty: params.max_difficulty,
+        min_difficulty: params.min_difficulty,
+        idle_decay_after: params.idle_decay_after,
+        idle_decay_time: params.idle_decay_time,
+    };
+    let storage = RuntimeGenesisConfig {
+        system: Default::default(),
+        md5_difficulty: genesis(),
+        sha3_difficulty: genesis(),
+        keccak_difficulty: genesis(),
+    }
+    .build_storage()
+    .expect("The simulator's genesis config is valid");
+
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut hashrates = initial;
+    let mut output = Vec::with_capacity(blocks as usize);
+
+    sp_io::TestExternalities::new(storage).execute_with(|| {
+        for number in 1..=blocks {
+            schedule
+                .iter()
+                .filter(|event| event.block == number)
+                .for_each(|event| event.change.apply(&mut hashrates));
+
+            // Blocks found per second with each algorithm.
+            let current = difficulties();
+            let rates = ALGOS.map(|algo| {
+                hashrates.get(algo) as f64 / current[algo_index(algo)].low_u128() as f64
+            });
+            let total_rate: f64 = rates.iter().sum();
+            if total_rate <= 0.0 {
+                return Err(SimulationError::NoHashrate(number));
+            }
+
+            // `gen` is in [0, 1), so flip it to keep away from ln(0).
+            let seconds = -(1.0 - rng.gen::<f64>()).ln() / total_rate;
+            let block_time = (seconds * 1000.0).round() as u64;
+
+            let mut winner = rng.gen::<f64>() * total_rate;
+            let algo = ALGOS
+                .into_iter()
+                .zip(rates)
+                .find(|(_, rate)| {
+                    winner -= rate;
+                    winner < 0.0
+                })
+                .map(|(algo, _)| algo)
+                // Float rounding can leave a sliver at the end; give it to the last algorithm
+                // that is actually mining.
+                .unwrap_or_else(|| {
+                    *ALGOS
+                        .iter()
+                        .rev()
+                        .find(|algo| hashrates.get(**algo) > 0)
+                        .expect("The total rate is positive")
+                });
+
+            Now::mutate(|now| *now += block_time);
+            MinedWith::set(&algo);
+            System::set_block_number(number);
+            <Md5Difficulty as OnFinalize<u64>>::on_finalize(number);
+            <Sha3Difficulty as OnFinalize<u64>>::on_finalize(number);
+            <KeccakDifficulty as OnFinalize<u64>>::on_finalize(number);
+
+            output.push(SimulatedBlock {
+                number,
+                algo,
+                block_time,
+                timestamp: Now::get(),
+                difficulties: difficulties(),
+                hashrates,
+            });
+        }
+
+        Ok(())
+    })?;
+
+    Ok(output)
+}
+
+/// Write the simulated blocks as CSV, one row per block.
+pub fn write_csv(out: &mut impl Write, blocks: &[SimulatedBlock]) -> std::io::Result<()> {
+    writeln!(
+        out,
+        "block,algo,block_time_ms,timestamp_ms,\
+         md5_difficulty,sha3_difficulty,keccak_difficulty,\
+         md5_hashrate,sha3_hashrate,keccak_hashrate"
+    )?;
+
+    for block in blocks {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{}",
+            block.number,
+            algo_name(block.algo),
+            block.block_time,
+            block.timestamp,
+            block.difficulties[0],
+            block.difficulties[1],
+            block.difficulties[2],
+            block.hashrates.get(SupportedHashes::Md5),
+            block.hashrates.get(SupportedHashes::Sha3),
+            block.hashrates.get(SupportedHashes::Keccak),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,151 @@
+use super::*;
+
+const MD5: SupportedHashes = SupportedHashes::Md5;
+const SHA3: SupportedHashes = SupportedHashes::Sha3;
+
+/// Only md5 is mined, at a million hashes per second.
+fn md5_miners() -> Hashrates {
+    let mut hashrates = Hashrates::default();
+    hashrates.set(MD5, 1_000_000);
+    hashrates
+}
+
+fn average_block_time(blocks: &[SimulatedBlock]) -> u64 {
+    blocks.iter().map(|block| block.block_time).sum::<u64>() / blocks.len() as u64
+}
+
+#[test]
+fn schedule_events_parse() {
+    let parse = |s: &str| ScheduleEvent::from_str(s).map(|event| (event.block, event.change));
+
+    assert_eq!(parse("10:md5=500"), Ok((10, HashrateChange::Set(MD5, 500))));
+    assert_eq!(
+        parse("20:sha3+30"),
+        Ok((20, HashrateChange::Join(SHA3, 30)))
+    );
+    assert_eq!(
+        parse("30:sha3-30"),
+        Ok((30, HashrateChange::Leave(SHA3, 30)))
+    );
+    assert_eq!(
+        parse("40:md5>sha3"),
+        Ok((40, HashrateChange::Switch(MD5, SHA3)))
+    );
+
+    assert!(parse("md5=500").is_err());
+    assert!(parse("10:blake=500").is_err());
+    assert!(parse("10:md5=lots").is_err());
+    assert!(parse("10:md5").is_err());
+}
+
+#[test]
+fn the_difficulty_settles_on_the_target_block_time() {
+    let params = DifficultyAdjustmentParams::default();
+    // Blocks start out five times faster than the target.
+    let blocks = simulate(params, 1_000_000.into(), md5_miners(), &[], 1_000, 42)
+        .expect("The default params are valid");
+
+    let settled = &blocks[700..];
+    let average = average_block_time(settled);
+    assert!(
+        (3_000..=8_000).contains(&average),
+        "Blocks should come about every {} ms, not {} ms",
+        params.target_block_time,
+        average,
+    );
+    let difficulty = settled.last().expect("Blocks were mined").difficulties[0];
+    assert!(difficulty > U256::from(2_500_000) && difficulty < U256::from(10_000_000));
+}
+
+#[test]
+fn simulations_are_reproducible() {
+    let run = |seed| {
+        simulate(
+            DifficultyAdjustmentParams::default(),
+            1_000_000.into(),
+            md5_miners(),
+            &[],
+            100,
+            seed,
+        )
+        .expect("The default params are valid")
+        .iter()
+        .map(|block| (block.algo, block.block_time, block.difficulties))
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
+
+#[test]
+fn switched_hashrate_mines_the_new_algorithm() {
+    let schedule = [ScheduleEvent {
+        block: 50,
+        change: HashrateChange::Switch(MD5, SHA3),
+    }];
+    let blocks = simulate(
+        DifficultyAdjustmentParams::default(),
+        1_000_000.into(),
+        md5_miners(),
+        &schedule,
+        100,
+        42,
+    )
+    .expect("The default params are valid");
+
+    assert!(blocks[..49].iter().all(|block| block.algo == MD5));
+    assert!(blocks[49..].iter().all(|block| block.algo == SHA3));
+    assert_eq!(blocks[49].hashrates.get(SHA3), 1_000_000);
+    assert_eq!(blocks[49].hashrates.get(MD5), 0);
+}
+
+#[test]
+fn simulations_stop_when_nobody_mines() {
+    let schedule = [ScheduleEvent {
+        block: 10,
+        change: HashrateChange::Leave(MD5, 1_000_000),
+    }];
+    let result = simulate(
+        DifficultyAdjustmentParams::default(),
+        1_000_000.into(),
+        md5_miners(),
+        &schedule,
+        100,
+        42,
+    );
+
+    assert!(matches!(result, Err(SimulationError::NoHashrate(10))));
+}
+
+#[test]
+fn invalid_params_are_rejected() {
+    let params = DifficultyAdjustmentParams {
+        target_block_time: 0,
+        ..Default::default()
+    };
+    let result = simulate(params, 1_000_000.into(), md5_miners(), &[], 10, 42);
+
+    assert!(matches!(result, Err(SimulationError::InvalidParams(_))));
+}
+
+#[test]
+fn csv_has_a_row_per_block() {
+    let blocks = simulate(
+        DifficultyAdjustmentParams::default(),
+        1_000_000.into(),
+        md5_miners(),
+        &[],
+        5,
+        42,
+    )
+    .expect("The default params are valid");
+    let mut csv = Vec::new();
+    write_csv(&mut csv, &blocks).expect("Writing to a Vec succeeds");
+
+    let csv = String::from_utf8(csv).expect("The CSV is UTF-8");
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert!(lines[0].starts_with("block,algo,"));
+    assert!(lines[1].starts_with("1,md5,"));
+}
@@ -0,0 +1,625 @@
+//! Double spend attacks, for demonstrating why merchants wait for confirmations.
+//!
+//! The attacker pays a merchant on the public chain, waits for the merchant to accept the
+//! payment, and meanwhile mines a private branch from just before the payment. The branch
+//! spends the same funds again, to an account the attacker controls. Once the branch has more
+//! work than the public chain, the attacker publishes it. Every node reorgs to it, and the
+//! payment to the merchant disappears, because its nonce is already used up.
+//!
+//! The `attack_doubleSpend` RPC runs the attacker's side. It builds the private branch itself,
+//! since the transaction pool would refuse the conflicting transfer, and mines it with as many
+//! threads as it is given. Its blocks go through the node's usual PoW block import. The branch
+//! is mined with timestamps as close together as the runtime allows, so an attacker with more
+//! hashrate than the rest of the network catches up quickly.
+//!
+//! This is only meant for development chains. The node doesn't let the network announce the
+//! blocks it imports, and announces everything except the private branch itself.
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use academy_pow_runtime::{
+    opaque::{Block, Header},
+    AccountId, Address, Balance, BalancesCall, Index, MinimumPeriod, PreDigest, Runtime,
+    RuntimeCall, Signature, SignedExtra, TimestampCall, UncheckedExtrinsic, VERSION,
+};
+use frame_support::traits::Get;
+use futures::StreamExt;
+use jsonrpsee::{
+    core::{async_trait, RpcResult},
+    proc_macros::rpc,
+    types::error::{CallError, ErrorObject},
+};
+use multi_pow::{multi_hash_meets_difficulty, Compute, MultiPow, Seal, SupportedHashes, Threshold};
+use parity_scale_codec::{Decode, Encode};
+use sc_block_builder::BlockBuilderBuilder;
+use sc_client_api::{BlockBackend, BlockchainEvents};
+use sc_consensus::{BlockImportParams, BoxBlockImport, ImportResult, StateAction, StorageChanges};
+use sc_consensus_pow::{PowAlgorithm, PowIntermediate, INTERMEDIATE_KEY};
+use sc_rpc_api::DenyUnsafe;
+use serde::{Deserialize, Serialize};
+use sp_api::ProvideRuntimeApi;
+use sp_block_builder::BlockBuilder;
+use sp_blockchain::HeaderBackend;
+use sp_consensus::BlockOrigin;
+use sp_consensus_pow::{DifficultyApi, POW_ENGINE_ID};
+use sp_core::{crypto::Ss58Codec, sr25519, Pair, H256, U256};
+use sp_inherents::InherentDataProvider;
+use sp_runtime::{
+    generic::{Era, SignedPayload},
+    traits::{BlakeTwo256, Block as BlockT, Hash, Header as HeaderT},
+    Digest, DigestItem, OpaqueExtrinsic,
+};
+use substrate_frame_rpc_system::AccountNonceApi;
+
+use crate::{
+    mining::AllowedAlgos, rpc::invalid_params, selfish::Announce, service::BlockImportFactory,
+};
+
+/// Unless told otherwise, the attacker gives up once the private branch is this many blocks
+/// longer than the public chain was when the attack started.
+const DEFAULT_EXTRA_BLOCKS: u32 = 10;
+
+/// Sign a transfer of `amount` from `from` to `to`, valid on the chain with the given genesis.
+pub fn signed_transfer(
+    genesis_hash: H256,
+    from: &sr25519::Pair,
+    to: AccountId,
+    amount: Balance,
+    nonce: Index,
+) -> UncheckedExtrinsic {
+    let call = RuntimeCall::Balances(BalancesCall::transfer_keep_alive {
+        dest: to.into(),
+        value: amount,
+    });
+    let extra: SignedExtra = (
+        frame_system::CheckNonZeroSender::<Runtime>::new(),
+        frame_system::CheckSpecVersion::<Runtime>::new(),
+        frame_system::CheckTxVersion::<Runtime>::new(),
+        frame_system::CheckGenesis::<Runtime>::new(),
+        frame_system::CheckEra::<Runtime>::from(Era::Immortal),
+        frame_system::CheckNonce::<Runtime>::from(nonce),
+        frame_system::CheckWeight::<Runtime>::new(),
+        pallet_transaction_payment::ChargeTransactionPayment::<Runtime>::from(0),
+    );
+    let payload = SignedPayload::from_raw(
+        call.clone(),
+        extra.clone(),
+        (
+            (),
+            VERSION.spec_version,
+            VERSION.transaction_version,
+            genesis_hash,
+            genesis_hash,
+            (),
+            (),
+            (),
+        ),
+    );
+    let signature = payload.using_encoded(|payload| from.sign(payload));
+
+    UncheckedExtrinsic::new_signed(
+        call,
+        AccountId::from(from.public()).into(),
+        Signature::Sr25519(signature),
+        extra,
+    )
+}
+
+/// What to attack, and how hard.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DoubleSpendParams {
+    /// The block to fork from. The private branch replaces everything on top of it.
+    pub ancestor: H256,
+    /// The secret key URI of the account that spends its funds twice, eg. `//Alice`.
+    pub from: String,
+    /// The SS58 address that receives the conflicting transfer.
+    pub to: String,
+    /// How much to transfer.
+    pub amount: Balance,
+    /// The most blocks to mine before giving up. Defaults to ten more than the public chain
+    /// has on top of the ancestor.
+    #[serde(default)]
+    pub max_blocks: Option<u32>,
+    /// How many threads to mine with, at most one per CPU. Defaults to one per CPU.
+    #[serde(default)]
+    pub boost: Option<u32>,
+}
+
+/// How an attack went.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DoubleSpendReport {
+    /// The block the private branch forks from.
+    pub ancestor: H256,
+    /// The hash of the conflicting transfer.
+    pub transfer: H256,
+    /// The blocks of the private branch, oldest first.
+    pub branch: Vec<H256>,
+    /// Whether the branch overtook the public chain and was published.
+    pub published: bool,
+    /// The transactions on the public chain that drop out of the best chain when the branch is
+    /// published, oldest first.
+    pub reverted: Vec<RevertedTransaction>,
+}
+
+/// A transaction that a published private branch dropped out of the best chain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevertedTransaction {
+    /// The hash of the transaction.
+    pub hash: H256,
+    /// Whether the conflicting transfer spent its nonce, so it is reverted for good. Any other
+    /// transaction goes back to the pool.
+    pub conflicts: bool,
+}
+
+/// Mines private branches that double spend, and publishes them once they win.
+pub struct DoubleSpend<C> {
+    client: Arc<C>,
+    block_import: BlockImportFactory,
+    algorithm: MultiPow<C>,
+    allowed_algos: AllowedAlgos,
+    algo: SupportedHashes,
+    author: sr25519::Public,
+    key: Option<sr25519::Pair>,
+    /// The blocks of the private branch, which must not be announced yet.
+    private: Arc<Mutex<HashSet<H256>>>,
+    announce: Announce,
+    /// Held for as long as an attack runs, so that only one runs at a time.
+    running: tokio::sync::Mutex<()>,
+}
+
+impl<C> DoubleSpend<C>
+where
+    C: ProvideRuntimeApi<Block> + HeaderBackend<Block> + BlockBackend<Block>,
+    C: Send + Sync + 'static,
+    C::Api: BlockBuilder<Block>
+        + AccountNonceApi<Block, AccountId, Index>
+        + DifficultyApi<Block, Threshold>,
+{
+    /// Attack with blocks mined by `author`, preferably with `algo`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        client: Arc<C>,
+        block_import: BlockImportFactory,
+        algorithm: MultiPow<C>,
+        allowed_algos: AllowedAlgos,
+        algo: SupportedHashes,
+        author: sr25519::Public,
+        key: Option<sr25519::Pair>,
+        announce: Announce,
+    ) -> Self {
+        Self {
+            client,
+            block_import,
+            algorithm,
+            allowed_algos,
+            algo,
+            author,
+            key,
+            private: Default::default(),
+            announce,
+            running: Default::default(),
+        }
+    }
+
+    fn private(&self) -> MutexGuard<'_, HashSet<H256>> {
+        self.private
+            .lock()
+            .expect("The private branch lock is never poisoned; qed")
+    }
+
+    /// Announce every imported block that isn't part of a private branch, like the network
+    /// would if we let it.
+    pub fn announce_public_blocks(&self) -> impl Future<Output = ()> + Send + 'static
+    where
+        C: BlockchainEvents<Block>,
+    {
+        let mut imports = self.client.import_notification_stream();
+        let private = self.private.clone();
+        let announce = self.announce.clone();
+
+        async move {
+            while let Some(notification) = imports.next().await {
+                let is_private = private
+                    .lock()
+                    .expect("The private branch lock is never poisoned; qed")
+                    .contains(&notification.hash);
+                if !is_private {
+                    announce(notification.hash);
+                }
+            }
+        }
+    }
+
+    /// Mine a private branch from `ancestor` that transfers `amount` from `from` to `to`, and
+    /// publish it once it has more work than the public chain.
+    pub async fn attack(
+        &self,
+        ancestor: H256,
+        from: sr25519::Pair,
+        to: AccountId,
+        amount: Balance,
+        max_blocks: Option<u32>,
+        threads: u32,
+    ) -> Result<DoubleSpendReport, String> {
+        let _running = self
+            .running
+            .try_lock()
+            .map_err(|_| "A double spend is already under way".to_string())?;
+
+        let info = self.client.info();
+        let ancestor_number = self
+            .client
+            .number(ancestor)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("Unknown block {ancestor:?}"))?;
+        if self
+            .client
+            .hash(ancestor_number)
+            .map_err(|e| e.to_string())?
+            != Some(ancestor)
+        {
+            return Err(format!("Block {ancestor:?} is not in the best chain"));
+        }
+        let max_blocks =
+            max_blocks.unwrap_or(info.best_number - ancestor_number + DEFAULT_EXTRA_BLOCKS);
+
+        // Spend the nonce that the public chain spends after the ancestor.
+        let from_account = AccountId::from(from.public());
+        let nonce = self
+            .client
+            .runtime_api()
+            .account_nonce(ancestor, from_account.clone())
+            .map_err(|e| e.to_string())?;
+        let transfer = signed_transfer(info.genesis_hash, &from, to, amount, nonce);
+        let transfer_hash = BlakeTwo256::hash_of(&transfer);
+        let transfer = OpaqueExtrinsic::from_bytes(&transfer.encode())
+            .expect("A runtime extrinsic is a valid opaque extrinsic; qed");
+
+        log::info!(
+            "💸 Double spending {transfer_hash:?} on a private branch from #{ancestor_number}"
+        );
+
+        let mut block_import = (self.block_import)();
+        let mut branch = Vec::new();
+        let (mut parent, mut parent_number) = (ancestor, ancestor_number);
+        let mut timestamp = self.timestamp(ancestor)?;
+
+        while (branch.len() as u32) < max_blocks {
+            let public_best = self.client.info().best_hash;
+
+            // Keep the timestamps as close together as the runtime allows.
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("The clock is past the epoch")
+                .as_millis() as u64;
+            timestamp = now.max(timestamp + MinimumPeriod::get());
+
+            let extrinsics = if branch.is_empty() {
+                vec![transfer.clone()]
+            } else {
+                Vec::new()
+            };
+            let hash = self
+                .mine_block(
+                    &mut block_import,
+                    parent,
+                    parent_number,
+                    timestamp,
+                    extrinsics,
+                    threads,
+                )
+                .await?;
+            branch.push(hash);
+            (parent, parent_number) = (hash, parent_number + 1);
+
+            // Our fork choice picks the branch as soon as it has more work.
+            if self.client.info().best_hash == hash {
+                let reverted =
+                    self.reverted_transactions(public_best, ancestor_number, &from_account, nonce)?;
+                let conflicting = reverted.iter().filter(|reverted| reverted.conflicts);
+                self.publish(&branch);
+                log::info!(
+                    "💸 Published a private branch of {} blocks, reverting {} transactions, {} \
+                     of them for good",
+                    branch.len(),
+                    reverted.len(),
+                    conflicting.count(),
+                );
+
+                return Ok(DoubleSpendReport {
+                    ancestor,
+                    transfer: transfer_hash,
+                    branch,
+                    published: true,
+                    reverted,
+                });
+            }
+        }
+
+        log::info!(
+            "💸 Gave up on the private branch after {} blocks",
+            branch.len()
+        );
+        Ok(DoubleSpendReport {
+            ancestor,
+            transfer: transfer_hash,
+            branch,
+            published: false,
+            reverted: Vec::new(),
+        })
+    }
+
+    /// Build a block with the given extrinsics on top of `parent`, mine it and import it
+    /// without announcing it.
+    async fn mine_block(
+        &self,
+        block_import: &mut BoxBlockImport<Block>,
+        parent: H256,
+        parent_number: u32,
+        timestamp: u64,
+        extrinsics: Vec<OpaqueExtrinsic>,
+        threads: u32,
+    ) -> Result<H256, String> {
+        let allowed = (self.allowed_algos)(parent);
+        let algo = if allowed.contains(&self.algo) {
+            self.algo
+        } else {
+            *allowed.first().ok_or_else(|| {
+                format!("The fork rules allow no algorithm on top of #{parent_number}")
+            })?
+        };
+
+        let inherent_data = (
+            sp_timestamp::InherentDataProvider::new(timestamp.into()),
+            academy_pow_runtime::block_author::InherentDataProvider(self.author.encode()),
+            academy_pow_runtime::uncles::InherentDataProvider(Vec::<Header>::new()),
+        )
+            .create_inherent_data()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let pre_digest = PreDigest::from((self.author.into(), algo)).encode();
+        let mut builder = BlockBuilderBuilder::new(&*self.client)
+            .on_parent_block(parent)
+            .with_parent_block_number(parent_number)
+            .with_inherent_digests(Digest {
+                logs: vec![DigestItem::PreRuntime(POW_ENGINE_ID, pre_digest)],
+            })
+            .build()
+            .map_err(|e| e.to_string())?;
+        let inherents = builder
+            .create_inherents(inherent_data)
+            .map_err(|e| e.to_string())?;
+        for extrinsic in inherents.into_iter().chain(extrinsics) {
+            builder
+                .push(extrinsic)
+                .map_err(|e| format!("The private block can't include an extrinsic: {e}"))?;
+        }
+        let built = builder.build().map_err(|e| e.to_string())?;
+        let (header, body) = built.block.deconstruct();
+
+        let difficulty = PowAlgorithm::<Block>::difficulty(&self.algorithm, parent)
+            .map_err(|e| e.to_string())?;
+        let pre_hash = header.hash();
+        let mut seal =
+            tokio::task::spawn_blocking(move || mine(pre_hash, difficulty, algo, threads))
+                .await
+                .map_err(|e| e.to_string())?;
+        if let Some(key) = &self.key {
+            seal.signature = Some(key.sign(&pre_hash[..]));
+        }
+
+        let mut import = BlockImportParams::new(BlockOrigin::Own, header);
+        import
+            .post_digests
+            .push(DigestItem::Seal(POW_ENGINE_ID, seal.encode()));
+        import.body = Some(body);
+        import.state_action =
+            StateAction::ApplyChanges(StorageChanges::Changes(built.storage_changes));
+        import.insert_intermediate(
+            INTERMEDIATE_KEY,
+            PowIntermediate::<Threshold> {
+                difficulty: Some(difficulty),
+            },
+        );
+
+        // Mark the block private before anyone hears of its import.
+        let hash = import.post_hash();
+        self.private().insert(hash);
+
+        match block_import.import_block(import).await {
+            Ok(ImportResult::Imported(_)) => Ok(hash),
+            Ok(result) => Err(format!("The private block was not imported: {result:?}")),
+            Err(e) => Err(format!("The private block was not imported: {e}")),
+        }
+    }
+
+    /// Announce the blocks of a branch that has overtaken the public chain.
+    fn publish(&self, branch: &[H256]) {
+        let mut private = self.private();
+        for hash in branch {
+            private.remove(hash);
+            (self.announce)(*hash);
+        }
+    }
+
+    /// The timestamp of the given block.
+    fn timestamp(&self, hash: H256) -> Result<u64, String> {
+        let body = self
+            .client
+            .block_body(hash)
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("No body for block {hash:?}"))?;
+
+        // The genesis block has no timestamp.
+        Ok(body
+            .iter()
+            .find_map(
+                |extrinsic| match UncheckedExtrinsic::decode(&mut &extrinsic.encode()[..]) {
+                    Ok(UncheckedExtrinsic {
+                        function: RuntimeCall::Timestamp(TimestampCall::set { now }),
+                        ..
+                    }) => Some(now),
+                    _ => None,
+                },
+            )
+            .unwrap_or_default())
+    }
+
+    /// The transactions in the chain ending at `tip`, above `number`, oldest first. Those that
+    /// `from` signed with the given nonce conflict with the double spend.
+    fn reverted_transactions(
+        &self,
+        tip: H256,
+        number: u32,
+        from: &AccountId,
+        nonce: Index,
+    ) -> Result<Vec<RevertedTransaction>, String> {
+        let from = Address::Id(from.clone());
+        let mut reverted = Vec::new();
+        let mut current = self.client.header(tip).map_err(|e| e.to_string())?;
+
+        while let Some(header) = current.filter(|header| *header.number() > number) {
+            let body = self
+                .client
+                .block_body(header.hash())
+                .map_err(|e| e.to_string())?
+                .unwrap_or_default();
+            for extrinsic in body.iter().rev() {
+                let Ok(UncheckedExtrinsic {
+                    signature: Some((signer, _, extra)),
+                    ..
+                }) = UncheckedExtrinsic::decode(&mut &extrinsic.encode()[..])
+                else {
+                    continue;
+                };
+
+                let (_, _, _, _, _, check_nonce, _, _) = extra;
+                reverted.push(RevertedTransaction {
+                    hash: BlakeTwo256::hash_of(extrinsic),
+                    conflicts: signer == from && check_nonce.0 == nonce,
+                });
+            }
+            current = self
+                .client
+                .header(*header.parent_hash())
+                .map_err(|e| e.to_string())?;
+        }
+
+        reverted.reverse();
+        Ok(reverted)
+    }
+}
+
+/// Find a seal for the given pre-hash, hashing on `threads` threads.
+fn mine(pre_hash: H256, difficulty: Threshold, algo: SupportedHashes, threads: u32) -> Seal {
+    let found = AtomicBool::new(false);
+    let seal = Mutex::new(None);
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| {
+                // Every thread searches its own slice of the extra nonces.
+                let extra_nonce: u64 = rand::random();
+                let mut nonce = U256::from(rand::random::<u64>());
+
+                while !found.load(Ordering::Relaxed) {
+                    let attempt = Compute {
+                        difficulty,
+                        pre_hash,
+                        nonce,
+                        extra_nonce,
+                    }
+                    .compute(algo);
+
+                    if multi_hash_meets_difficulty(&attempt.work, difficulty) {
+                        found.store(true, Ordering::Relaxed);
+                        *seal.lock().expect("The seal lock is never poisoned; qed") = Some(attempt);
+                    }
+                    nonce = nonce.overflowing_add(U256::one()).0;
+                }
+            });
+        }
+    });
+
+    seal.into_inner()
+        .expect("The seal lock is never poisoned; qed")
+        .expect("The threads only stop once one of them found a seal; qed")
+}
+
+/// RPC methods to run double spend attacks.
+#[rpc(client, server)]
+pub trait DoubleSpendApi {
+    /// Mine a private branch that double spends, and publish it once it has more work than the
+    /// public chain. Returns once the branch is published or the attacker gives up. This is an
+    /// unsafe method.
+    #[method(name = "attack_doubleSpend")]
+    async fn double_spend(&self, params: DoubleSpendParams) -> RpcResult<DoubleSpendReport>;
+}
+
+/// Implements the `DoubleSpendApi` on top of the attacker.
+pub struct DoubleSpendRpc<C> {
+    double_spend: Arc<DoubleSpend<C>>,
+    deny_unsafe: DenyUnsafe,
+}
+
+impl<C> DoubleSpendRpc<C> {
+    pub fn new(double_spend: Arc<DoubleSpend<C>>, deny_unsafe: DenyUnsafe) -> Self {
+        Self {
+            double_spend,
+            deny_unsafe,
+        }
+    }
+}
+
+#[async_trait]
+impl<C> DoubleSpendApiServer for DoubleSpendRpc<C>
+where
+    C: ProvideRuntimeApi<Block> + HeaderBackend<Block> + BlockBackend<Block>,
+    C: Send + Sync + 'static,
+    C::Api: BlockBuilder<Block>
+        + AccountNonceApi<Block, AccountId, Index>
+        + DifficultyApi<Block, Threshold>,
+{
+    async fn double_spend(&self, params: DoubleSpendParams) -> RpcResult<DoubleSpendReport> {
+        self.deny_unsafe.check_if_safe()?;
+
+        let from = sr25519::Pair::from_string(&params.from, None)
+            .map_err(|_| invalid_params(format!("Invalid secret URI: {}", params.from)))?;
+        let to = AccountId::from_ss58check(&params.to)
+            .map_err(|_| invalid_params(format!("Invalid SS58 address: {}", params.to)))?;
+        // More threads than CPUs only slow the node down.
+        let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get() as u32);
+        let threads = params.boost.map_or(cpus, |boost| boost.clamp(1, cpus));
+
+        self.double_spend
+            .attack(
+                params.ancestor,
+                from,
+                to,
+                params.amount,
+                params.max_blocks,
+                threads,
+            )
+            .await
+            .map_err(|e| {
+                CallError::Custom(ErrorObject::owned(
+                    jsonrpsee::types::error::INTERNAL_ERROR_CODE,
+                    "The double spend failed",
+                    Some(e),
+                ))
+                .into()
+            })
+    }
+}
@@ -0,0 +1,301 @@
+//! Probabilistic finality for a chain that has none.
+//!
+//! Proof of work never makes a block final. Any chain with more work can replace it. But the
+//! deeper a block is buried, the less likely that becomes, and at some point we are happy to
+//! treat it as settled. The gadget here finalizes every block that has a given number of
+//! blocks' worth of work built on top of it. This lets the node prune, and refuses any reorg
+//! that deep, so a late chain with more work can't undo settled payments either.
+//!
+//! Depth is measured in work rather than in blocks, because blocks don't all have the same
+//! difficulty. Each algorithm's difficulty is in its own unit though, so every block on top
+//! counts as its difficulty over the difficulty of its algorithm at the confirmed block. A
+//! block counts as `N` confirmations deep once those add up to `N`, ie. once the work on top of
+//! it would have mined `N` blocks at its own difficulties.
+//!
+//! The `pow_confirmations` RPC tells wallets how deep a block is, and how likely an attacker
+//! is to still reorg it.
+
+use std::sync::Arc;
+
+use academy_pow_runtime::opaque::{Block, Header};
+use futures::StreamExt;
+use jsonrpsee::{
+    core::RpcResult,
+    proc_macros::rpc,
+    types::error::{CallError, ErrorObject},
+};
+use multi_pow::{Seal, SupportedHashes, Threshold};
+use parity_scale_codec::Decode;
+use sc_client_api::{Backend, BlockchainEvents, Finalizer};
+use serde::{Deserialize, Serialize};
+use sp_blockchain::HeaderBackend;
+use sp_consensus_pow::POW_ENGINE_ID;
+use sp_core::H256;
+use sp_runtime::{traits::Header as HeaderT, DigestItem};
+
+use crate::rpc::invalid_params;
+
+/// The share of the hashrate that is assumed to be attacking, unless the caller says otherwise.
+const DEFAULT_ATTACKER_SHARE: f64 = 0.1;
+
+/// The most blocks the RPC walks back from the best block to add up the work on top of a block.
+/// Blocks deeper than this count only this many blocks' worth of work.
+const MAX_CONFIRMATION_WALK: u32 = 10_000;
+
+/// The seal of a block, if it has one. Only genesis doesn't.
+///
+/// The import checks that the seal's difficulty is the real one, so we can take its word here.
+fn seal(header: &Header) -> Option<Seal> {
+    header.digest().logs.iter().find_map(|item| match item {
+        DigestItem::Seal(POW_ENGINE_ID, seal) => Seal::decode(&mut &seal[..]).ok(),
+        _ => None,
+    })
+}
+
+/// The work built on top of a block.
+///
+/// Difficulties of different algorithms aren't in the same unit, so it is added up separately
+/// for each of them.
+#[derive(Default)]
+struct WorkOnTop {
+    work: Threshold,
+    blocks: u32,
+}
+
+impl WorkOnTop {
+    fn add(&mut self, header: &Header) {
+        self.blocks += 1;
+        let Some(seal) = seal(header) else {
+            return;
+        };
+
+        let work = seal.difficulty.for_algo(seal.work.algo);
+        let total = match seal.work.algo {
+            SupportedHashes::Md5 => &mut self.work.md5,
+            SupportedHashes::Sha3 => &mut self.work.sha3,
+            SupportedHashes::Keccak => &mut self.work.keccak,
+        };
+        *total = total.saturating_add(work);
+    }
+
+    /// How many blocks the work on top would have mined at the difficulties in effect when the
+    /// block with the given seal was mined, to three decimals. Without a seal, every block on
+    /// top counts as one.
+    fn confirmations(&self, seal: Option<&Seal>) -> f64 {
+        let Some(seal) = seal else {
+            return self.blocks as f64;
+        };
+
+        [
+            SupportedHashes::Md5,
+            SupportedHashes::Sha3,
+            SupportedHashes::Keccak,
+        ]
+        .into_iter()
+        .filter(|algo| !seal.difficulty.for_algo(*algo).is_zero())
+        .map(|algo| {
+            let ratio = self.work.for_algo(algo).saturating_mul(1000.into())
+                / seal.difficulty.for_algo(algo);
+            ratio.low_u128() as f64 / 1000.0
+        })
+        .sum()
+    }
+}
+
+/// The highest block in the chain ending at `best` with at least `depth` confirmations on top
+/// of it, if it isn't finalized yet.
+fn finalizable<C>(client: &C, best: H256, depth: u32) -> sp_blockchain::Result<Option<H256>>
+where
+    C: HeaderBackend<Block>,
+{
+    let finalized = client.info().finalized_number;
+    let mut work_on_top = WorkOnTop::default();
+    let mut current = client.header(best)?;
+
+    while let Some(header) = current {
+        if *header.number() <= finalized {
+            return Ok(None);
+        }
+
+        if work_on_top.confirmations(seal(&header).as_ref()) >= depth as f64 {
+            return Ok(Some(header.hash()));
+        }
+
+        work_on_top.add(&header);
+        current = client.header(*header.parent_hash())?;
+    }
+
+    Ok(None)
+}
+
+/// Finalize blocks once they are `depth` confirmations deep in the best chain.
+pub async fn run_confirmation_finality<C, BE>(client: Arc<C>, depth: u32)
+where
+    C: BlockchainEvents<Block> + HeaderBackend<Block> + Finalizer<Block, BE>,
+    BE: Backend<Block>,
+{
+    let mut imports = client.import_notification_stream();
+
+    while let Some(notification) = imports.next().await {
+        if !notification.is_new_best {
+            continue;
+        }
+
+        let finalized =
+            finalizable(client.as_ref(), notification.hash, depth).and_then(|hash| match hash {
+                Some(hash) => client.finalize_block(hash, None, true).map(|()| Some(hash)),
+                None => Ok(None),
+            });
+
+        match finalized {
+            Ok(Some(hash)) => log::debug!("Finalized {hash:?}, {depth} confirmations deep"),
+            Ok(None) => {}
+            Err(e) => log::warn!("Failed to finalize a block {depth} confirmations deep: {e}"),
+        }
+    }
+}
+
+/// The probability that an attacker with the given share of the hashrate ever catches up
+/// from `confirmations` blocks behind, as computed in section 11 of the Bitcoin whitepaper.
+pub fn reorg_probability(attacker_share: f64, confirmations: u32) -> f64 {
+    let q = attacker_share;
+    let p = 1.0 - q;
+    if q >= p {
+        return 1.0;
+    }
+    if q <= 0.0 {
+        return if confirmations == 0 { 1.0 } else { 0.0 };
+    }
+
+    // While the honest miners find `confirmations` blocks, the attacker finds a Poisson
+    // distributed number of them. Work in logarithms so that deep blocks don't underflow.
+    let lambda = confirmations as f64 * q / p;
+    let mut ln_poisson = -lambda;
+    let mut probability = 1.0;
+    for k in 0..=confirmations {
+        if k > 0 {
+            ln_poisson += lambda.ln() - (k as f64).ln();
+        }
+        let catch_up = (q / p).powi((confirmations - k) as i32);
+        probability -= ln_poisson.exp() * (1.0 - catch_up);
+    }
+
+    probability.clamp(0.0, 1.0)
+}
+
+/// How settled a block is.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Confirmations {
+    pub number: u32,
+    /// Whether the block is part of the best chain. If not, it has no confirmations.
+    pub in_best_chain: bool,
+    /// How many blocks are built on top of this one in the best chain.
+    pub depth: u32,
+    /// How many blocks the work built on top of this one would have mined at its difficulties.
+    pub confirmations: f64,
+    /// Whether the node has finalized the block, so it will never reorg it.
+    pub finalized: bool,
+    /// The probability that an attacker with the given share of the hashrate reorgs the block.
+    pub reorg_probability: f64,
+}
+
+/// Work out how settled the block with the given hash is.
+pub fn confirmations<C>(
+    client: &C,
+    hash: H256,
+    attacker_share: f64,
+) -> sp_blockchain::Result<Option<Confirmations>>
+where
+    C: HeaderBackend<Block>,
+{
+    let Some(header) = client.header(hash)? else {
+        return Ok(None);
+    };
+    let number = *header.number();
+    let info = client.info();
+
+    if client.hash(number)? != Some(hash) {
+        return Ok(Some(Confirmations {
+            number,
+            in_best_chain: false,
+            depth: 0,
+            confirmations: 0.0,
+            finalized: false,
+            reorg_probability: 1.0,
+        }));
+    }
+
+    let depth = info.best_number.saturating_sub(number);
+    let mut work_on_top = WorkOnTop::default();
+    let mut current = client.header(info.best_hash)?;
+    while let Some(above) = current.filter(|above| {
+        *above.number() > number && info.best_number - *above.number() < MAX_CONFIRMATION_WALK
+    }) {
+        work_on_top.add(&above);
+        current = client.header(*above.parent_hash())?;
+    }
+
+    let confirmations = work_on_top.confirmations(seal(&header).as_ref());
+    let finalized = number <= info.finalized_number;
+
+    Ok(Some(Confirmations {
+        number,
+        in_best_chain: true,
+        depth,
+        confirmations,
+        finalized,
+        reorg_probability: if finalized {
+            0.0
+        } else {
+            reorg_probability(attacker_share, confirmations as u32)
+        },
+    }))
+}
+
+/// RPC methods to tell how settled a block is.
+#[rpc(server)]
+pub trait ConfirmationsApi<Hash> {
+    /// How deep the given block is buried in the best chain, and the probability that it is
+    /// reorged by an attacker controlling `attacker_share` of the hashrate, 10% by default.
+    #[method(name = "pow_confirmations")]
+    fn confirmations(&self, hash: Hash, attacker_share: Option<f64>) -> RpcResult<Confirmations>;
+}
+
+/// Implements the `ConfirmationsApi` by looking at the client's chain.
+pub struct ConfirmationsRpc<C> {
+    client: Arc<C>,
+}
+
+impl<C> ConfirmationsRpc<C> {
+    pub fn new(client: Arc<C>) -> Self {
+        Self { client }
+    }
+}
+
+impl<C> ConfirmationsApiServer<H256> for ConfirmationsRpc<C>
+where
+    C: HeaderBackend<Block> + Send + Sync + 'static,
+{
+    fn confirmations(&self, hash: H256, attacker_share: Option<f64>) -> RpcResult<Confirmations> {
+        let attacker_share = attacker_share.unwrap_or(DEFAULT_ATTACKER_SHARE);
+        if !(0.0..=1.0).contains(&attacker_share) {
+            return Err(invalid_params(format!(
+                "The attacker's share of the hashrate must be between 0 and 1, got {attacker_share}"
+            )));
+        }
+
+        confirmations(self.client.as_ref(), hash, attacker_share)
+            .map_err(|e| {
+                CallError::Custom(ErrorObject::owned(
+                    jsonrpsee::types::error::INTERNAL_ERROR_CODE,
+                    "Unable to look up the chain",
+                    Some(e.to_string()),
+                ))
+            })?
+            .ok_or_else(|| invalid_params(format!("Unknown block {hash:?}")))
+    }
+}
+
+#[cfg(test)]
+mod tests;
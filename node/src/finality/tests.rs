@@ -0,0 +1,201 @@
+use academy_pow_runtime::opaque::Header;
+use multi_pow::{
+    MultiHash,
+    SupportedHashes::{Md5, Sha3},
+};
+use parity_scale_codec::Encode;
+use sp_blockchain::{BlockStatus, Info};
+use sp_core::U256;
+use sp_runtime::Digest;
+
+use super::*;
+
+/// A best chain, with nothing finalized but genesis.
+struct MockChain(Vec<Header>);
+
+impl MockChain {
+    /// A chain of blocks mined with the given algorithms at the given difficulties, on top of
+    /// an unsealed genesis.
+    fn new(blocks: &[(SupportedHashes, Threshold)]) -> Self {
+        let genesis = Header::new(
+            0,
+            H256::zero(),
+            H256::zero(),
+            H256::zero(),
+            Digest::default(),
+        );
+        let mut headers = vec![genesis];
+        for (algo, difficulty) in blocks {
+            let seal = Seal {
+                work: MultiHash {
+                    algo: *algo,
+                    value: H256::zero(),
+                },
+                difficulty: *difficulty,
+                nonce: U256::zero(),
+                extra_nonce: 0,
+                signature: None,
+            };
+            let parent = &headers[headers.len() - 1];
+            let header = Header::new(
+                parent.number + 1,
+                H256::zero(),
+                H256::zero(),
+                parent.hash(),
+                Digest {
+                    logs: vec![DigestItem::Seal(POW_ENGINE_ID, seal.encode())],
+                },
+            );
+            headers.push(header);
+        }
+        Self(headers)
+    }
+
+    fn hash_at(&self, height: u32) -> H256 {
+        self.0[height as usize].hash()
+    }
+
+    fn best_hash(&self) -> H256 {
+        self.0[self.0.len() - 1].hash()
+    }
+}
+
+impl HeaderBackend<Block> for MockChain {
+    fn header(&self, hash: H256) -> sp_blockchain::Result<Option<Header>> {
+        Ok(self.0.iter().find(|header| header.hash() == hash).cloned())
+    }
+
+    fn info(&self) -> Info<Block> {
+        let best = &self.0[self.0.len() - 1];
+        Info {
+            best_hash: best.hash(),
+            best_number: best.number,
+            genesis_hash: self.hash_at(0),
+            finalized_hash: self.hash_at(0),
+            finalized_number: 0,
+            finalized_state: None,
+            number_leaves: 1,
+            block_gap: None,
+        }
+    }
+
+    fn status(&self, hash: H256) -> sp_blockchain::Result<BlockStatus> {
+        Ok(match self.header(hash)? {
+            Some(_) => BlockStatus::InChain,
+            None => BlockStatus::Unknown,
+        })
+    }
+
+    fn number(&self, hash: H256) -> sp_blockchain::Result<Option<u32>> {
+        Ok(self.header(hash)?.map(|header| header.number))
+    }
+
+    fn hash(&self, number: u32) -> sp_blockchain::Result<Option<H256>> {
+        Ok(self.0.get(number as usize).map(|header| header.hash()))
+    }
+}
+
+fn difficulty(md5: u64, sha3: u64) -> Threshold {
+    Threshold {
+        md5: md5.into(),
+        sha3: sha3.into(),
+        keccak: 1_000.into(),
+    }
+}
+
+#[test]
+fn reorg_probability_matches_the_whitepaper() {
+    // The table in section 11 of the Bitcoin whitepaper, rounded to seven decimals.
+    for (attacker_share, confirmations, expected) in [
+        (0.1, 0, 1.0),
+        (0.1, 1, 0.2045873),
+        (0.1, 5, 0.0009137),
+        (0.1, 10, 0.0000012),
+        (0.3, 5, 0.1773523),
+        (0.3, 10, 0.0416605),
+    ] {
+        let probability = reorg_probability(attacker_share, confirmations);
+        assert!(
+            (probability - expected).abs() < 5e-8,
+            "q={attacker_share}, z={confirmations}: expected {expected}, got {probability}"
+        );
+    }
+}
+
+#[test]
+fn a_majority_attacker_always_catches_up() {
+    assert_eq!(reorg_probability(0.5, 100), 1.0);
+}
+
+#[test]
+fn finalizes_the_highest_block_deep_enough() {
+    let chain = MockChain::new(&[(Md5, difficulty(1_000, 1_000)); 6]);
+
+    assert_eq!(
+        finalizable(&chain, chain.best_hash(), 3).unwrap(),
+        Some(chain.hash_at(3))
+    );
+    assert_eq!(finalizable(&chain, chain.best_hash(), 6).unwrap(), None);
+}
+
+#[test]
+fn harder_blocks_on_top_count_for_more() {
+    let chain = MockChain::new(&[
+        (Md5, difficulty(1_000, 1_000)),
+        (Md5, difficulty(2_000, 1_000)),
+        (Md5, difficulty(2_000, 1_000)),
+    ]);
+
+    // Either of the blocks on top would have mined two blocks at the first one's difficulty.
+    assert_eq!(
+        finalizable(&chain, chain.best_hash(), 4).unwrap(),
+        Some(chain.hash_at(1))
+    );
+}
+
+#[test]
+fn difficulties_of_different_algorithms_are_not_added_up() {
+    // Sha3 difficulties are a thousand times md5's, but that doesn't make a sha3 block a
+    // thousand confirmations.
+    let mut blocks = vec![
+        (Md5, difficulty(1_000, 1_000_000)),
+        (Sha3, difficulty(1_000, 1_000_000)),
+        (Sha3, difficulty(1_000, 1_000_000)),
+    ];
+    let chain = MockChain::new(&blocks);
+    assert_eq!(finalizable(&chain, chain.best_hash(), 3).unwrap(), None);
+
+    blocks.push((Md5, difficulty(1_000, 1_000_000)));
+    let chain = MockChain::new(&blocks);
+    assert_eq!(
+        finalizable(&chain, chain.best_hash(), 3).unwrap(),
+        Some(chain.hash_at(1))
+    );
+}
+
+#[test]
+fn confirmations_are_counted_per_algorithm() {
+    let chain = MockChain::new(&[
+        (Md5, difficulty(1_000, 1_000_000)),
+        (Sha3, difficulty(1_000, 1_000_000)),
+        (Sha3, difficulty(1_000, 2_000_000)),
+        (Md5, difficulty(500, 1_000_000)),
+    ]);
+
+    // One sha3 block at the same difficulty, one at twice it, and an md5 block at half it.
+    assert_eq!(
+        confirmations(&chain, chain.hash_at(1), 0.1).unwrap(),
+        Some(Confirmations {
+            number: 1,
+            in_best_chain: true,
+            depth: 3,
+            confirmations: 3.5,
+            finalized: false,
+            reorg_probability: reorg_probability(0.1, 3),
+        })
+    );
+    assert_eq!(
+        confirmations(&chain, H256::repeat_byte(1), 0.1).unwrap(),
+        None
+    );
+}
@@ -0,0 +1,20 @@
+//! Substrate Node Template CLI library.
+//!
+//! The node is also a library so that the integration tests can start nodes in-process.
+
+pub mod chain_spec;
+pub mod checkpoints;
+#[macro_use]
+pub mod service;
+pub mod cli;
+pub mod command;
+pub mod difficulty_rpc;
+pub mod difficulty_simulator;
+pub mod double_spend;
+pub mod finality;
+pub mod manual_seal;
+pub mod metrics;
+pub mod mining;
+pub mod rpc;
+pub mod selfish;
+mod uncles;
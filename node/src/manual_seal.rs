@@ -0,0 +1,280 @@
+//! Support for sealing blocks on demand instead of mining continuously.
+//!
+//! Instant seal skips the mining entirely, which makes it handy for quickly testing the runtime.
+//! But the runtime still expects every block to look like a PoW block. It reads the mining
+//! algorithm and the author from the PoW pre-digest, and requires the author inherent.
+//! The consensus data provider here makes instant sealed blocks look the part.
+//!
+//! The blocks are imported straight into our own client, so by default they carry a dummy seal
+//! that nobody checks. Peers running the regular PoW import do check it though. With
+//! `--mine-seals`, the provider mines a genuine seal at the runtime's difficulty instead, which
+//! is only quick on a chain with a low difficulty. Skipping the PoW import also skips its fork
+//! rules, so the provider checks those itself.
+//!
+//! Manual seal goes one step further and only seals a block when asked to over RPC.
+//! Besides the standard `engine_createBlock`, which can already build on any parent,
+//! we offer `engine_createBlockWithAlgo` to also choose the mining algorithm of each block.
+//! Together they let you script exact fork trees without doing any hashing.
+
+use std::sync::{Arc, Mutex};
+
+use academy_pow_runtime::{
+    opaque::{Block, Header},
+    PreDigest,
+};
+use futures::{
+    channel::{mpsc, oneshot},
+    SinkExt, Stream, StreamExt,
+};
+use jsonrpsee::{
+    core::{async_trait, Error as JsonRpseeError, RpcResult},
+    proc_macros::rpc,
+};
+use multi_pow::{
+    multi_hash_meets_difficulty, Compute, ForkingConfig, MultiHash, Seal, SupportedHashes,
+    Threshold,
+};
+use parity_scale_codec::Encode;
+use sc_consensus::BlockImportParams;
+use sc_consensus_manual_seal::{
+    consensus::ConsensusDataProvider, rpc::CreatedBlock, EngineCommand, Error,
+};
+use sp_api::ProvideRuntimeApi;
+use sp_consensus_pow::{DifficultyApi, POW_ENGINE_ID};
+use sp_core::{sr25519, Pair, H256, U256};
+use sp_inherents::InherentData;
+use sp_runtime::{traits::Header as HeaderT, Digest, DigestItem};
+
+use crate::rpc::invalid_params;
+
+/// The algorithm the next block will be sealed with.
+pub type SharedAlgo = Arc<Mutex<SupportedHashes>>;
+
+/// Adds the PoW pre-digest and a seal to blocks that are sealed on demand.
+pub struct PowConsensusDataProvider<C> {
+    client: Arc<C>,
+    author: sr25519::Public,
+    /// Signs the seals, for peers that require signed seals.
+    key: Option<sr25519::Pair>,
+    algo: SharedAlgo,
+    fork_config: ForkingConfig,
+    /// Whether to mine a genuine seal, rather than put in a dummy one.
+    mine_seals: bool,
+}
+
+impl<C> PowConsensusDataProvider<C> {
+    pub fn new(
+        client: Arc<C>,
+        author: sr25519::Public,
+        key: Option<sr25519::Pair>,
+        algo: SharedAlgo,
+        fork_config: ForkingConfig,
+    ) -> Self {
+        Self {
+            client,
+            author,
+            key,
+            algo,
+            fork_config,
+            mine_seals: false,
+        }
+    }
+
+    /// Mine a genuine seal at the runtime's difficulty for every block, so that peers accept
+    /// them.
+    pub fn with_mined_seals(mut self, mine_seals: bool) -> Self {
+        self.mine_seals = mine_seals;
+        self
+    }
+
+    fn algo(&self) -> SupportedHashes {
+        *self
+            .algo
+            .lock()
+            .expect("The algo lock is never poisoned; qed")
+    }
+}
+
+impl<C> ConsensusDataProvider<Block> for PowConsensusDataProvider<C>
+where
+    C: ProvideRuntimeApi<Block> + Send + Sync,
+    C::Api: DifficultyApi<Block, Threshold>,
+{
+    type Proof = ();
+
+    fn create_digest(&self, parent: &Header, _inherents: &InherentData) -> Result<Digest, Error> {
+        // The block skips the PoW import, so nothing else holds it to the fork rules.
+        let algo = self.algo();
+        let parent_number = *parent.number();
+        if !self.fork_config.allows(parent_number, algo) {
+            return Err(Error::StringError(format!(
+                "The fork rules don't allow sealing a {algo:?} block on top of #{parent_number}"
+            )));
+        }
+
+        let pre_digest = PreDigest::from((self.author.into(), algo));
+
+        Ok(Digest {
+            logs: vec![DigestItem::PreRuntime(POW_ENGINE_ID, pre_digest.encode())],
+        })
+    }
+
+    fn append_block_import(
+        &self,
+        parent: &Header,
+        params: &mut BlockImportParams<Block>,
+        _inherents: &InherentData,
+        _proof: Self::Proof,
+    ) -> Result<(), Error> {
+        let algo = self.algo();
+        let pre_hash = params.header.hash();
+        let mut seal = if self.mine_seals {
+            // Peers verify the block like any other PoW block, so the seal must meet the
+            // difficulty the runtime demands.
+            let difficulty = self
+                .client
+                .runtime_api()
+                .difficulty(parent.hash())
+                .map_err(|err| {
+                    Error::StringError(format!("Fetching difficulty from runtime failed: {err:?}"))
+                })?;
+
+            (0u64..)
+                .map(|nonce| {
+                    Compute {
+                        difficulty,
+                        pre_hash,
+                        nonce: nonce.into(),
+                        extra_nonce: 0,
+                    }
+                    .compute(algo)
+                })
+                .find(|seal| multi_hash_meets_difficulty(&seal.work, difficulty))
+                .expect("A minable difficulty is met well within 2^64 hashes; qed")
+        } else {
+            // Nobody checks this seal, since the blocks are imported straight into the client.
+            // It is only there so that the blocks have the same shape as mined ones.
+            Seal {
+                work: MultiHash {
+                    algo,
+                    value: Default::default(),
+                },
+                difficulty: Threshold::default(),
+                nonce: U256::zero(),
+                extra_nonce: 0,
+                signature: None,
+            }
+        };
+        if let Some(key) = &self.key {
+            seal.signature = Some(key.sign(&pre_hash[..]));
+        }
+        params
+            .post_digests
+            .push(DigestItem::Seal(POW_ENGINE_ID, seal.encode()));
+
+        Ok(())
+    }
+}
+
+/// A manual seal command, along with the algorithm to seal the block with.
+/// `None` means the node's own mining algorithm.
+pub type AlgoCommand = (Option<SupportedHashes>, EngineCommand<H256>);
+
+/// The ends of the manual seal command channels that the RPCs send to.
+#[derive(Clone)]
+pub struct ManualSealSinks {
+    /// Used by the standard `engine_*` RPCs.
+    pub engine: mpsc::Sender<EngineCommand<H256>>,
+    /// Used by `engine_createBlockWithAlgo`.
+    pub with_algo: mpsc::Sender<AlgoCommand>,
+}
+
+/// Create the channels that feed manual seal.
+///
+/// Manual seal handles one command at a time, and only takes the next one once the previous
+/// block is done. So we switch the shared algorithm as each command is taken, and it stays
+/// in place while that block is sealed.
+pub fn command_channels(
+    default_algo: SupportedHashes,
+    algo: SharedAlgo,
+) -> (
+    ManualSealSinks,
+    impl Stream<Item = EngineCommand<H256>> + Unpin + Send + 'static,
+) {
+    let (engine, engine_commands) = mpsc::channel(1024);
+    let (with_algo, algo_commands) = mpsc::channel(1024);
+
+    let commands = futures::stream::select(
+        engine_commands.map(|command| (None, command)),
+        algo_commands,
+    )
+    .map(move |(requested_algo, command)| {
+        *algo.lock().expect("The algo lock is never poisoned; qed") =
+            requested_algo.unwrap_or(default_algo);
+        command
+    });
+
+    (ManualSealSinks { engine, with_algo }, commands)
+}
+
+/// RPC methods to seal blocks with a particular mining algorithm.
+#[rpc(server)]
+pub trait PowManualSealApi<Hash> {
+    /// Like `engine_createBlock`, but the block's pre-digest and seal name the given algorithm.
+    #[method(name = "engine_createBlockWithAlgo")]
+    async fn create_block_with_algo(
+        &self,
+        create_empty: bool,
+        finalize: bool,
+        parent_hash: Option<Hash>,
+        algo: String,
+    ) -> RpcResult<CreatedBlock<Hash>>;
+}
+
+/// Implements the `PowManualSealApi` by sending commands to manual seal.
+pub struct PowManualSeal {
+    commands: mpsc::Sender<AlgoCommand>,
+}
+
+impl PowManualSeal {
+    pub fn new(commands: mpsc::Sender<AlgoCommand>) -> Self {
+        Self { commands }
+    }
+}
+
+#[async_trait]
+impl PowManualSealApiServer<H256> for PowManualSeal {
+    async fn create_block_with_algo(
+        &self,
+        create_empty: bool,
+        finalize: bool,
+        parent_hash: Option<H256>,
+        algo: String,
+    ) -> RpcResult<CreatedBlock<H256>> {
+        let algo: SupportedHashes = algo.parse().map_err(|()| {
+            invalid_params(format!(
+                "Unknown mining algo: {algo}. Possible values: md5, sha3, keccak"
+            ))
+        })?;
+
+        let (sender, receiver) = oneshot::channel();
+        let command = EngineCommand::SealNewBlock {
+            create_empty,
+            finalize,
+            parent_hash,
+            sender: Some(sender),
+        };
+
+        self.commands
+            .clone()
+            .send((Some(algo), command))
+            .await
+            .map_err(JsonRpseeError::to_call_error)?;
+
+        match receiver.await {
+            Ok(Ok(created)) => Ok(created),
+            Ok(Err(e)) => Err(e.into()),
+            Err(e) => Err(JsonRpseeError::to_call_error(e)),
+        }
+    }
+}
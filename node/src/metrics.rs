@@ -0,0 +1,195 @@
+//! Prometheus metrics for mining and PoW block import.
+//!
+//! Besides the metrics Substrate already exports, these give a view of the PoW side of the node:
+//! how fast it hashes, how many seals it finds, why blocks get rejected, what the difficulty is
+//! doing, and how deep the reorgs go.
+
+use std::sync::Arc;
+
+use academy_pow_runtime::{opaque::Block, PreDigest};
+use futures::StreamExt;
+use multi_pow::{RejectHook, RejectReason, SealError, SupportedHashes, Threshold};
+use parity_scale_codec::Decode;
+use sc_client_api::BlockchainEvents;
+use sp_api::ProvideRuntimeApi;
+use sp_consensus_pow::{DifficultyApi, POW_ENGINE_ID};
+use sp_runtime::{traits::Header as HeaderT, DigestItem};
+use substrate_prometheus_endpoint::{
+    register, CounterVec, GaugeVec, Histogram, HistogramOpts, Opts, PrometheusError, Registry, F64,
+    U64,
+};
+
+fn algo_label(algo: SupportedHashes) -> &'static str {
+    match algo {
+        SupportedHashes::Md5 => "md5",
+        SupportedHashes::Sha3 => "sha3",
+        SupportedHashes::Keccak => "keccak",
+    }
+}
+
+fn reject_label(reason: RejectReason) -> &'static str {
+    match reason {
+        RejectReason::BadSeal => "bad_seal",
+        RejectReason::BadPreDigest => "bad_pre_digest",
+        RejectReason::ForkRules => "fork_rules",
+        RejectReason::Seal(SealError::AlgoMismatch) => "algo_mismatch",
+        RejectReason::Seal(SealError::BadSignature) => "bad_signature",
+        RejectReason::Seal(SealError::InsufficientWork) => "insufficient_work",
+        RejectReason::Seal(SealError::WorkMismatch) => "work_mismatch",
+    }
+}
+
+/// The metrics, registered with the node's Prometheus registry.
+#[derive(Clone)]
+pub struct Metrics {
+    hashes_per_second: GaugeVec<F64>,
+    seals_found: CounterVec<U64>,
+    seals_rejected: CounterVec<U64>,
+    difficulty: GaugeVec<F64>,
+    reorg_depth: Histogram,
+    blocks_imported: CounterVec<U64>,
+}
+
+impl Metrics {
+    pub fn register(registry: &Registry) -> Result<Self, PrometheusError> {
+        Ok(Self {
+            hashes_per_second: register(
+                GaugeVec::new(
+                    Opts::new(
+                        "academy_pow_hashes_per_second",
+                        "Hashes per second computed by the local miner",
+                    ),
+                    &["algo"],
+                )?,
+                registry,
+            )?,
+            seals_found: register(
+                CounterVec::new(
+                    Opts::new(
+                        "academy_pow_seals_found_total",
+                        "Seals found by the local miner",
+                    ),
+                    &["algo"],
+                )?,
+                registry,
+            )?,
+            seals_rejected: register(
+                CounterVec::new(
+                    Opts::new(
+                        "academy_pow_seals_rejected_total",
+                        "Blocks whose seal failed verification",
+                    ),
+                    &["reason"],
+                )?,
+                registry,
+            )?,
+            difficulty: register(
+                GaugeVec::new(
+                    Opts::new(
+                        "academy_pow_difficulty",
+                        "The difficulty of each algorithm at the best block",
+                    ),
+                    &["algo"],
+                )?,
+                registry,
+            )?,
+            reorg_depth: register(
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "academy_pow_reorg_depth",
+                        "How many blocks were retracted when the best block switched forks",
+                    )
+                    .buckets(vec![1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 34.0]),
+                )?,
+                registry,
+            )?,
+            blocks_imported: register(
+                CounterVec::new(
+                    Opts::new(
+                        "academy_pow_blocks_imported_total",
+                        "Blocks imported, by the algorithm they were sealed with",
+                    ),
+                    &["algo"],
+                )?,
+                registry,
+            )?,
+        })
+    }
+
+    /// Record the local miner's hashrate.
+    pub fn report_hashrate(&self, algo: SupportedHashes, hashes_per_second: f64) {
+        self.hashes_per_second
+            .with_label_values(&[algo_label(algo)])
+            .set(hashes_per_second);
+    }
+
+    /// Count a seal found by the local miner.
+    pub fn seal_found(&self, algo: SupportedHashes) {
+        self.seals_found
+            .with_label_values(&[algo_label(algo)])
+            .inc();
+    }
+
+    /// A hook for `MultiPow` that counts rejected seals by reason.
+    pub fn reject_hook(&self) -> RejectHook {
+        let seals_rejected = self.seals_rejected.clone();
+        Arc::new(move |reason| {
+            seals_rejected
+                .with_label_values(&[reject_label(reason)])
+                .inc()
+        })
+    }
+
+    /// Follow block imports, keeping the difficulty, reorg and import metrics up to date.
+    pub async fn run_import_metrics<C>(self, client: Arc<C>)
+    where
+        C: BlockchainEvents<Block> + ProvideRuntimeApi<Block>,
+        C::Api: DifficultyApi<Block, Threshold>,
+    {
+        let mut imports = client.import_notification_stream();
+
+        while let Some(notification) = imports.next().await {
+            let algo = notification
+                .header
+                .digest()
+                .logs
+                .iter()
+                .find_map(|item| match item {
+                    DigestItem::PreRuntime(POW_ENGINE_ID, pre_digest) => {
+                        PreDigest::decode(&mut &pre_digest[..])
+                            .ok()
+                            .map(|(_, algo)| algo)
+                    }
+                    _ => None,
+                });
+            if let Some(algo) = algo {
+                self.blocks_imported
+                    .with_label_values(&[algo_label(algo)])
+                    .inc();
+            }
+
+            if !notification.is_new_best {
+                continue;
+            }
+
+            if let Some(tree_route) = &notification.tree_route {
+                let depth = tree_route.retracted().len();
+                if depth > 0 {
+                    self.reorg_depth.observe(depth as f64);
+                }
+            }
+
+            if let Ok(threshold) = client.runtime_api().difficulty(notification.hash) {
+                for (algo, difficulty) in [
+                    (SupportedHashes::Md5, threshold.md5),
+                    (SupportedHashes::Sha3, threshold.sha3),
+                    (SupportedHashes::Keccak, threshold.keccak),
+                ] {
+                    self.difficulty
+                        .with_label_values(&[algo_label(algo)])
+                        .set(difficulty.low_u128() as f64);
+                }
+            }
+        }
+    }
+}
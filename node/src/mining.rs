@@ -0,0 +1,591 @@
+//! The PoW miner, run as a node task that can be stopped, restarted and reconfigured.
+//!
+//! `sc_consensus_pow` builds the blocks to mine on, but leaves the hashing to us. Its mining
+//! worker is tied to the pre-runtime digest it was started with, so mining with a different
+//! algorithm or for a different author takes a fresh worker. The supervisor here watches the
+//! mining settings and starts a new worker whenever they change, or stops hashing altogether
+//! when mining is switched off.
+//!
+//! The settings change through the unsafe `mining_*` RPCs, which lets you switch algorithms
+//! in the middle of a fork demo without restarting the node.
+//!
+//! The mining worker checks the fork rules before it builds a block to mine on, so that it
+//! doesn't build or mine blocks that its own import would reject. If they forbid the algorithm,
+//! the miner falls back to an allowed one if so configured, or stops.
+//!
+//! With profit switching, the miner also changes the algorithm on its own. It keeps track of
+//! its hashrate with each algorithm and mines whichever one the fork rules allow that promises
+//! the most blocks per second at the current difficulties.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use academy_pow_runtime::opaque::Block;
+use futures::{
+    future::{self, BoxFuture, Either},
+    FutureExt,
+};
+use jsonrpsee::{core::RpcResult, proc_macros::rpc};
+use multi_pow::{multi_hash_meets_difficulty, Compute, ForkingConfig, SupportedHashes, Threshold};
+use parity_scale_codec::Encode;
+use sc_consensus::JustificationSyncLink;
+use sc_consensus_pow::{MiningHandle, PowAlgorithm};
+use sc_rpc_api::DenyUnsafe;
+use sp_blockchain::HeaderBackend;
+use sp_consensus::Environment;
+use sp_core::{crypto::Ss58Codec, sr25519, Pair, H256, U256};
+use sp_runtime::traits::Block as BlockT;
+use tokio::sync::watch;
+
+use crate::{metrics::Metrics, rpc::invalid_params};
+
+/// How many nonces to try before checking for a new block to mine on, and letting the other
+/// tasks run.
+const HASHES_PER_BATCH: u64 = 1000;
+
+/// How long to wait for a block to mine on before asking again.
+const IDLE_WAIT: Duration = Duration::from_secs(1);
+
+/// How often to report the hashrate, and to reconsider the algorithm when profit switching.
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many hashes to time when we don't know our hashrate with an algorithm yet.
+const BENCHMARK_HASHES: u64 = 10_000;
+
+/// Profit switching only moves to another algorithm once it promises this many times as many
+/// blocks as the current one, so that the miner doesn't flap between two similar algorithms.
+const SWITCH_MARGIN: f64 = 1.1;
+
+/// How much weight each new hashrate measurement gets in the running estimate.
+const HASHRATE_SMOOTHING: f64 = 0.2;
+
+const ALGOS: [SupportedHashes; 3] = [
+    SupportedHashes::Md5,
+    SupportedHashes::Sha3,
+    SupportedHashes::Keccak,
+];
+
+/// What the miner should be doing.
+#[derive(Clone)]
+pub struct MiningSettings {
+    /// Whether to mine at all.
+    pub running: bool,
+    /// The hashing algorithm to mine with.
+    pub algo: SupportedHashes,
+    /// Whether the miner picks the algorithm itself, based on its hashrates and the difficulty.
+    pub profit_switching: bool,
+    /// Whether to switch to an allowed algorithm when the fork rules forbid `algo`, rather than
+    /// stop mining. Profit switching always does.
+    pub fork_fallback: bool,
+    /// The algorithm the fork rules follow, if the node's maxi position follows mining. The
+    /// fork rules are fixed when the node starts, so the RPCs can't switch to another algorithm.
+    pub followed_algo: Option<SupportedHashes>,
+    /// The account that receives the block rewards.
+    pub author: sr25519::Public,
+    /// The author's key pair, if seals should be signed.
+    pub key: Option<sr25519::Pair>,
+    /// The extra nonce to put in every seal. Random if not set.
+    pub extra_nonce: Option<u64>,
+}
+
+/// The handle the RPCs use to change the mining settings.
+pub type MiningControl = Arc<watch::Sender<MiningSettings>>;
+
+/// Create the channel that carries the mining settings from the RPCs to the miner.
+pub fn control_channel(
+    settings: MiningSettings,
+) -> (MiningControl, watch::Receiver<MiningSettings>) {
+    let (control, settings) = watch::channel(settings);
+    (Arc::new(control), settings)
+}
+
+/// The algorithms the fork rules allow in a child of the given block.
+pub type AllowedAlgos = Arc<dyn Fn(H256) -> Vec<SupportedHashes> + Send + Sync>;
+
+/// Look up the algorithms the fork rules allow on top of a parent block.
+pub fn allowed_algos<C>(client: Arc<C>, fork_config: ForkingConfig) -> AllowedAlgos
+where
+    C: HeaderBackend<Block> + 'static,
+{
+    Arc::new(move |parent| {
+        // If we can't tell, let the import decide.
+        let Ok(Some(parent_number)) = client.number(parent) else {
+            return ALGOS.to_vec();
+        };
+
+        ALGOS
+            .into_iter()
+            .filter(|algo| fork_config.allows(parent_number, *algo))
+            .collect()
+    })
+}
+
+/// Where a mining worker notes the block it refused to build on because the fork rules forbid
+/// its algorithm there, for the miner to pick up.
+#[derive(Clone, Default)]
+pub struct ForbiddenParent(Arc<Mutex<Option<H256>>>);
+
+impl ForbiddenParent {
+    /// Note that the fork rules forbid mining on top of `parent`.
+    pub fn set(&self, parent: H256) {
+        *self
+            .0
+            .lock()
+            .expect("The forbidden parent lock is never poisoned; qed") = Some(parent);
+    }
+
+    /// The block the worker last refused to build on, if any since we last asked.
+    fn take(&self) -> Option<H256> {
+        self.0
+            .lock()
+            .expect("The forbidden parent lock is never poisoned; qed")
+            .take()
+    }
+}
+
+/// The local miner's hashrate with each algorithm, as far as we know it.
+#[derive(Clone, Default)]
+struct Hashrates(Arc<Mutex<[Option<f64>; 3]>>);
+
+impl Hashrates {
+    fn index(algo: SupportedHashes) -> usize {
+        match algo {
+            SupportedHashes::Md5 => 0,
+            SupportedHashes::Sha3 => 1,
+            SupportedHashes::Keccak => 2,
+        }
+    }
+
+    /// The estimated hashrate with `algo`. If we haven't mined with it yet, time a few hashes.
+    fn get(&self, algo: SupportedHashes) -> f64 {
+        let mut hashrates = self
+            .0
+            .lock()
+            .expect("The hashrate lock is never poisoned; qed");
+        *hashrates[Self::index(algo)].get_or_insert_with(|| benchmark(algo))
+    }
+
+    /// Fold a new measurement of the hashrate with `algo` into the estimate.
+    fn observe(&self, algo: SupportedHashes, hashrate: f64) {
+        let mut hashrates = self
+            .0
+            .lock()
+            .expect("The hashrate lock is never poisoned; qed");
+        let estimate = &mut hashrates[Self::index(algo)];
+        *estimate = Some(match *estimate {
+            Some(old) => old + HASHRATE_SMOOTHING * (hashrate - old),
+            None => hashrate,
+        });
+    }
+}
+
+/// Measure how many hashes per second we compute with `algo`.
+fn benchmark(algo: SupportedHashes) -> f64 {
+    let start = Instant::now();
+    for nonce in 0..BENCHMARK_HASHES {
+        Compute {
+            difficulty: Threshold::default(),
+            pre_hash: H256::zero(),
+            nonce: nonce.into(),
+            extra_nonce: 0,
+        }
+        .compute(algo);
+    }
+
+    BENCHMARK_HASHES as f64 / start.elapsed().as_secs_f64().max(f64::MIN_POSITIVE)
+}
+
+/// A difficulty as a float, for estimating block rates.
+fn to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0.0, |acc, word| acc * 2f64.powi(64) + *word as f64)
+}
+
+/// Decides which algorithms the miner may mine, and which is the most profitable.
+#[derive(Clone)]
+struct AlgoSelection {
+    allowed_algos: AllowedAlgos,
+    hashrates: Hashrates,
+}
+
+impl AlgoSelection {
+    /// The algorithm to mine on top of `parent`, if it isn't `current`.
+    ///
+    /// Every hash meets the difficulty with probability `1 / difficulty`, so our expected
+    /// blocks per second with an algorithm is our hashrate with it divided by its difficulty.
+    fn better_algo(
+        &self,
+        current: SupportedHashes,
+        parent: H256,
+        difficulty: Threshold,
+    ) -> Option<SupportedHashes> {
+        let allowed = (self.allowed_algos)(parent);
+        let blocks_per_second =
+            |algo| self.hashrates.get(algo) / to_f64(difficulty.for_algo(algo)).max(1.0);
+
+        let best = allowed
+            .iter()
+            .copied()
+            .max_by(|a, b| blocks_per_second(*a).total_cmp(&blocks_per_second(*b)))?;
+
+        let switch = best != current
+            && (!allowed.contains(&current)
+                || blocks_per_second(best) > blocks_per_second(current) * SWITCH_MARGIN);
+        switch.then_some(best)
+    }
+}
+
+/// A proposer factory that every mining worker shares.
+///
+/// Each worker wants its own factory, but a factory registers its metrics when it is created,
+/// and registering them twice fails.
+pub struct SharedEnvironment<E>(Arc<Mutex<E>>);
+
+impl<E> SharedEnvironment<E> {
+    pub fn new(env: E) -> Self {
+        Self(Arc::new(Mutex::new(env)))
+    }
+}
+
+impl<E> Clone for SharedEnvironment<E> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<B: BlockT, E: Environment<B>> Environment<B> for SharedEnvironment<E> {
+    type Proposer = E::Proposer;
+    type CreateProposer = E::CreateProposer;
+    type Error = E::Error;
+
+    fn init(&mut self, parent_header: &B::Header) -> Self::CreateProposer {
+        self.0
+            .lock()
+            .expect("The proposer lock is never poisoned; qed")
+            .init(parent_header)
+    }
+}
+
+/// A change to the settings that the miner decided on itself.
+enum Adjustment {
+    /// Mine with another algorithm.
+    SwitchAlgo(SupportedHashes),
+    /// Stop mining.
+    Stop,
+}
+
+/// Mine according to the latest settings, until the node shuts down.
+///
+/// `start_worker` starts a mining worker for the given settings, returning its handle and the
+/// task that builds the blocks to mine on. Both are dropped whenever the settings change. The
+/// worker must not build on blocks where the fork rules forbid its algorithm, and note them in
+/// the given `ForbiddenParent` instead.
+/// When the fork rules or profit switching call for a different algorithm, or for stopping
+/// altogether, the miner changes the settings itself.
+pub async fn run_miner<A, L, P, F>(
+    mut start_worker: F,
+    control: MiningControl,
+    mut settings: watch::Receiver<MiningSettings>,
+    allowed_algos: AllowedAlgos,
+    metrics: Option<Metrics>,
+) where
+    A: PowAlgorithm<Block, Difficulty = Threshold> + Send + Sync + 'static,
+    L: JustificationSyncLink<Block> + Send + Sync + 'static,
+    P: Send + 'static,
+    F: FnMut(
+        &MiningSettings,
+        ForbiddenParent,
+    ) -> (MiningHandle<Block, A, L, P>, BoxFuture<'static, ()>),
+{
+    let algo_selection = AlgoSelection {
+        allowed_algos,
+        hashrates: Hashrates::default(),
+    };
+
+    loop {
+        let current = settings.borrow_and_update().clone();
+
+        let mining = if current.running {
+            log::info!(
+                "⛏  Mining with {:?} for {}",
+                current.algo,
+                current.author.to_ss58check()
+            );
+            let forbidden = ForbiddenParent::default();
+            let (worker, worker_task) = start_worker(&current, forbidden.clone());
+            let hashing = hash(
+                worker,
+                forbidden,
+                current,
+                algo_selection.clone(),
+                metrics.clone(),
+            );
+            future::select(worker_task, Box::pin(hashing))
+                .map(|finished| match finished {
+                    Either::Left(((), _)) => None,
+                    Either::Right((adjustment, _)) => Some(adjustment),
+                })
+                .boxed()
+        } else {
+            log::info!("⛏  Mining stopped");
+            future::pending().boxed()
+        };
+
+        match future::select(mining, Box::pin(settings.changed())).await {
+            Either::Left((Some(Adjustment::SwitchAlgo(algo)), _)) => {
+                control.send_modify(|settings| settings.algo = algo);
+            }
+            Either::Left((Some(Adjustment::Stop), _)) => {
+                control.send_modify(|settings| settings.running = false);
+            }
+            Either::Left((None, _)) => return,
+            Either::Right((Ok(()), _)) => continue,
+            // We hold on to the sender ourselves, so this can't happen.
+            Either::Right((Err(_), _)) => return,
+        }
+    }
+}
+
+/// Try nonces on whatever block the worker currently offers, and submit the seals that meet
+/// the difficulty.
+///
+/// This only returns when the settings should change, because the fork rules forbid the
+/// algorithm or profit switching found a better one.
+async fn hash<A, L, P>(
+    worker: MiningHandle<Block, A, L, P>,
+    forbidden: ForbiddenParent,
+    settings: MiningSettings,
+    algo_selection: AlgoSelection,
+    metrics: Option<Metrics>,
+) -> Adjustment
+where
+    A: PowAlgorithm<Block, Difficulty = Threshold>,
+    L: JustificationSyncLink<Block>,
+{
+    let MiningSettings {
+        algo,
+        key,
+        profit_switching,
+        fork_fallback,
+        extra_nonce,
+        ..
+    } = settings;
+
+    // Search from random points, so that miners working on the same block, even for the same
+    // author, don't repeat each other's work. Every hashing loop gets its own extra nonce,
+    // unless the miner was given one to stay within its share of a pool, and every block it
+    // works on gets a fresh starting nonce.
+    let extra_nonce = extra_nonce.unwrap_or_else(rand::random);
+    let mut nonce = U256::zero();
+    let mut current_build = None;
+    // Hashes since the hashrate was last reported.
+    let mut hashes: u64 = 0;
+    let mut last_report = Instant::now();
+
+    loop {
+        // The worker won't build on a block that the fork rules, and so our own import, reject.
+        // Rather than wait for it forever, or keep hashing on a stale block, move on.
+        if let Some(parent) = forbidden.take() {
+            let difficulty = worker
+                .metadata()
+                .map_or_else(Threshold::default, |metadata| metadata.difficulty);
+            let fallback = (fork_fallback || profit_switching)
+                .then(|| algo_selection.better_algo(algo, parent, difficulty))
+                .flatten();
+
+            return match fallback {
+                Some(allowed) => {
+                    log::warn!(
+                        "⛏  The fork rules no longer allow mining with {algo:?} after {parent}. \
+                         Falling back to {allowed:?}"
+                    );
+                    Adjustment::SwitchAlgo(allowed)
+                }
+                None => {
+                    log::error!(
+                        "⛏  The fork rules no longer allow mining with {algo:?} after {parent}. \
+                         Stopping the miner. Choose another algorithm with `mining_setAlgo` and \
+                         resume with `mining_start`, or restart with `--fork-fallback`."
+                    );
+                    Adjustment::Stop
+                }
+            };
+        }
+
+        let Some(metadata) = worker.metadata() else {
+            tokio::time::sleep(IDLE_WAIT).await;
+            // We weren't hashing while we waited, so don't count that time in the hashrate.
+            hashes = 0;
+            last_report = Instant::now();
+            continue;
+        };
+
+        if current_build != Some(metadata.pre_hash) {
+            current_build = Some(metadata.pre_hash);
+            nonce = U256::from_big_endian(&rand::random::<[u8; 32]>());
+        }
+
+        for _ in 0..HASHES_PER_BATCH {
+            let compute = Compute {
+                difficulty: metadata.difficulty,
+                pre_hash: metadata.pre_hash,
+                nonce,
+                extra_nonce,
+            };
+            let mut seal = compute.compute(algo);
+            hashes += 1;
+
+            if multi_hash_meets_difficulty(&seal.work, seal.difficulty) {
+                // Prove we hold the key that the rewards are going to.
+                if let Some(key) = &key {
+                    seal.signature = Some(key.sign(&metadata.pre_hash[..]));
+                }
+                if let Some(metrics) = &metrics {
+                    metrics.seal_found(algo);
+                }
+                worker.submit(seal.encode()).await;
+                break;
+            }
+
+            nonce = nonce.overflowing_add(U256::one()).0;
+        }
+
+        let elapsed = last_report.elapsed();
+        if elapsed >= REPORT_INTERVAL {
+            let hashrate = hashes as f64 / elapsed.as_secs_f64();
+            if let Some(metrics) = &metrics {
+                metrics.report_hashrate(algo, hashrate);
+            }
+            algo_selection.hashrates.observe(algo, hashrate);
+            hashes = 0;
+            last_report = Instant::now();
+
+            if profit_switching {
+                if let Some(better) =
+                    algo_selection.better_algo(algo, metadata.best_hash, metadata.difficulty)
+                {
+                    log::info!("⛏  Switching from {algo:?} to the more profitable {better:?}");
+                    return Adjustment::SwitchAlgo(better);
+                }
+            }
+        }
+
+        tokio::task::yield_now().await;
+    }
+}
+
+/// RPC methods to control the miner. They are all unsafe.
+#[rpc(server)]
+pub trait MiningApi {
+    /// Start mining with the current settings.
+    #[method(name = "mining_start")]
+    fn start(&self) -> RpcResult<()>;
+
+    /// Stop mining.
+    #[method(name = "mining_stop")]
+    fn stop(&self) -> RpcResult<()>;
+
+    /// Mine with the given algorithm from the next block on. This turns profit switching off.
+    ///
+    /// Fails if the node's maxi position follows another algorithm.
+    #[method(name = "mining_setAlgo")]
+    fn set_algo(&self, algo: String) -> RpcResult<()>;
+
+    /// Turn profit switching on or off.
+    #[method(name = "mining_setProfitSwitching")]
+    fn set_profit_switching(&self, enabled: bool) -> RpcResult<()>;
+
+    /// Pay the block rewards to the given author from the next block on.
+    ///
+    /// The author is an SS58 address or hex public key. Secret keys never go over RPC, so seals
+    /// are only signed if the author is the one in `--mining-key-file`.
+    #[method(name = "mining_setAuthor")]
+    fn set_author(&self, author: String) -> RpcResult<()>;
+}
+
+/// Implements the `MiningApi` by changing the settings the miner watches.
+pub struct Mining {
+    control: MiningControl,
+    deny_unsafe: DenyUnsafe,
+    /// The key the node was started with, to sign seals with whenever it is the author.
+    key: Option<sr25519::Pair>,
+}
+
+impl Mining {
+    pub fn new(control: MiningControl, deny_unsafe: DenyUnsafe) -> Self {
+        let key = control.borrow().key.clone();
+        Self {
+            control,
+            deny_unsafe,
+            key,
+        }
+    }
+
+    /// Apply `change` to the settings. The miner only restarts if they actually changed.
+    fn update(&self, change: impl FnOnce(&mut MiningSettings) -> bool) -> RpcResult<()> {
+        self.deny_unsafe.check_if_safe()?;
+        self.control.send_if_modified(change);
+        Ok(())
+    }
+}
+
+impl MiningApiServer for Mining {
+    fn start(&self) -> RpcResult<()> {
+        self.update(|settings| !std::mem::replace(&mut settings.running, true))
+    }
+
+    fn stop(&self) -> RpcResult<()> {
+        self.update(|settings| std::mem::replace(&mut settings.running, false))
+    }
+
+    fn set_algo(&self, algo: String) -> RpcResult<()> {
+        self.deny_unsafe.check_if_safe()?;
+        let algo: SupportedHashes = algo.parse().map_err(|()| {
+            invalid_params(format!(
+                "Unknown mining algo: {algo}. Possible values: md5, sha3, keccak"
+            ))
+        })?;
+
+        let followed_algo = self.control.borrow().followed_algo;
+        if let Some(followed) = followed_algo.filter(|followed| *followed != algo) {
+            return Err(invalid_params(format!(
+                "The fork rules follow {followed:?}, so the node can't switch to {algo:?}. \
+                 Restart it with another `--mining-algo` or `--maxi-position` instead"
+            )));
+        }
+
+        self.update(|settings| {
+            let switching = std::mem::replace(&mut settings.profit_switching, false);
+            switching || std::mem::replace(&mut settings.algo, algo) != algo
+        })
+    }
+
+    fn set_profit_switching(&self, enabled: bool) -> RpcResult<()> {
+        self.update(|settings| {
+            std::mem::replace(&mut settings.profit_switching, enabled) != enabled
+        })
+    }
+
+    fn set_author(&self, author: String) -> RpcResult<()> {
+        self.deny_unsafe.check_if_safe()?;
+        let public = sr25519::Public::from_string(&author)
+            .map_err(|_| invalid_params("Expected an SS58 address or a hex public key".into()))?;
+        let key = self.key.clone().filter(|key| key.public() == public);
+
+        self.update(|settings| {
+            let changed = settings.author != public
+                || settings.key.as_ref().map(Pair::public) != key.as_ref().map(Pair::public);
+            settings.author = public;
+            settings.key = key;
+            changed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,151 @@
+use multi_pow::SupportedHashes::{Keccak, Md5, Sha3};
+
+use super::*;
+
+/// Algorithm selection where the fork rules allow `allowed`, and we hash 1000 times a second
+/// with every algorithm.
+fn selection(allowed: &[SupportedHashes]) -> AlgoSelection {
+    let allowed = allowed.to_vec();
+    let hashrates = Hashrates::default();
+    for algo in ALGOS {
+        hashrates.observe(algo, 1000.0);
+    }
+
+    AlgoSelection {
+        allowed_algos: Arc::new(move |_| allowed.clone()),
+        hashrates,
+    }
+}
+
+fn difficulty(md5: u64, sha3: u64, keccak: u64) -> Threshold {
+    Threshold {
+        md5: md5.into(),
+        sha3: sha3.into(),
+        keccak: keccak.into(),
+    }
+}
+
+#[test]
+fn stays_with_the_most_profitable_algo() {
+    let selection = selection(&ALGOS);
+
+    assert_eq!(
+        selection.better_algo(Md5, H256::zero(), difficulty(1000, 2000, 2000)),
+        None
+    );
+}
+
+#[test]
+fn switches_when_another_algo_is_better_by_the_margin() {
+    let selection = selection(&ALGOS);
+
+    // Sha3 promises 1000 / 900 = 1.11 times as many blocks as md5.
+    assert_eq!(
+        selection.better_algo(Md5, H256::zero(), difficulty(1000, 900, 2000)),
+        Some(Sha3)
+    );
+}
+
+#[test]
+fn stays_when_another_algo_is_better_by_less_than_the_margin() {
+    let selection = selection(&ALGOS);
+
+    // Sha3 promises 1000 / 950 = 1.05 times as many blocks as md5.
+    assert_eq!(
+        selection.better_algo(Md5, H256::zero(), difficulty(1000, 950, 2000)),
+        None
+    );
+}
+
+#[test]
+fn accounts_for_the_hashrates() {
+    let selection = selection(&ALGOS);
+    selection
+        .hashrates
+        .observe(Keccak, 1000.0 + 4000.0 / HASHRATE_SMOOTHING);
+
+    // Keccak is twice as hard as md5, but we hash five times as fast with it.
+    assert_eq!(
+        selection.better_algo(Md5, H256::zero(), difficulty(1000, 2000, 2000)),
+        Some(Keccak)
+    );
+}
+
+#[test]
+fn falls_back_when_the_current_algo_is_forbidden() {
+    let selection = selection(&[Sha3, Keccak]);
+
+    // Md5 would be the most profitable, and keccak is only a little better than sha3, but md5
+    // is forbidden so any margin will do.
+    assert_eq!(
+        selection.better_algo(Md5, H256::zero(), difficulty(100, 1000, 990)),
+        Some(Keccak)
+    );
+}
+
+#[test]
+fn nothing_to_fall_back_to_when_every_algo_is_forbidden() {
+    let selection = selection(&[]);
+
+    assert_eq!(
+        selection.better_algo(Md5, H256::zero(), difficulty(1000, 1000, 1000)),
+        None
+    );
+}
+
+/// The mining RPCs, for a node started with Alice's key.
+fn mining_rpc(deny_unsafe: DenyUnsafe) -> (Mining, watch::Receiver<MiningSettings>) {
+    let key = sr25519::Pair::from_string("//Alice", None).expect("Alice's key is valid");
+    let (control, settings) = control_channel(MiningSettings {
+        running: true,
+        algo: Md5,
+        profit_switching: false,
+        fork_fallback: false,
+        followed_algo: None,
+        author: key.public(),
+        key: Some(key),
+        extra_nonce: None,
+    });
+    (Mining::new(control, deny_unsafe), settings)
+}
+
+fn public(seed: &str) -> sr25519::Public {
+    sr25519::Pair::from_string(seed, None)
+        .expect("The seed is valid")
+        .public()
+}
+
+#[test]
+fn setting_the_author_is_unsafe() {
+    let (mining, settings) = mining_rpc(DenyUnsafe::Yes);
+
+    assert!(mining.set_author(public("//Bob").to_ss58check()).is_err());
+    assert_eq!(settings.borrow().author, public("//Alice"));
+}
+
+#[test]
+fn secret_keys_are_not_accepted_over_rpc() {
+    let (mining, settings) = mining_rpc(DenyUnsafe::No);
+
+    assert!(mining.set_author("//Bob".into()).is_err());
+    assert_eq!(settings.borrow().author, public("//Alice"));
+}
+
+#[test]
+fn seals_are_signed_only_while_the_key_file_is_the_author() {
+    let (mining, settings) = mining_rpc(DenyUnsafe::No);
+
+    mining
+        .set_author(public("//Bob").to_ss58check())
+        .expect("Bob's address is valid");
+    assert_eq!(settings.borrow().author, public("//Bob"));
+    assert!(settings.borrow().key.is_none());
+
+    mining
+        .set_author(public("//Alice").to_ss58check())
+        .expect("Alice's address is valid");
+    assert_eq!(
+        settings.borrow().key.as_ref().map(Pair::public),
+        Some(public("//Alice"))
+    );
+}
@@ -7,13 +7,32 @@
 
 use std::sync::Arc;
 
-use academy_pow_runtime::{opaque::Block, AccountId, Balance, Index};
-use jsonrpsee::RpcModule;
+use academy_pow_runtime::{
+    difficulty::{DifficultyAdjustmentApi, DifficultyHistoryApi},
+    opaque::Block,
+    AccountId, Balance, Index,
+};
+use jsonrpsee::{
+    types::error::{CallError, ErrorObject},
+    RpcModule,
+};
+use multi_pow::Threshold;
+use sc_client_api::BlockBackend;
 pub use sc_rpc_api::DenyUnsafe;
 use sc_transaction_pool_api::TransactionPool;
 use sp_api::ProvideRuntimeApi;
 use sp_block_builder::BlockBuilder;
 use sp_blockchain::{Error as BlockChainError, HeaderBackend, HeaderMetadata};
+use sp_consensus_pow::DifficultyApi;
+
+use crate::{
+    difficulty_rpc::{Difficulty, DifficultyApiServer},
+    double_spend::{DoubleSpend, DoubleSpendApiServer, DoubleSpendRpc},
+    finality::{ConfirmationsApiServer, ConfirmationsRpc},
+    manual_seal::{ManualSealSinks, PowManualSeal, PowManualSealApiServer},
+    mining::{Mining, MiningApiServer, MiningControl},
+    selfish::{SelfishMining, Withholding, WithholdingApiServer},
+};
 
 /// Full client dependencies.
 pub struct FullDeps<C, P> {
@@ -23,6 +42,14 @@ pub struct FullDeps<C, P> {
     pub pool: Arc<P>,
     /// Whether to deny unsafe calls
     pub deny_unsafe: DenyUnsafe,
+    /// Channels to the manual seal author, if the node seals blocks manually
+    pub manual_seal: Option<ManualSealSinks>,
+    /// Control over the miner, if the node mines blocks
+    pub mining: Option<MiningControl>,
+    /// The selfish miner, if the node withholds the blocks it mines
+    pub withholding: Option<SelfishMining>,
+    /// The double spender, if the node offers double spend attacks
+    pub double_spend: Option<Arc<DoubleSpend<C>>>,
 }
 
 /// Instantiate all full RPC extensions.
@@ -32,10 +59,13 @@ pub fn create_full<C, P>(
 where
     C: ProvideRuntimeApi<Block>,
     C: HeaderBackend<Block> + HeaderMetadata<Block, Error = BlockChainError> + 'static,
+    C: BlockBackend<Block>,
     C: Send + Sync + 'static,
     C::Api: substrate_frame_rpc_system::AccountNonceApi<Block, AccountId, Index>,
     C::Api: pallet_transaction_payment_rpc::TransactionPaymentRuntimeApi<Block, Balance>,
     C::Api: BlockBuilder<Block>,
+    C::Api: DifficultyHistoryApi<Block, u64> + DifficultyAdjustmentApi<Block>,
+    C::Api: DifficultyApi<Block, Threshold>,
     P: TransactionPool + 'static,
 {
     use pallet_transaction_payment_rpc::{TransactionPayment, TransactionPaymentApiServer};
@@ -46,10 +76,45 @@ where
         client,
         pool,
         deny_unsafe,
+        manual_seal,
+        mining,
+        withholding,
+        double_spend,
     } = deps;
 
     module.merge(System::new(client.clone(), pool, deny_unsafe).into_rpc())?;
-    module.merge(TransactionPayment::new(client).into_rpc())?;
+    module.merge(TransactionPayment::new(client.clone()).into_rpc())?;
+    module.merge(Difficulty::new(client.clone()).into_rpc())?;
+    module.merge(ConfirmationsRpc::new(client).into_rpc())?;
+
+    if let Some(ManualSealSinks { engine, with_algo }) = manual_seal {
+        use sc_consensus_manual_seal::rpc::{ManualSeal, ManualSealApiServer};
+
+        module.merge(ManualSeal::new(engine).into_rpc())?;
+        module.merge(PowManualSeal::new(with_algo).into_rpc())?;
+    }
+
+    if let Some(control) = mining {
+        module.merge(Mining::new(control, deny_unsafe).into_rpc())?;
+    }
+
+    if let Some(selfish_mining) = withholding {
+        module.merge(Withholding::new(selfish_mining, deny_unsafe).into_rpc())?;
+    }
+
+    if let Some(double_spend) = double_spend {
+        module.merge(DoubleSpendRpc::new(double_spend, deny_unsafe).into_rpc())?;
+    }
 
     Ok(module)
 }
+
+/// The error for an RPC call with invalid parameters, explained by `message`.
+pub fn invalid_params(message: String) -> jsonrpsee::core::Error {
+    CallError::Custom(ErrorObject::owned(
+        jsonrpsee::types::error::INVALID_PARAMS_CODE,
+        message,
+        None::<()>,
+    ))
+    .into()
+}
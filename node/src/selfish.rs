@@ -0,0 +1,392 @@
+//! Selfish mining, for demonstrating the attack in class.
+//!
+//! A selfish miner doesn't announce the blocks it finds. It keeps mining on its own private
+//! chain, and only publishes blocks when the rest of the network is about to catch up, so that
+//! the honest miners waste their work on blocks that get reorged away. With a large enough
+//! share of the hashrate, this earns the attacker more than its fair share of the rewards.
+//!
+//! The strategy is the one described by Eyal and Sirer in "Majority is not Enough":
+//!
+//! - While ahead by more than two blocks, publish our blocks as the public chain reaches their
+//!   height, so the honest miners always have something to race against.
+//! - When the public chain comes within one block, publish everything and win with the longer
+//!   chain.
+//! - When it catches up, publish everything and race. If we find the next block, we publish it
+//!   straight away and win the race.
+//! - When it pulls ahead, give up on the private chain.
+//!
+//! Alternatively, the miner can withhold its blocks until told to release them over RPC.
+//!
+//! This is only meant for development chains. The node doesn't announce any imported blocks
+//! while withholding, and publishes by announcing its withheld blocks. Peers then import them
+//! as usual, so the reorgs show up like any other.
+//!
+//! Not announcing blocks only hides them from the peers we are already connected to. A peer that
+//! connects later learns our best block from the sync handshake, and syncs the best chain from
+//! there. So the miner imports its own blocks without making them the best block, and builds on
+//! them through [`WithholdingSelectChain`] instead. Once published, a block becomes our best
+//! block if it beats the public chain.
+
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use academy_pow_runtime::opaque::{Block, Header};
+use futures::StreamExt;
+use jsonrpsee::{core::RpcResult, proc_macros::rpc};
+use sc_client_api::{backend::BlockImportOperation, Backend, BlockchainEvents, LockImportRun};
+use sc_consensus::{
+    BlockCheckParams, BlockImport, BlockImportParams, ForkChoiceStrategy, ImportResult,
+};
+use sc_rpc_api::DenyUnsafe;
+use serde::{Deserialize, Serialize};
+use sp_blockchain::HeaderBackend;
+use sp_consensus::{BlockOrigin, Error as ConsensusError, SelectChain};
+use sp_core::H256;
+use sp_runtime::traits::Header as HeaderT;
+
+/// When to publish withheld blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WithholdingStrategy {
+    /// Publish blocks by the selfish mining strategy.
+    Selfish,
+    /// Only publish blocks when asked to over RPC.
+    Manual,
+}
+
+impl FromStr for WithholdingStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "selfish" => Ok(Self::Selfish),
+            "manual" => Ok(Self::Manual),
+            _ => Err(format!(
+                "Unknown withholding strategy: {s}. Possible values: selfish, manual"
+            )),
+        }
+    }
+}
+
+/// Publishes a block to the network.
+pub type Announce = Arc<dyn Fn(H256) + Send + Sync>;
+
+struct State {
+    /// Our blocks that nobody has seen yet, oldest first, with their numbers.
+    withheld: VecDeque<(u32, H256)>,
+    /// The last block we mined, with its number, published or not.
+    tip: Option<(u32, H256)>,
+    /// The height of the best chain the rest of the network knows about.
+    public_height: u32,
+    /// Whether we published our chain to tie with the public one, and are racing it.
+    racing: bool,
+}
+
+/// Where the selfish miner stands.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WithholdingStatus {
+    /// How many of our blocks we are withholding.
+    pub withheld: u32,
+    /// The height of our private chain, if we are withholding any blocks.
+    pub private_height: Option<u32>,
+    /// The height of the best chain the rest of the network knows about.
+    pub public_height: u32,
+    /// Whether we published our chain to tie with the public one, and are racing it.
+    pub racing: bool,
+}
+
+/// Withholds the blocks the node mines, and publishes them according to the strategy.
+#[derive(Clone)]
+pub struct SelfishMining {
+    strategy: WithholdingStrategy,
+    state: Arc<Mutex<State>>,
+    announce: Announce,
+}
+
+impl SelfishMining {
+    /// Start withholding from a chain of the given height, which everyone already knows about.
+    pub fn new(strategy: WithholdingStrategy, best_number: u32, announce: Announce) -> Self {
+        Self {
+            strategy,
+            state: Arc::new(Mutex::new(State {
+                withheld: VecDeque::new(),
+                tip: None,
+                public_height: best_number,
+                racing: false,
+            })),
+            announce,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("The withholding lock is never poisoned; qed")
+    }
+
+    /// Publish the withheld blocks up to the given height.
+    fn publish_up_to(&self, state: &mut State, height: u32) -> u32 {
+        let mut published = 0;
+        while let Some(&(number, hash)) = state.withheld.front() {
+            if number > height {
+                break;
+            }
+            (self.announce)(hash);
+            state.withheld.pop_front();
+            state.public_height = state.public_height.max(number);
+            published += 1;
+        }
+        published
+    }
+
+    /// Publish every withheld block.
+    pub fn release(&self) -> u32 {
+        let mut state = self.state();
+        let released = self.publish_up_to(&mut state, u32::MAX);
+        if released > 0 {
+            log::info!("😈 Released {released} withheld blocks");
+        }
+        released
+    }
+
+    /// How many blocks we are withholding, and how far ahead we are.
+    pub fn status(&self) -> WithholdingStatus {
+        let state = self.state();
+        WithholdingStatus {
+            withheld: state.withheld.len() as u32,
+            private_height: state.withheld.back().map(|(number, _)| *number),
+            public_height: state.public_height,
+            racing: state.racing,
+        }
+    }
+
+    /// The last block we mined, with its number, for the miner to build on.
+    pub fn tip(&self) -> Option<(u32, H256)> {
+        self.state().tip
+    }
+
+    /// We mined a block.
+    fn on_own_block(&self, number: u32, hash: H256) {
+        let mut state = self.state();
+        state.withheld.push_back((number, hash));
+        state.tip = Some((number, hash));
+
+        if self.strategy == WithholdingStrategy::Selfish && state.racing {
+            // We were tied with the honest miners, and now we're ahead. Take the win.
+            state.racing = false;
+            self.publish_up_to(&mut state, u32::MAX);
+            log::info!("😈 Won the race at #{number}");
+        } else {
+            log::info!("😈 Withholding #{number} ({hash:?})");
+        }
+    }
+
+    /// Someone else mined a block, and we imported it.
+    fn on_public_block(&self, number: u32) {
+        let mut state = self.state();
+        state.public_height = state.public_height.max(number);
+        state.racing = false;
+
+        if self.strategy == WithholdingStrategy::Manual {
+            return;
+        }
+        let Some(&(private_height, _)) = state.withheld.back() else {
+            return;
+        };
+
+        let public_height = state.public_height;
+        match private_height.checked_sub(public_height) {
+            // They are ahead. Our private chain is worthless now.
+            None => {
+                log::info!("😈 The public chain overtook our private chain. Giving up on it");
+                state.withheld.clear();
+                state.tip = None;
+            }
+            // They caught up. Publish everything and hope our blocks reach the others first.
+            Some(0) => {
+                self.publish_up_to(&mut state, u32::MAX);
+                state.racing = true;
+                log::info!("😈 Racing the public chain at #{public_height}");
+            }
+            // They are one block behind. Publishing everything orphans their latest blocks.
+            Some(1) => {
+                self.publish_up_to(&mut state, u32::MAX);
+                log::info!("😈 Published our chain up to #{private_height}, one block ahead");
+            }
+            // We're comfortably ahead. Only match their height, so they keep wasting work.
+            Some(_) => {
+                self.publish_up_to(&mut state, public_height);
+            }
+        }
+    }
+
+    /// Follow block imports, withholding our own blocks and publishing them when it pays.
+    pub async fn run<C>(self, client: Arc<C>)
+    where
+        C: BlockchainEvents<Block>,
+    {
+        let mut imports = client.import_notification_stream();
+
+        while let Some(notification) = imports.next().await {
+            let number = *notification.header.number();
+            match notification.origin {
+                BlockOrigin::Own => self.on_own_block(number, notification.hash),
+                _ => self.on_public_block(number),
+            }
+        }
+    }
+}
+
+/// Make a block we just published our best block, if it is higher than the current one.
+///
+/// We imported it without making it our best block, so that the sync handshake didn't tell new
+/// peers about it. Now that it is public, it may as well be.
+pub fn adopt<B, C>(client: &C, hash: H256) -> sp_blockchain::Result<()>
+where
+    B: Backend<Block>,
+    C: HeaderBackend<Block> + LockImportRun<Block, B>,
+{
+    let Some(number) = client.number(hash)? else {
+        return Ok(());
+    };
+    if number <= client.info().best_number {
+        return Ok(());
+    }
+
+    client.lock_import_and_run(|operation| operation.op.mark_head(hash))
+}
+
+/// Imports the blocks we mine without making them our best block, so that the sync handshake
+/// doesn't tell new peers about them. The miner builds on them through
+/// [`WithholdingSelectChain`].
+pub struct WithholdingBlockImport<I> {
+    inner: I,
+}
+
+impl<I> WithholdingBlockImport<I> {
+    pub fn new(inner: I) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait::async_trait]
+impl<I> BlockImport<Block> for WithholdingBlockImport<I>
+where
+    I: BlockImport<Block> + Send + Sync,
+{
+    type Error = I::Error;
+
+    async fn check_block(
+        &mut self,
+        block: BlockCheckParams<Block>,
+    ) -> Result<ImportResult, Self::Error> {
+        self.inner.check_block(block).await
+    }
+
+    async fn import_block(
+        &mut self,
+        mut block: BlockImportParams<Block>,
+    ) -> Result<ImportResult, Self::Error> {
+        block.fork_choice = Some(ForkChoiceStrategy::Custom(false));
+        self.inner.import_block(block).await
+    }
+}
+
+/// Picks the block to mine on. That is the last block we mined while it is at least as high as
+/// the best block, and the best block otherwise. Without a selfish miner, it is always the best
+/// block.
+pub struct WithholdingSelectChain<S, C> {
+    inner: S,
+    client: Arc<C>,
+    selfish_mining: Option<SelfishMining>,
+}
+
+impl<S, C> WithholdingSelectChain<S, C> {
+    pub fn new(inner: S, client: Arc<C>, selfish_mining: Option<SelfishMining>) -> Self {
+        Self {
+            inner,
+            client,
+            selfish_mining,
+        }
+    }
+}
+
+impl<S: Clone, C> Clone for WithholdingSelectChain<S, C> {
+    fn clone(&self) -> Self {
+        Self::new(
+            self.inner.clone(),
+            self.client.clone(),
+            self.selfish_mining.clone(),
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl<S, C> SelectChain<Block> for WithholdingSelectChain<S, C>
+where
+    S: SelectChain<Block>,
+    C: HeaderBackend<Block>,
+{
+    async fn leaves(&self) -> Result<Vec<H256>, ConsensusError> {
+        self.inner.leaves().await
+    }
+
+    async fn best_chain(&self) -> Result<Header, ConsensusError> {
+        let best = self.inner.best_chain().await?;
+        let Some((number, hash)) = self.selfish_mining.as_ref().and_then(SelfishMining::tip) else {
+            return Ok(best);
+        };
+        if number < *best.number() {
+            return Ok(best);
+        }
+
+        self.client
+            .header(hash)
+            .map_err(|err| ConsensusError::ChainLookup(err.to_string()))?
+            .ok_or_else(|| ConsensusError::ChainLookup(format!("Our block {hash:?} is gone")))
+    }
+}
+
+/// RPC methods to control the selfish miner.
+#[rpc(server)]
+pub trait WithholdingApi {
+    /// Publish every withheld block. Returns how many there were. This is an unsafe method.
+    #[method(name = "withholding_release")]
+    fn release(&self) -> RpcResult<u32>;
+
+    /// How many blocks the node is withholding, and how far ahead of the public chain it is.
+    #[method(name = "withholding_status")]
+    fn status(&self) -> RpcResult<WithholdingStatus>;
+}
+
+/// Implements the `WithholdingApi` on top of the selfish miner.
+pub struct Withholding {
+    selfish_mining: SelfishMining,
+    deny_unsafe: DenyUnsafe,
+}
+
+impl Withholding {
+    pub fn new(selfish_mining: SelfishMining, deny_unsafe: DenyUnsafe) -> Self {
+        Self {
+            selfish_mining,
+            deny_unsafe,
+        }
+    }
+}
+
+impl WithholdingApiServer for Withholding {
+    fn release(&self) -> RpcResult<u32> {
+        self.deny_unsafe.check_if_safe()?;
+        Ok(self.selfish_mining.release())
+    }
+
+    fn status(&self) -> RpcResult<WithholdingStatus> {
+        Ok(self.selfish_mining.status())
+    }
+}
+
+#[cfg(test)]
+mod tests;
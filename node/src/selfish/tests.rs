@@ -0,0 +1,163 @@
+use super::*;
+
+/// The height of the chain everyone knows about when the selfish miner starts.
+const START: u32 = 10;
+
+/// A selfish miner, along with the blocks it announces.
+fn selfish_miner(strategy: WithholdingStrategy) -> (SelfishMining, Arc<Mutex<Vec<H256>>>) {
+    let announced = Arc::new(Mutex::new(Vec::new()));
+    let announce: Announce = {
+        let announced = announced.clone();
+        Arc::new(move |hash| announced.lock().unwrap().push(hash))
+    };
+
+    (SelfishMining::new(strategy, START, announce), announced)
+}
+
+/// Our block at the given height.
+fn own(number: u32) -> H256 {
+    H256::from_low_u64_be(number.into())
+}
+
+/// Mine our own blocks up to the given height.
+fn mine_up_to(selfish_mining: &SelfishMining, height: u32) {
+    for number in START + 1..=height {
+        selfish_mining.on_own_block(number, own(number));
+    }
+}
+
+#[test]
+fn withholds_own_blocks() {
+    let (selfish_mining, announced) = selfish_miner(WithholdingStrategy::Selfish);
+    mine_up_to(&selfish_mining, START + 2);
+
+    assert!(announced.lock().unwrap().is_empty());
+    assert_eq!(
+        selfish_mining.status(),
+        WithholdingStatus {
+            withheld: 2,
+            private_height: Some(START + 2),
+            public_height: START,
+            racing: false,
+        }
+    );
+}
+
+#[test]
+fn gives_up_when_overtaken() {
+    let (selfish_mining, announced) = selfish_miner(WithholdingStrategy::Selfish);
+    mine_up_to(&selfish_mining, START + 1);
+
+    selfish_mining.on_public_block(START + 2);
+
+    assert!(announced.lock().unwrap().is_empty());
+    assert_eq!(selfish_mining.status().withheld, 0);
+    assert_eq!(selfish_mining.status().public_height, START + 2);
+}
+
+#[test]
+fn races_on_a_tie_and_wins_with_the_next_block() {
+    let (selfish_mining, announced) = selfish_miner(WithholdingStrategy::Selfish);
+    mine_up_to(&selfish_mining, START + 1);
+
+    selfish_mining.on_public_block(START + 1);
+    assert_eq!(*announced.lock().unwrap(), vec![own(START + 1)]);
+    assert!(selfish_mining.status().racing);
+
+    // Our next block is published straight away.
+    selfish_mining.on_own_block(START + 2, own(START + 2));
+    assert_eq!(
+        *announced.lock().unwrap(),
+        vec![own(START + 1), own(START + 2)]
+    );
+    assert!(!selfish_mining.status().racing);
+    assert_eq!(selfish_mining.status().withheld, 0);
+}
+
+#[test]
+fn loses_the_race_when_they_find_the_next_block() {
+    let (selfish_mining, _) = selfish_miner(WithholdingStrategy::Selfish);
+    mine_up_to(&selfish_mining, START + 1);
+    selfish_mining.on_public_block(START + 1);
+
+    selfish_mining.on_public_block(START + 2);
+    assert!(!selfish_mining.status().racing);
+
+    // Back to withholding.
+    selfish_mining.on_own_block(START + 3, own(START + 3));
+    assert_eq!(selfish_mining.status().withheld, 1);
+}
+
+#[test]
+fn publishes_everything_with_a_lead_of_one() {
+    let (selfish_mining, announced) = selfish_miner(WithholdingStrategy::Selfish);
+    mine_up_to(&selfish_mining, START + 2);
+
+    selfish_mining.on_public_block(START + 1);
+
+    assert_eq!(
+        *announced.lock().unwrap(),
+        vec![own(START + 1), own(START + 2)]
+    );
+    assert_eq!(selfish_mining.status().withheld, 0);
+    assert_eq!(selfish_mining.status().public_height, START + 2);
+    assert!(!selfish_mining.status().racing);
+}
+
+#[test]
+fn only_matches_the_public_height_with_a_bigger_lead() {
+    let (selfish_mining, announced) = selfish_miner(WithholdingStrategy::Selfish);
+    mine_up_to(&selfish_mining, START + 3);
+
+    selfish_mining.on_public_block(START + 1);
+    assert_eq!(*announced.lock().unwrap(), vec![own(START + 1)]);
+    assert_eq!(selfish_mining.status().withheld, 2);
+
+    // Down to a lead of one, so the rest goes out.
+    selfish_mining.on_public_block(START + 2);
+    assert_eq!(
+        *announced.lock().unwrap(),
+        vec![own(START + 1), own(START + 2), own(START + 3)]
+    );
+    assert_eq!(selfish_mining.status().withheld, 0);
+}
+
+#[test]
+fn manual_withholding_only_publishes_on_release() {
+    let (selfish_mining, announced) = selfish_miner(WithholdingStrategy::Manual);
+    mine_up_to(&selfish_mining, START + 2);
+
+    selfish_mining.on_public_block(START + 1);
+    selfish_mining.on_public_block(START + 2);
+    assert!(announced.lock().unwrap().is_empty());
+
+    assert_eq!(selfish_mining.release(), 2);
+    assert_eq!(
+        *announced.lock().unwrap(),
+        vec![own(START + 1), own(START + 2)]
+    );
+}
+
+#[test]
+fn mines_on_its_own_blocks_whether_published_or_not() {
+    let (selfish_mining, _) = selfish_miner(WithholdingStrategy::Selfish);
+    assert_eq!(selfish_mining.tip(), None);
+
+    mine_up_to(&selfish_mining, START + 2);
+    assert_eq!(selfish_mining.tip(), Some((START + 2, own(START + 2))));
+
+    // A lead of one publishes everything, and we keep building on it.
+    selfish_mining.on_public_block(START + 1);
+    assert_eq!(selfish_mining.status().withheld, 0);
+    assert_eq!(selfish_mining.tip(), Some((START + 2, own(START + 2))));
+}
+
+#[test]
+fn forgets_its_tip_when_overtaken() {
+    let (selfish_mining, _) = selfish_miner(WithholdingStrategy::Selfish);
+    mine_up_to(&selfish_mining, START + 1);
+
+    selfish_mining.on_public_block(START + 2);
+
+    assert_eq!(selfish_mining.tip(), None);
+}
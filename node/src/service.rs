@@ -1,17 +1,44 @@
 //! Service and ServiceFactory implementation. Specialized wrapper over substrate service.
 
 use core::clone::Clone;
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use academy_pow_runtime::{self, opaque::Block, PreDigest, RuntimeApi};
+use academy_pow_runtime::{self, opaque::Block, PreDigest, RuntimeApi, UncleGenerations};
+use futures::FutureExt;
 use multi_pow::{ForkingConfig, MultiPow, SupportedHashes};
 use parity_scale_codec::Encode;
+use sc_client_api::Backend;
 use sc_consensus::LongestChain;
 use sc_executor::NativeElseWasmExecutor;
-use sc_service::{error::Error as ServiceError, Configuration, PartialComponents, TaskManager};
+use sc_network::NetworkBlock;
+use sc_service::{
+    error::Error as ServiceError, ChainType, Configuration, PartialComponents, TaskManager,
+};
 use sc_telemetry::{Telemetry, TelemetryWorker};
+use sp_blockchain::HeaderBackend;
 use sp_core::sr25519;
 
+use crate::{
+    chain_spec::ForkingExtensions,
+    checkpoints::{Checkpoint, CheckpointBlockImport},
+    double_spend::DoubleSpend,
+    finality::run_confirmation_finality,
+    manual_seal::{command_channels, PowConsensusDataProvider, SharedAlgo},
+    metrics::Metrics,
+    mining::{
+        allowed_algos, control_channel, run_miner, ForbiddenParent, MiningSettings,
+        SharedEnvironment,
+    },
+    selfish::{
+        adopt, Announce, SelfishMining, WithholdingBlockImport, WithholdingSelectChain,
+        WithholdingStrategy,
+    },
+    uncles::{uncle_candidates, UncleWorkBlockImport},
+};
+
 // Our native executor instance.
 pub struct ExecutorDispatch;
 
@@ -35,12 +62,81 @@ type FullSelectChain = sc_consensus::LongestChain<FullBackend, Block>;
 type BasicImportQueue = sc_consensus::DefaultImportQueue<Block>;
 type BoxBlockImport = sc_consensus::BoxBlockImport<Block>;
 
+/// Creates the PoW block import. The miner takes a fresh one every time it restarts.
+pub type BlockImportFactory = Arc<dyn Fn() -> BoxBlockImport + Send + Sync>;
+
+/// How the node seals the blocks it authors.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Sealing {
+    /// Mine blocks with real proof of work.
+    Pow,
+    /// Seal a block as soon as there are transactions for it, without doing any work.
+    Instant,
+    /// Seal blocks only when asked to over RPC, without doing any work.
+    Manual,
+}
+
+/// Everything the node needs to know about how to author blocks.
+#[derive(Clone)]
+pub struct MiningConfig {
+    /// The account that receives the block rewards.
+    pub author: sr25519::Public,
+    /// The author's key pair, if seals should be signed.
+    pub key: Option<sr25519::Pair>,
+    /// The hashing algorithm to mine with.
+    pub algo: SupportedHashes,
+    /// Whether to switch to whichever algorithm is the most profitable to mine.
+    pub profit_switching: bool,
+    /// Whether to switch to an allowed algorithm, rather than stop, when the fork rules
+    /// forbid `algo`.
+    pub fork_fallback: bool,
+    /// The algorithm the fork rules follow, if the maxi position follows mining.
+    pub followed_algo: Option<SupportedHashes>,
+    /// The extra nonce to put in every seal. Random if not set.
+    pub extra_nonce: Option<u64>,
+    /// Whether to withhold mined blocks, and when to publish them. Only for development chains.
+    pub withholding: Option<WithholdingStrategy>,
+    /// Whether to offer the double spend RPC. Only for development chains.
+    pub double_spend: bool,
+    /// How to seal the blocks.
+    pub sealing: Sealing,
+    /// Whether blocks sealed on demand get a genuine seal that peers accept, rather than a
+    /// dummy one.
+    pub mine_seals: bool,
+}
+
+/// Everything the node needs to know about which blocks to import, beyond the fork rules.
+#[derive(Clone)]
+pub struct ImportConfig {
+    /// Whether the work of included uncles counts towards fork choice.
+    pub count_uncle_work: bool,
+    /// Whether seals must be signed by the author named in the pre-digest.
+    pub require_signed_seals: bool,
+    /// How far in the future an imported block's timestamp may be.
+    pub max_timestamp_drift: Duration,
+    /// Blocks the chain must contain, in addition to the chain spec's checkpoints.
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// The timestamp to check imported blocks against.
+///
+/// The runtime's drift allowance is fixed, so we shift the timestamp we give it
+/// to make the effective allowance `max_drift` instead.
+fn import_timestamp(max_drift: Duration) -> sp_timestamp::InherentDataProvider {
+    let now = *sp_timestamp::Timestamp::current();
+    let shifted = (now + max_drift.as_millis() as u64)
+        .saturating_sub(academy_pow_runtime::MAX_TIMESTAMP_DRIFT_MILLIS);
+
+    sp_timestamp::InherentDataProvider::new(shifted.into())
+}
+
 /// Returns most parts of a service. Not enough to run a full chain,
 /// But enough to perform chain operations like purge-chain
 #[allow(clippy::type_complexity)]
 pub fn new_partial(
     config: &Configuration,
     fork_config: ForkingConfig,
+    import_config: ImportConfig,
 ) -> Result<
     PartialComponents<
         FullClient,
@@ -48,7 +144,7 @@ pub fn new_partial(
         FullSelectChain,
         BasicImportQueue,
         sc_transaction_pool::FullPool<Block, FullClient>,
-        (BoxBlockImport, Option<Telemetry>),
+        (BlockImportFactory, Option<Telemetry>, Option<Metrics>),
     >,
     ServiceError,
 > {
@@ -90,18 +186,42 @@ pub fn new_partial(
         client.clone(),
     );
 
+    let ImportConfig {
+        count_uncle_work,
+        require_signed_seals,
+        max_timestamp_drift,
+        checkpoints: extra_checkpoints,
+    } = import_config;
+
+    // Checkpoints come from both the chain spec and the command line.
+    let mut checkpoints = ForkingExtensions::try_get(&*config.chain_spec)
+        .map(|extensions| extensions.checkpoints.clone())
+        .unwrap_or_default();
+    checkpoints.extend(extra_checkpoints);
+
+    let metrics = config
+        .prometheus_registry()
+        .map(Metrics::register)
+        .transpose()?;
+
+    let mut algorithm =
+        MultiPow::new(client.clone(), fork_config).with_signed_seals(require_signed_seals);
+    if let Some(metrics) = &metrics {
+        algorithm = algorithm.with_reject_hook(metrics.reject_hook());
+    }
+
     let block_import = sc_consensus_pow::PowBlockImport::new(
         client.clone(),
         client.clone(),
-        MultiPow::new(client.clone(), fork_config),
+        algorithm.clone(),
         0, // check inherents starting at block 0
         select_chain.clone(),
         move |_, ()| async move {
-            let timestamp = sp_timestamp::InherentDataProvider::from_system_time();
+            let timestamp = import_timestamp(max_timestamp_drift);
 
-            // We don't need the current mining key to check inherents, so we just use a default.
-            // TODO, I don't think we need to do any checking here at all, right?
-            // So can I just remove the author entirely?
+            // The runtime checks the author inherent against the block's own pre-digest, so the
+            // data we provide here is never used. We still need the provider, because it is the
+            // one that turns the runtime's author errors into a readable import error.
             let author =
                 academy_pow_runtime::block_author::InherentDataProvider(Default::default());
 
@@ -109,14 +229,43 @@ pub fn new_partial(
         },
     );
 
+    // Optionally wrap the PoW import so that the work of included uncles counts towards fork choice.
+    // Either way, blocks conflicting with a checkpoint don't even get that far.
+    let boxed_block_import = {
+        let client = client.clone();
+        let select_chain = select_chain.clone();
+        let algorithm = algorithm.clone();
+
+        move || -> BoxBlockImport {
+            let pow_import: BoxBlockImport = if count_uncle_work {
+                Box::new(UncleWorkBlockImport::new(
+                    block_import.clone(),
+                    client.clone(),
+                    select_chain.clone(),
+                    algorithm.clone(),
+                ))
+            } else {
+                Box::new(block_import.clone())
+            };
+
+            Box::new(CheckpointBlockImport::new(
+                pow_import,
+                client.clone(),
+                checkpoints.clone(),
+            ))
+        }
+    };
+
     let import_queue = sc_consensus_pow::import_queue(
-        Box::new(block_import.clone()),
+        boxed_block_import(),
         None,
-        MultiPow::new(client.clone(), fork_config),
+        algorithm,
         &task_manager.spawn_essential_handle(),
         config.prometheus_registry(),
     )?;
 
+    let block_import: BlockImportFactory = Arc::new(boxed_block_import);
+
     Ok(PartialComponents {
         client,
         backend,
@@ -125,18 +274,46 @@ pub fn new_partial(
         keystore_container,
         select_chain,
         transaction_pool,
-        other: (Box::new(block_import), telemetry),
+        other: (block_import, telemetry, metrics),
     })
 }
 
 /// Builds a new service for a full client.
 pub fn new_full(
-    config: Configuration,
+    mut config: Configuration,
     fork_config: ForkingConfig,
-    sr25519_public_key: sr25519::Public,
-    instant_seal: bool,
-    mining_algo: SupportedHashes,
+    mining_config: MiningConfig,
+    import_config: ImportConfig,
 ) -> Result<TaskManager, ServiceError> {
+    let MiningConfig {
+        author: sr25519_public_key,
+        key: mining_key,
+        algo: mining_algo,
+        profit_switching,
+        fork_fallback,
+        followed_algo,
+        extra_nonce,
+        withholding,
+        double_spend,
+        sealing,
+        mine_seals,
+    } = mining_config;
+
+    // Withholding blocks and double spending are attacks. Only let students try them where
+    // nothing is at stake.
+    if withholding.is_some() || double_spend {
+        if !matches!(
+            config.chain_spec.chain_type(),
+            ChainType::Development | ChainType::Local
+        ) {
+            return Err(ServiceError::Other(
+                "Attacks are only allowed on development chains".into(),
+            ));
+        }
+        // Don't announce anything we import, so our blocks stay private until we publish them.
+        config.announce_block = false;
+    }
+
     let sc_service::PartialComponents {
         client,
         backend,
@@ -145,8 +322,8 @@ pub fn new_full(
         keystore_container,
         select_chain,
         transaction_pool,
-        other: (pow_block_import, mut telemetry),
-    } = new_partial(&config, fork_config)?;
+        other: (pow_block_import, mut telemetry, metrics),
+    } = new_partial(&config, fork_config, import_config.clone())?;
 
     let net_config = sc_network::config::FullNetworkConfiguration::new(&config.network);
 
@@ -163,18 +340,101 @@ pub fn new_full(
             block_relay: None,
         })?;
 
+    // Keep a handle on the backend so the miner can look for stale blocks to include as uncles.
+    let uncles_backend = backend.clone();
+
     let role = config.role.clone();
     let prometheus_registry = config.prometheus_registry().cloned();
+    let confirmation_depth = ForkingExtensions::try_get(&*config.chain_spec)
+        .map(|extensions| extensions.confirmation_depth)
+        .unwrap_or_default();
+
+    // With manual seal, the RPCs need a way to tell the author to seal a block.
+    let sealing_algo: SharedAlgo = Arc::new(Mutex::new(mining_algo));
+    let (manual_seal_sinks, manual_seal_commands) =
+        if role.is_authority() && sealing == Sealing::Manual {
+            let (sinks, commands) = command_channels(mining_algo, sealing_algo.clone());
+            (Some(sinks), Some(commands))
+        } else {
+            (None, None)
+        };
+
+    // A selfish miner publishes its blocks by announcing them itself. It imported them without
+    // making them its best block, so it adopts them now, if they beat the public chain.
+    let selfish_mining = match withholding {
+        Some(strategy) if role.is_authority() && sealing == Sealing::Pow => {
+            let announce: Announce = {
+                let sync_service = sync_service.clone();
+                let client = client.clone();
+                Arc::new(move |hash| {
+                    if let Err(err) = adopt(&*client, hash) {
+                        log::warn!("😈 Failed to make {hash:?} our best block: {err}");
+                    }
+                    sync_service.announce_block(hash, None)
+                })
+            };
+            Some(SelfishMining::new(
+                strategy,
+                client.info().best_number,
+                announce,
+            ))
+        }
+        _ => None,
+    };
+
+    // A double spender announces everything but its private branch itself.
+    let double_spend =
+        (double_spend && role.is_authority() && sealing == Sealing::Pow).then(|| {
+            let sync_service = sync_service.clone();
+            let announce: Announce = Arc::new(move |hash| sync_service.announce_block(hash, None));
+            let algorithm = MultiPow::new(client.clone(), fork_config)
+                .with_signed_seals(import_config.require_signed_seals);
+
+            Arc::new(DoubleSpend::new(
+                client.clone(),
+                pow_block_import.clone(),
+                algorithm,
+                allowed_algos(client.clone(), fork_config),
+                mining_algo,
+                sr25519_public_key,
+                mining_key.clone(),
+                announce,
+            ))
+        });
+
+    // With PoW, the RPCs can start, stop and reconfigure the miner.
+    let (mining_control, mining_settings) = if role.is_authority() && sealing == Sealing::Pow {
+        let (control, settings) = control_channel(MiningSettings {
+            running: true,
+            algo: mining_algo,
+            profit_switching,
+            fork_fallback,
+            followed_algo,
+            author: sr25519_public_key,
+            key: mining_key.clone(),
+            extra_nonce,
+        });
+        (Some(control), Some(settings))
+    } else {
+        (None, None)
+    };
 
     let rpc_extensions_builder = {
         let client = client.clone();
         let pool = transaction_pool.clone();
+        let mining_control = mining_control.clone();
+        let selfish_mining = selfish_mining.clone();
+        let double_spend = double_spend.clone();
 
         Box::new(move |deny_unsafe, _| {
             let deps = crate::rpc::FullDeps {
                 client: client.clone(),
                 pool: pool.clone(),
                 deny_unsafe,
+                manual_seal: manual_seal_sinks.clone(),
+                mining: mining_control.clone(),
+                withholding: selfish_mining.clone(),
+                double_spend: double_spend.clone(),
             };
             crate::rpc::create_full(deps).map_err(Into::into)
         })
@@ -195,6 +455,38 @@ pub fn new_full(
         telemetry: telemetry.as_mut(),
     })?;
 
+    if let Some(metrics) = metrics.clone() {
+        task_manager.spawn_handle().spawn(
+            "pow-metrics",
+            None,
+            metrics.run_import_metrics(client.clone()),
+        );
+    }
+
+    if let Some(selfish_mining) = selfish_mining.clone() {
+        task_manager.spawn_handle().spawn(
+            "pow-withholding",
+            None,
+            selfish_mining.run(client.clone()),
+        );
+    }
+
+    if let Some(double_spend) = &double_spend {
+        task_manager.spawn_handle().spawn(
+            "pow-double-spend-announcer",
+            None,
+            double_spend.announce_public_blocks(),
+        );
+    }
+
+    if confirmation_depth > 0 {
+        task_manager.spawn_handle().spawn(
+            "pow-finality",
+            None,
+            run_confirmation_finality::<_, FullBackend>(client.clone(), confirmation_depth),
+        );
+    }
+
     if role.is_authority() {
         let proposer = sc_basic_authorship::ProposerFactory::new(
             task_manager.spawn_handle(),
@@ -204,18 +496,35 @@ pub fn new_full(
             telemetry.as_ref().map(|x| x.handle()),
         );
 
-        // If instant seal is requested, we just start it. Otherwise, we do the full PoW setup.
-        if instant_seal {
+        // Blocks sealed on demand still need the inherents a mined block has.
+        let seal_inherent_data_providers = move |_, ()| async move {
+            let timestamp = sp_timestamp::InherentDataProvider::from_system_time();
+
+            let author = academy_pow_runtime::block_author::InherentDataProvider(
+                sr25519_public_key.encode(),
+            );
+
+            Ok((timestamp, author))
+        };
+
+        // If instant or manual seal is requested, we just start it. Otherwise, we do the full PoW setup.
+        if sealing == Sealing::Instant {
+            let consensus_data_provider = PowConsensusDataProvider::new(
+                client.clone(),
+                sr25519_public_key,
+                mining_key,
+                sealing_algo,
+                fork_config,
+            )
+            .with_mined_seals(mine_seals);
             let params = sc_consensus_manual_seal::InstantSealParams {
                 block_import: client.clone(),
                 env: proposer,
                 client,
                 pool: transaction_pool,
                 select_chain,
-                consensus_data_provider: None,
-                create_inherent_data_providers: move |_, ()| async move {
-                    Ok(sp_timestamp::InherentDataProvider::from_system_time())
-                },
+                consensus_data_provider: Some(Box::new(consensus_data_provider)),
+                create_inherent_data_providers: seal_inherent_data_providers,
             };
 
             let authorship_future = sc_consensus_manual_seal::run_instant_seal(params);
@@ -225,68 +534,129 @@ pub fn new_full(
                 None,
                 authorship_future,
             );
-        } else {
-            let (mining_worker, mining_worker_task) = sc_consensus_pow::start_mining_worker(
-                Box::new(pow_block_import),
+        } else if let Some(commands_stream) = manual_seal_commands {
+            let consensus_data_provider = PowConsensusDataProvider::new(
                 client.clone(),
+                sr25519_public_key,
+                mining_key,
+                sealing_algo,
+                fork_config,
+            )
+            .with_mined_seals(mine_seals);
+            let params = sc_consensus_manual_seal::ManualSealParams {
+                block_import: client.clone(),
+                env: proposer,
+                client,
+                pool: transaction_pool,
+                commands_stream,
                 select_chain,
-                MultiPow::new(client, fork_config),
-                proposer,
-                sync_service.clone(),
-                sync_service,
-                // Note the mining algorithm in the pre-runtime digest.
-                // This allows us to know which algo it was in the runtime.
-                // TODO This also makes it possible to remove the algo info from
-                // the seal.
-                Some(PreDigest::from((sr25519_public_key.into(), mining_algo)).encode()),
-                // This code is copied from above. Would be better to not repeat it.
-                move |_, ()| async move {
-                    let timestamp = sp_timestamp::InherentDataProvider::from_system_time();
-
-                    let author = academy_pow_runtime::block_author::InherentDataProvider(
-                        sr25519_public_key.encode(),
-                    );
-
-                    Ok((timestamp, author))
-                },
-                std::time::Duration::from_secs(10),
-                std::time::Duration::from_secs(5),
+                consensus_data_provider: Some(Box::new(consensus_data_provider)),
+                create_inherent_data_providers: seal_inherent_data_providers,
+            };
+
+            let authorship_future = sc_consensus_manual_seal::run_manual_seal(params);
+
+            task_manager.spawn_essential_handle().spawn_blocking(
+                "manual-seal",
+                None,
+                authorship_future,
             );
+        } else if let (Some(mining_control), Some(mining_settings)) =
+            (mining_control, mining_settings)
+        {
+            let mut algorithm = MultiPow::new(client.clone(), fork_config)
+                .with_signed_seals(import_config.require_signed_seals);
+            if let Some(metrics) = &metrics {
+                algorithm = algorithm.with_reject_hook(metrics.reject_hook());
+            }
+
+            let allowed_algos = allowed_algos(client.clone(), fork_config);
+            let allowed_algos_for_miner = allowed_algos.clone();
+
+            // A selfish miner keeps its blocks out of the best chain, but still builds on them.
+            let select_chain =
+                WithholdingSelectChain::new(select_chain, client.clone(), selfish_mining.clone());
+            let withholds = selfish_mining.is_some();
+
+            // Every restart of the miner starts a new mining worker, which gets its own block
+            // import but shares the proposer factory.
+            let proposer = SharedEnvironment::new(proposer);
+            let start_worker = move |settings: &MiningSettings, forbidden: ForbiddenParent| {
+                let (author, algo) = (settings.author, settings.algo);
+                let uncles_backend = uncles_backend.clone();
+                let worker_allowed_algos = allowed_algos.clone();
+
+                let block_import: BoxBlockImport = if withholds {
+                    Box::new(WithholdingBlockImport::new(pow_block_import()))
+                } else {
+                    pow_block_import()
+                };
+
+                let (worker, worker_task) = sc_consensus_pow::start_mining_worker(
+                    block_import,
+                    client.clone(),
+                    select_chain.clone(),
+                    algorithm.clone(),
+                    proposer.clone(),
+                    sync_service.clone(),
+                    sync_service.clone(),
+                    // Note the mining algorithm in the pre-runtime digest.
+                    // This allows us to know which algo it was in the runtime.
+                    // TODO This also makes it possible to remove the algo info from
+                    // the seal.
+                    Some(PreDigest::from((author.into(), algo)).encode()),
+                    // This code is copied from above. Would be better to not repeat it.
+                    move |parent, ()| {
+                        // The inherent data is the last thing the worker needs before it builds
+                        // a block, so check the fork rules here, and don't build at all on a
+                        // parent where our own import would reject the block.
+                        let allowed = worker_allowed_algos(parent).contains(&algo);
+                        if !allowed {
+                            forbidden.set(parent);
+                        }
+                        let uncles = uncle_candidates(
+                            uncles_backend.blockchain(),
+                            parent,
+                            UncleGenerations::get(),
+                        );
+
+                        async move {
+                            if !allowed {
+                                return Err(format!(
+                                    "The fork rules forbid mining with {algo:?} after {parent}"
+                                )
+                                .into());
+                            }
+
+                            let timestamp = sp_timestamp::InherentDataProvider::from_system_time();
+
+                            let author = academy_pow_runtime::block_author::InherentDataProvider(
+                                author.encode(),
+                            );
+
+                            let uncles = academy_pow_runtime::uncles::InherentDataProvider(uncles);
+
+                            Ok((timestamp, author, uncles))
+                        }
+                    },
+                    Duration::from_secs(10),
+                    Duration::from_secs(5),
+                );
+
+                (worker, worker_task.boxed())
+            };
 
             task_manager.spawn_essential_handle().spawn_blocking(
                 "pow-miner",
                 Some("pow-mining"),
-                mining_worker_task,
+                run_miner(
+                    start_worker,
+                    mining_control,
+                    mining_settings,
+                    allowed_algos_for_miner,
+                    metrics,
+                ),
             );
-
-            // Start Mining worker.
-            //TODO Some of this should move into the multi_pow crate.
-            use multi_pow::{multi_hash_meets_difficulty, Compute};
-            use sp_core::U256;
-            let mut nonce: U256 = U256::from(0);
-            std::thread::spawn(move || loop {
-                let worker = mining_worker.clone();
-                let metadata = worker.metadata();
-                if let Some(metadata) = metadata {
-                    let compute = Compute {
-                        difficulty: metadata.difficulty,
-                        pre_hash: metadata.pre_hash,
-                        nonce,
-                    };
-                    let seal = compute.compute(mining_algo);
-                    if multi_hash_meets_difficulty(&seal.work, seal.difficulty) {
-                        nonce = U256::from(0);
-                        let _ = futures::executor::block_on(worker.submit(seal.encode()));
-                    } else {
-                        nonce = nonce.saturating_add(U256::from(1));
-                        if nonce == U256::MAX {
-                            nonce = U256::from(0);
-                        }
-                    }
-                } else {
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                }
-            });
         }
     }
 
@@ -0,0 +1,223 @@
+//! Client-side support for uncles.
+//!
+//! The runtime's uncles pallet decides which uncles are valid and pays the rewards.
+//! The node's job is to find candidate uncles for the miner to include, and optionally,
+//! to count the work of included uncles when choosing the best chain.
+
+use std::sync::Arc;
+
+use academy_pow_runtime::{
+    opaque::{Block, Header},
+    uncles, RuntimeCall, UncheckedExtrinsic,
+};
+use multi_pow::{MultiPow, Seal, SupportedHashes, Threshold};
+use parity_scale_codec::{Decode, Encode};
+use sc_client_api::AuxStore;
+use sc_consensus::{
+    BlockCheckParams, BlockImport, BlockImportParams, ForkChoiceStrategy, ImportResult,
+};
+use sc_consensus_pow::{PowAlgorithm, PowAux, PowIntermediate, INTERMEDIATE_KEY};
+use sp_api::ProvideRuntimeApi;
+use sp_blockchain::HeaderBackend;
+use sp_consensus::{Error as ConsensusError, SelectChain};
+use sp_consensus_pow::{DifficultyApi, TotalDifficulty, POW_ENGINE_ID};
+use sp_core::H256;
+use sp_runtime::{
+    traits::{Header as HeaderT, Zero},
+    DigestItem,
+};
+
+/// Collect the headers of stale blocks that a child of `parent_hash` could include as uncles.
+///
+/// These are the siblings of each of the last `generations` blocks in the chain ending at
+/// `parent_hash`. Whether they are actually acceptable is up to the runtime, which filters
+/// them when creating the inherent.
+pub fn uncle_candidates<B>(blockchain: &B, parent_hash: H256, generations: u32) -> Vec<Header>
+where
+    B: sp_blockchain::Backend<Block>,
+{
+    let mut candidates = Vec::new();
+    let mut child = parent_hash;
+
+    for _ in 0..generations {
+        let Ok(Some(header)) = blockchain.header(child) else {
+            break;
+        };
+        if header.number().is_zero() {
+            break;
+        }
+        let ancestor = *header.parent_hash();
+
+        // Every other child of this ancestor is a stale sibling of our chain.
+        for sibling in blockchain.children(ancestor).unwrap_or_default() {
+            if sibling == child {
+                continue;
+            }
+            if let Ok(Some(uncle)) = blockchain.header(sibling) {
+                candidates.push(uncle);
+            }
+        }
+
+        child = ancestor;
+    }
+
+    candidates
+}
+
+/// Prefix for the aux storage entries holding the cumulative uncle work of each block.
+const UNCLE_WORK_AUX_PREFIX: [u8; 4] = *b"Unc:";
+
+fn aux_key(hash: &H256) -> Vec<u8> {
+    UNCLE_WORK_AUX_PREFIX
+        .iter()
+        .chain(hash.as_ref())
+        .copied()
+        .collect()
+}
+
+/// Read the total work of all uncles included in the chain ending at `hash`.
+/// Blocks imported before uncle work was tracked are treated as having none.
+fn read_uncle_work<C: AuxStore>(client: &C, hash: &H256) -> Result<Threshold, ConsensusError> {
+    match client
+        .get_aux(&aux_key(hash))
+        .map_err(|e| ConsensusError::ClientImport(e.to_string()))?
+    {
+        Some(bytes) => Threshold::decode(&mut &bytes[..])
+            .map_err(|e| ConsensusError::ClientImport(e.to_string())),
+        None => Ok(Threshold::default()),
+    }
+}
+
+/// The work of all the uncles included in a block body.
+///
+/// Each uncle counts for the difficulty it was sealed at, but only towards its own algorithm.
+/// The runtime checks that the seals are genuine, so we can take the claimed difficulty at face
+/// value here. An invalid block will fail to import anyway.
+fn included_uncle_work(body: &[<Block as sp_runtime::traits::Block>::Extrinsic]) -> Threshold {
+    let mut work = Threshold::default();
+
+    let included_uncles = body.iter().filter_map(|extrinsic| {
+        match UncheckedExtrinsic::decode(&mut &extrinsic.encode()[..]) {
+            Ok(UncheckedExtrinsic {
+                function: RuntimeCall::Uncles(uncles::Call::set_uncles { uncles }),
+                ..
+            }) => Some(uncles),
+            _ => None,
+        }
+    });
+
+    for uncle in included_uncles.flatten() {
+        let Some(DigestItem::Seal(POW_ENGINE_ID, seal)) = uncle.digest().logs().last() else {
+            continue;
+        };
+        let Ok(seal) = Seal::decode(&mut &seal[..]) else {
+            continue;
+        };
+
+        let difficulty = seal.difficulty.for_algo(seal.work.algo);
+        let mut uncle_work = Threshold::default();
+        match seal.work.algo {
+            SupportedHashes::Md5 => uncle_work.md5 = difficulty,
+            SupportedHashes::Sha3 => uncle_work.sha3 = difficulty,
+            SupportedHashes::Keccak => uncle_work.keccak = difficulty,
+        }
+        work.increment(uncle_work);
+    }
+
+    work
+}
+
+/// A block import that wraps the PoW block import and counts the work of included uncles
+/// towards fork choice.
+///
+/// Normally the chain with the most total difficulty wins. With this wrapper, the difficulty of
+/// every uncle included in a chain is added to that chain's total, similar to the GHOST protocol.
+/// This rewards chains that acknowledge the work of miners who lost a race.
+pub struct UncleWorkBlockImport<I, C, S> {
+    inner: I,
+    client: Arc<C>,
+    select_chain: S,
+    algorithm: MultiPow<C>,
+}
+
+impl<I, C, S> UncleWorkBlockImport<I, C, S> {
+    pub fn new(inner: I, client: Arc<C>, select_chain: S, algorithm: MultiPow<C>) -> Self {
+        Self {
+            inner,
+            client,
+            select_chain,
+            algorithm,
+        }
+    }
+}
+
+impl<I: Clone, C, S: Clone> Clone for UncleWorkBlockImport<I, C, S> {
+    fn clone(&self) -> Self {
+        Self::new(
+            self.inner.clone(),
+            self.client.clone(),
+            self.select_chain.clone(),
+            self.algorithm.clone(),
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl<I, C, S> BlockImport<Block> for UncleWorkBlockImport<I, C, S>
+where
+    I: BlockImport<Block, Error = ConsensusError> + Send + Sync,
+    C: ProvideRuntimeApi<Block> + HeaderBackend<Block> + AuxStore + Send + Sync,
+    C::Api: DifficultyApi<Block, Threshold>,
+    S: SelectChain<Block>,
+{
+    type Error = ConsensusError;
+
+    async fn check_block(
+        &mut self,
+        block: BlockCheckParams<Block>,
+    ) -> Result<ImportResult, Self::Error> {
+        self.inner.check_block(block).await
+    }
+
+    async fn import_block(
+        &mut self,
+        mut block: BlockImportParams<Block>,
+    ) -> Result<ImportResult, Self::Error> {
+        let parent_hash = *block.header.parent_hash();
+
+        let mut uncle_work = read_uncle_work(self.client.as_ref(), &parent_hash)?;
+        if let Some(body) = &block.body {
+            uncle_work.increment(included_uncle_work(body));
+        }
+        block
+            .auxiliary
+            .push((aux_key(&block.post_hash()), Some(uncle_work.encode())));
+
+        // The inner PoW import only decides the fork choice if we haven't already.
+        if block.fork_choice.is_none() {
+            let difficulty =
+                match block.get_intermediate::<PowIntermediate<Threshold>>(INTERMEDIATE_KEY) {
+                    Ok(PowIntermediate {
+                        difficulty: Some(difficulty),
+                    }) => *difficulty,
+                    _ => PowAlgorithm::<Block>::difficulty(&self.algorithm, parent_hash)?,
+                };
+
+            let mut total =
+                PowAux::<Threshold>::read::<_, Block>(self.client.as_ref(), &parent_hash)?
+                    .total_difficulty;
+            total.increment(difficulty);
+            total.increment(uncle_work);
+
+            let best_hash = self.select_chain.best_chain().await?.hash();
+            let mut best_total =
+                PowAux::<Threshold>::read::<_, Block>(self.client.as_ref(), &best_hash)?
+                    .total_difficulty;
+            best_total.increment(read_uncle_work(self.client.as_ref(), &best_hash)?);
+
+            block.fork_choice = Some(ForkChoiceStrategy::Custom(total > best_total));
+        }
+
+        self.inner.import_block(block).await
+    }
+}
@@ -3,6 +3,7 @@
 //! The included trait allows other pallets to fetch the author's account as long as the
 //! runtime's AccountId type can be created from an sr25519 public key.
 
+use frame_support::traits::FindAuthor;
 pub use pallet::*;
 use parity_scale_codec::{Decode, Encode};
 use sp_core::sr25519;
@@ -22,9 +23,13 @@ pub mod pallet {
     /// The BlockAuthor Inherent pallet.
     #[pallet::pallet]
     pub struct Pallet<T>(PhantomData<T>);
-    /// The pallet's configuration trait. Nothing to configure.
+    /// The pallet's configuration trait.
     #[pallet::config]
     pub trait Config: frame_system::Config {
+        /// Find the author that the consensus engine claims mined this block.
+        /// The author in the inherent must agree with it.
+        type FindAuthor: FindAuthor<Self::AccountId>;
+
         fn on_author_set(_author_account: Self::AccountId) {}
     }
 
@@ -32,6 +37,10 @@ pub mod pallet {
     pub enum Error<T> {
         /// Author already set in block.
         AuthorAlreadySet,
+        /// The block has no consensus digest naming its author.
+        AuthorNotInDigest,
+        /// The author in the inherent is not the author in the consensus digest.
+        AuthorMismatch,
     }
 
     /// Author of current block.
@@ -44,11 +53,21 @@ pub mod pallet {
         <T as frame_system::Config>::AccountId: From<sp_core::sr25519::Public>,
     {
         /// Inherent to set the author of a block
-        #[pallet::weight(1_000_000)]
+        ///
+        /// The author must be the same one that the consensus digest names, so that the
+        /// account credited with the work is the one that actually receives the reward.
+        #[pallet::weight((1_000_000, DispatchClass::Mandatory))]
         pub fn set_author(origin: OriginFor<T>, author: sr25519::Public) -> DispatchResult {
             ensure_none(origin)?;
             ensure!(Author::<T>::get().is_none(), Error::<T>::AuthorAlreadySet);
 
+            let digest = frame_system::Pallet::<T>::digest();
+            let pre_runtime_digests = digest.logs.iter().filter_map(|d| d.as_pre_runtime());
+            let digest_author = T::FindAuthor::find_author(pre_runtime_digests)
+                .ok_or(Error::<T>::AuthorNotInDigest)?;
+            let author_account: T::AccountId = author.into();
+            ensure!(digest_author == author_account, Error::<T>::AuthorMismatch);
+
             // Store the author in case other pallets want to fetch it and to let
             // offchain tools inspect it
             Author::<T>::put(author);
@@ -175,7 +194,7 @@ impl sp_inherents::InherentDataProvider for InherentDataProvider {
     async fn try_handle_error(
         &self,
         identifier: &InherentIdentifier,
-        _error: &[u8],
+        error: &[u8],
     ) -> Option<Result<(), sp_inherents::Error>> {
         // Dont' process modules from other inherents
         if *identifier != INHERENT_IDENTIFIER {
@@ -183,8 +202,12 @@ impl sp_inherents::InherentDataProvider for InherentDataProvider {
         }
 
         // All errors with the author inehrent are fatal
-        Some(Err(sp_inherents::Error::Application(Box::from(
-            String::from("Error processing author inherent"),
-        ))))
+        let message = match InherentError::try_from(identifier, error) {
+            Some(InherentError::Other(reason)) => {
+                format!("Error processing author inherent: {:?}", reason)
+            }
+            None => String::from("Error processing author inherent"),
+        };
+        Some(Err(sp_inherents::Error::Application(Box::from(message))))
     }
 }
@@ -8,10 +8,12 @@
 use core::cmp::{max, min};
 
 use frame_support::traits::Time;
-use parity_scale_codec::{Decode, Encode, MaxEncodedLen};
+use multi_pow::SupportedHashes;
+use parity_scale_codec::{Codec, Decode, Encode, MaxEncodedLen};
 use scale_info::TypeInfo;
 use sp_core::U256;
-use sp_runtime::traits::UniqueSaturatedInto;
+use sp_runtime::traits::{AtLeast32Bit, Saturating, UniqueSaturatedInto};
+use sp_std::vec::Vec;
 
 #[derive(Encode, Decode, Clone, Copy, Eq, PartialEq, Debug, MaxEncodedLen, TypeInfo)]
 pub struct DifficultyAndTimestamp<M> {
@@ -19,6 +21,218 @@ pub struct DifficultyAndTimestamp<M> {
     pub timestamp: M,
 }
 
+/// The tunable parameters of the difficulty adjustment. Each instance stores its own copy, which
+/// Root may change at any time.
+#[derive(Encode, Decode, Clone, Copy, Eq, PartialEq, Debug, MaxEncodedLen, TypeInfo)]
+pub struct DifficultyAdjustmentParams {
+    /// The block time, in milliseconds, that the DAA will attempt to maintain
+    pub target_block_time: u128,
+    /// Dampening factor to use for difficulty adjustment
+    pub damp_factor: u128,
+    /// Clamp factor to use for difficulty adjustment
+    /// Limit value to within this factor of goal. Recommended value: 2
+    pub clamp_factor: u128,
+    /// The maximum difficulty allowed. Recommended to use u128::max_value()
+    pub max_difficulty: Difficulty,
+    /// Minimum difficulty, enforced in difficulty retargetting
+    /// avoids getting stuck when trying to increase difficulty subject to dampening
+    /// Must be at least the damp factor
+    pub min_difficulty: Difficulty,
+    /// How long, in milliseconds, the algorithm may go without a block before its difficulty
+    /// starts to decay
+    pub idle_decay_after: u128,
+    /// How quickly an idle algorithm's difficulty decays, in milliseconds. After being idle for
+    /// this much longer than `idle_decay_after` the difficulty has halved, after twice as long
+    /// it is down to a third, and so on, never below the minimum difficulty. Zero disables
+    /// the decay.
+    pub idle_decay_time: u128,
+}
+
+impl Default for DifficultyAdjustmentParams {
+    fn default() -> Self {
+        DifficultyAdjustmentParams {
+            target_block_time: 5_000,
+            damp_factor: 3,
+            clamp_factor: 2,
+            max_difficulty: u128::max_value().into(),
+            // Setting min difficulty to damp factor per recommendation
+            min_difficulty: 3.into(),
+            // Start relaxing an algorithm after about an hour without blocks,
+            idle_decay_after: 60 * 60 * 1_000,
+            // and halve its difficulty over the following hour.
+            idle_decay_time: 60 * 60 * 1_000,
+        }
+    }
+}
+
+/// The shortest target block time that may be set, in milliseconds.
+pub const MIN_TARGET_BLOCK_TIME: u128 = 100;
+/// The longest target block time that may be set, in milliseconds. One day.
+pub const MAX_TARGET_BLOCK_TIME: u128 = 86_400_000;
+/// The largest damp or clamp factor that may be set.
+///
+/// Together with the block time bound this keeps the DAA's arithmetic far from overflowing.
+pub const MAX_FACTOR: u128 = 1_000;
+/// The longest idle decay delay or decay time that may be set, in milliseconds. Thirty days.
+pub const MAX_IDLE_DECAY: u128 = 30 * 86_400_000;
+
+sp_api::decl_runtime_apis! {
+    /// An API to inspect the difficulty adjustment of each hashing algorithm.
+    pub trait DifficultyAdjustmentApi {
+        /// The parameters the given algorithm's difficulty is currently adjusted with.
+        fn params(algo: SupportedHashes) -> DifficultyAdjustmentParams;
+    }
+}
+
+sp_api::decl_runtime_apis! {
+    /// An API to inspect the recent difficulties of each hashing algorithm.
+    pub trait DifficultyHistoryApi<Moment: Codec> {
+        /// The given algorithm's difficulty adjustment window and what the DAA makes of it.
+        fn difficulty_history(algo: SupportedHashes) -> DifficultyHistory<Moment>;
+    }
+}
+
+/// The difficulty adjustment window of one algorithm, and what the DAA makes of it.
+#[derive(Encode, Decode, Clone, Eq, PartialEq, Debug, TypeInfo)]
+pub struct DifficultyHistory<M> {
+    /// The difficulties and timestamps of the last blocks mined with this algorithm, from
+    /// earliest to latest. Shorter than the full window until enough blocks have been mined.
+    pub window: Vec<DifficultyAndTimestamp<M>>,
+    /// The difficulty assumed for blocks before the window filled up.
+    pub initial_difficulty: Difficulty,
+    /// The difficulty the next block mined with this algorithm must meet.
+    pub current_difficulty: Difficulty,
+    /// The average block time over the window, in milliseconds, as the DAA sees it.
+    pub average_block_time: u128,
+    /// The difficulty the DAA would set if the next block were mined right on target.
+    pub next_difficulty: Difficulty,
+}
+
+impl<M: AtLeast32Bit + Copy> DifficultyHistory<M> {
+    /// Describe a difficulty adjustment window whose next block must meet `current_difficulty`.
+    pub fn new(
+        data: &[Option<DifficultyAndTimestamp<M>>],
+        initial_difficulty: Difficulty,
+        current_difficulty: Difficulty,
+        params: &DifficultyAdjustmentParams,
+    ) -> Self {
+        let current = retarget(data, initial_difficulty, params);
+
+        // Pretend the next block arrives right on target to see where the difficulty goes.
+        let mut next = data.to_vec();
+        next.rotate_left(1);
+        next[data.len() - 1] = data[data.len() - 1].map(|last| DifficultyAndTimestamp {
+            difficulty: current_difficulty,
+            timestamp: last
+                .timestamp
+                .saturating_add(params.target_block_time.unique_saturated_into()),
+        });
+
+        DifficultyHistory {
+            window: data.iter().flatten().copied().collect(),
+            initial_difficulty,
+            current_difficulty,
+            average_block_time: current.average_block_time,
+            next_difficulty: retarget(&next, initial_difficulty, params).difficulty,
+        }
+    }
+}
+
+/// The outcome of running the DAA over a window.
+pub struct Retarget {
+    /// The average block time over the window, in milliseconds.
+    pub average_block_time: u128,
+    /// The new difficulty.
+    pub difficulty: Difficulty,
+}
+
+/// Compute the next difficulty from the past difficulties and timestamps, from earliest to
+/// latest. Empty slots, from before the window filled up, count as blocks mined right on
+/// target at the initial difficulty.
+pub fn retarget<M: UniqueSaturatedInto<u128> + Copy>(
+    data: &[Option<DifficultyAndTimestamp<M>>],
+    initial_difficulty: Difficulty,
+    params: &DifficultyAdjustmentParams,
+) -> Retarget {
+    let mut ts_delta = 0;
+    for i in 1..(DIFFICULTY_ADJUST_WINDOW as usize) {
+        let prev: Option<u128> = data[i - 1].map(|d| d.timestamp.unique_saturated_into());
+        let cur: Option<u128> = data[i].map(|d| d.timestamp.unique_saturated_into());
+
+        let delta = match (prev, cur) {
+            (Some(prev), Some(cur)) => cur.saturating_sub(prev),
+            _ => params.target_block_time,
+        };
+        ts_delta += delta;
+    }
+
+    if ts_delta == 0 {
+        ts_delta = 1;
+    }
+
+    let mut diff_sum = U256::zero();
+    //TODO Could we just initialize every array cell to the initial difficulty to not need the
+    // separate storage item?
+    for item in data.iter().take(DIFFICULTY_ADJUST_WINDOW as usize) {
+        let diff = match item.map(|d| d.difficulty) {
+            Some(diff) => diff,
+            None => initial_difficulty,
+        };
+        diff_sum += diff;
+    }
+
+    if diff_sum < params.min_difficulty {
+        diff_sum = params.min_difficulty;
+    }
+
+    // Calculate the average length of the adjustment window
+    let adjustment_window = DIFFICULTY_ADJUST_WINDOW * params.target_block_time;
+
+    // adjust time delta toward goal subject to dampening and clamping
+    let adj_ts = clamp(
+        damp(ts_delta, adjustment_window, params.damp_factor),
+        adjustment_window,
+        params.clamp_factor,
+    );
+
+    // minimum difficulty avoids getting stuck due to dampening
+    let difficulty = min(
+        params.max_difficulty,
+        max(
+            params.min_difficulty,
+            diff_sum * U256::from(params.target_block_time) / U256::from(adj_ts),
+        ),
+    );
+
+    Retarget {
+        average_block_time: ts_delta / (DIFFICULTY_ADJUST_WINDOW - 1),
+        difficulty,
+    }
+}
+
+/// Relax the difficulty of an algorithm that has gone `idle` milliseconds without a block.
+///
+/// The difficulty follows `difficulty * decay_time / (decay_time + overdue)` where `overdue` is
+/// how far past `idle_decay_after` the algorithm has been idle, and never drops below the
+/// minimum difficulty. It depends on the wall-clock time alone, so it doesn't matter how many
+/// blocks of other algorithms were mined in between.
+pub fn decay(
+    difficulty: Difficulty,
+    idle: u128,
+    params: &DifficultyAdjustmentParams,
+) -> Difficulty {
+    if params.idle_decay_time == 0 {
+        return difficulty
+    }
+    let Some(overdue) = idle.checked_sub(params.idle_decay_after) else {
+        return difficulty
+    };
+
+    let decayed = difficulty.saturating_mul(U256::from(params.idle_decay_time))
+        / U256::from(params.idle_decay_time.saturating_add(overdue));
+    min(difficulty, max(params.min_difficulty, decayed))
+}
+
 /// Move value linearly toward a goal
 pub fn damp(actual: u128, goal: u128, damp_factor: u128) -> u128 {
     (actual + (damp_factor - 1) * goal) / damp_factor
@@ -29,7 +243,8 @@ pub fn clamp(actual: u128, goal: u128, clamp_factor: u128) -> u128 {
     max(goal / clamp_factor, min(actual, goal * clamp_factor))
 }
 
-const DIFFICULTY_ADJUST_WINDOW: u128 = 60;
+/// How many blocks the DAA looks back over.
+pub const DIFFICULTY_ADJUST_WINDOW: u128 = 60;
 type Difficulty = U256;
 
 pub use pallet::*;
@@ -44,21 +259,11 @@ pub mod pallet {
     /// Pallet's configuration trait.
     #[pallet::config]
     pub trait Config<I: 'static = ()>: frame_system::Config {
+        /// The overarching event type.
+        type RuntimeEvent: From<Event<Self, I>>
+            + IsType<<Self as frame_system::Config>::RuntimeEvent>;
         /// A Source for timestamp data
         type TimeProvider: Time;
-        /// The block time that the DAA will attempt to maintain
-        type TargetBlockTime: Get<u128>;
-        /// Dampening factor to use for difficulty adjustment
-        type DampFactor: Get<u128>;
-        /// Clamp factor to use for difficulty adjustment
-        /// Limit value to within this factor of goal. Recommended value: 2
-        type ClampFactor: Get<u128>;
-        /// The maximum difficulty allowed. Recommended to use u128::max_value()
-        type MaxDifficulty: Get<u128>;
-        /// Minimum difficulty, enforced in difficulty retargetting
-        /// avoids getting stuck when trying to increase difficulty subject to dampening
-        /// Recommended to use same value as DampFactor
-        type MinDifficulty: Get<u128>;
 
         /// Now that the pallet is instantiable, we need a way to decide which blocks are
         /// relevant to this instance. This function does just that.
@@ -88,19 +293,63 @@ pub mod pallet {
         }
     }
 
-    /// Current difficulty.
+    /// The difficulty the DAA set when this instance last saw a block, before any idle decay.
+    /// Read it through `difficulty()`.
     #[pallet::storage]
-    #[pallet::getter(fn difficulty)]
     pub type CurrentDifficulty<T: Config<I>, I: 'static = ()> = StorageValue<_, Difficulty, ValueQuery>;
 
+    /// When this instance last saw a block mined with its algorithm, or when it started watching
+    /// if it has never seen one.
+    #[pallet::storage]
+    pub type LastMined<T: Config<I>, I: 'static = ()> =
+        StorageValue<_, <<T as Config<I>>::TimeProvider as Time>::Moment, OptionQuery>;
+
     /// Initial difficulty.
     #[pallet::storage]
     pub type InitialDifficulty<T: Config<I>, I: 'static = ()> = StorageValue<_, Difficulty, ValueQuery>;
 
+    /// The parameters the difficulty is adjusted with.
+    #[pallet::storage]
+    #[pallet::getter(fn params)]
+    pub type Params<T: Config<I>, I: 'static = ()> =
+        StorageValue<_, DifficultyAdjustmentParams, ValueQuery>;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config<I>, I: 'static = ()> {
+        /// Root changed the difficulty adjustment parameters.
+        ParamsSet { params: DifficultyAdjustmentParams },
+    }
+
+    #[pallet::error]
+    pub enum Error<T, I = ()> {
+        /// The target block time is outside of the allowed range.
+        TargetBlockTimeOutOfRange,
+        /// The damp factor must be at least one and at most `MAX_FACTOR`.
+        DampFactorOutOfRange,
+        /// The clamp factor must be at least one and at most `MAX_FACTOR`.
+        ClampFactorOutOfRange,
+        /// The minimum difficulty must be at least the damp factor, or the difficulty can get
+        /// stuck.
+        MinDifficultyTooLow,
+        /// The minimum difficulty is above the maximum difficulty.
+        MinDifficultyAboveMax,
+        /// The idle decay delay and time must each be between the target block time and
+        /// `MAX_IDLE_DECAY`, unless the decay is disabled.
+        IdleDecayOutOfRange,
+    }
+
     #[pallet::genesis_config]
     pub struct GenesisConfig<T: Config<I>, I: 'static = ()> {
         pub _ph_data: PhantomData<(T, I)>,
         pub initial_difficulty: Difficulty,
+        pub target_block_time: u128,
+        pub damp_factor: u128,
+        pub clamp_factor: u128,
+        pub max_difficulty: Difficulty,
+        pub min_difficulty: Difficulty,
+        pub idle_decay_after: u128,
+        pub idle_decay_time: u128,
     }
 
     #[pallet::genesis_build]
@@ -112,26 +361,137 @@ pub mod pallet {
             // Store the initial difficulty in storage because we will need it
             // during the first DIFFICULTY_ADJUSTMENT_WINDOW blocks (see todo below).
             InitialDifficulty::<T, I>::put(self.initial_difficulty);
+
+            let params = DifficultyAdjustmentParams {
+                target_block_time: self.target_block_time,
+                damp_factor: self.damp_factor,
+                clamp_factor: self.clamp_factor,
+                max_difficulty: self.max_difficulty,
+                min_difficulty: self.min_difficulty,
+                idle_decay_after: self.idle_decay_after,
+                idle_decay_time: self.idle_decay_time,
+            };
+            Pallet::<T, I>::ensure_params_valid(&params)
+                .expect("The genesis difficulty adjustment params are valid");
+            Params::<T, I>::put(params);
         }
     }
 
     impl<T: Config<I>, I: 'static> Default for GenesisConfig<T, I> {
         fn default() -> Self {
+            let params = DifficultyAdjustmentParams::default();
             GenesisConfig {
                 _ph_data: Default::default(),
                 initial_difficulty: 4_000_000.into(),
+                target_block_time: params.target_block_time,
+                damp_factor: params.damp_factor,
+                clamp_factor: params.clamp_factor,
+                max_difficulty: params.max_difficulty,
+                min_difficulty: params.min_difficulty,
+                idle_decay_after: params.idle_decay_after,
+                idle_decay_time: params.idle_decay_time,
             }
         }
     }
 
+    #[pallet::call]
+    impl<T: Config<I>, I: 'static> Pallet<T, I> {
+        /// Change the parameters the difficulty is adjusted with. They take effect from the
+        /// next block that is relevant to this instance.
+        #[pallet::call_index(0)]
+        pub fn set_params(
+            origin: OriginFor<T>,
+            params: DifficultyAdjustmentParams,
+        ) -> DispatchResult {
+            ensure_root(origin)?;
+            Self::ensure_params_valid(&params)?;
+
+            Params::<T, I>::put(params);
+            Self::deposit_event(Event::ParamsSet { params });
+
+            Ok(())
+        }
+    }
+
+    impl<T: Config<I>, I: 'static> Pallet<T, I> {
+        /// Check that the parameters are within sane bounds.
+        pub fn ensure_params_valid(params: &DifficultyAdjustmentParams) -> Result<(), Error<T, I>> {
+            ensure!(
+                (MIN_TARGET_BLOCK_TIME..=MAX_TARGET_BLOCK_TIME).contains(&params.target_block_time),
+                Error::<T, I>::TargetBlockTimeOutOfRange
+            );
+            ensure!(
+                (1..=MAX_FACTOR).contains(&params.damp_factor),
+                Error::<T, I>::DampFactorOutOfRange
+            );
+            ensure!(
+                (1..=MAX_FACTOR).contains(&params.clamp_factor),
+                Error::<T, I>::ClampFactorOutOfRange
+            );
+            ensure!(
+                params.min_difficulty >= params.damp_factor.into(),
+                Error::<T, I>::MinDifficultyTooLow
+            );
+            ensure!(
+                params.min_difficulty <= params.max_difficulty,
+                Error::<T, I>::MinDifficultyAboveMax
+            );
+            if params.idle_decay_time != 0 {
+                let allowed = params.target_block_time..=MAX_IDLE_DECAY;
+                ensure!(
+                    allowed.contains(&params.idle_decay_after)
+                        && allowed.contains(&params.idle_decay_time),
+                    Error::<T, I>::IdleDecayOutOfRange
+                );
+            }
+
+            Ok(())
+        }
+
+        /// The difficulty adjustment window and what the DAA makes of it.
+        pub fn history() -> DifficultyHistory<<<T as Config<I>>::TimeProvider as Time>::Moment> {
+            DifficultyHistory::new(
+                &PastDifficultiesAndTimestamps::<T, I>::get(),
+                InitialDifficulty::<T, I>::get(),
+                Self::difficulty(),
+                &Self::params(),
+            )
+        }
+
+        /// The difficulty the next block mined with this algorithm must meet.
+        ///
+        /// If the algorithm hasn't been mined for a while, this is relaxed according to how
+        /// long ago `LastMined` was, so the decay applies whether or not any blocks of other
+        /// algorithms were mined in the meantime.
+        pub fn difficulty() -> Difficulty {
+            let difficulty = CurrentDifficulty::<T, I>::get();
+            let Some(last_mined) = LastMined::<T, I>::get() else {
+                return difficulty
+            };
+
+            let now: u128 = T::TimeProvider::now().unique_saturated_into();
+            let idle = now.saturating_sub(last_mined.unique_saturated_into());
+            decay(difficulty, idle, &Self::params())
+        }
+    }
+
     #[pallet::hooks]
     impl<T: Config<I>, I: 'static> Hooks<BlockNumberFor<T>> for Pallet<T, I> {
         fn on_finalize(_n: BlockNumberFor<T>) {
             // First check if this is block is relevant to this instance of the difficulty adjustment algorithm
             if !T::relevant_to_this_instance() {
+                // Start the idle clock the first time we see this instance, eg. right after a
+                // fork enabled the algorithm.
+                if LastMined::<T, I>::get().is_none() {
+                    LastMined::<T, I>::put(T::TimeProvider::now());
+                }
                 return
             }
 
+            // The block met the difficulty as it was, decay included, so that goes in the window.
+            let mined_at = Self::difficulty();
+            LastMined::<T, I>::put(T::TimeProvider::now());
+
             let mut data = PastDifficultiesAndTimestamps::<T, I>::get();
 
             for i in 1..data.len() {
@@ -140,61 +500,17 @@ pub mod pallet {
 
             data[data.len() - 1] = Some(DifficultyAndTimestamp {
                 timestamp: T::TimeProvider::now(),
-                difficulty: Self::difficulty(),
+                difficulty: mined_at,
             });
 
-            let mut ts_delta = 0;
-            for i in 1..(DIFFICULTY_ADJUST_WINDOW as usize) {
-                let prev: Option<u128> = data[i - 1].map(|d| d.timestamp.unique_saturated_into());
-                let cur: Option<u128> = data[i].map(|d| d.timestamp.unique_saturated_into());
-
-                let delta = match (prev, cur) {
-                    (Some(prev), Some(cur)) => cur.saturating_sub(prev),
-                    _ => T::TargetBlockTime::get(),
-                };
-                ts_delta += delta;
-            }
-
-            if ts_delta == 0 {
-                ts_delta = 1;
-            }
-
-            let mut diff_sum = U256::zero();
-            //TODO Could we just initialize every array cell to the initial difficulty to not need the
-            // separate storage item?
-            for item in data.iter().take(DIFFICULTY_ADJUST_WINDOW as usize) {
-                let diff = match item.map(|d| d.difficulty) {
-                    Some(diff) => diff,
-                    None => InitialDifficulty::<T, I>::get(),
-                };
-                diff_sum += diff;
-            }
-
-            if diff_sum < U256::from(T::MinDifficulty::get()) {
-                diff_sum = U256::from(T::MinDifficulty::get());
-            }
-
-            // Calculate the average length of the adjustment window
-            let adjustment_window = DIFFICULTY_ADJUST_WINDOW * T::TargetBlockTime::get();
-
-            // adjust time delta toward goal subject to dampening and clamping
-            let adj_ts = clamp(
-                damp(ts_delta, adjustment_window, T::DampFactor::get()),
-                adjustment_window,
-                T::ClampFactor::get(),
-            );
-
-            // minimum difficulty avoids getting stuck due to dampening
-            let difficulty = min(
-                U256::from(T::MaxDifficulty::get()),
-                max(
-                    U256::from(T::MinDifficulty::get()),
-                    diff_sum * U256::from(T::TargetBlockTime::get()) / U256::from(adj_ts),
-                ),
-            );
+            let params = Self::params();
+            let difficulty = retarget(&data, InitialDifficulty::<T, I>::get(), &params).difficulty;
 
             <PastDifficultiesAndTimestamps<T, I>>::put(data);
             <CurrentDifficulty<T, I>>::put(difficulty);
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
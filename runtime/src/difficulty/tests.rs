@@ -0,0 +1,310 @@
+use frame_support::{assert_noop, assert_ok, derive_impl, parameter_types, traits::Hooks};
+use sp_runtime::{BuildStorage, DispatchError};
+
+use super::*;
+use crate::difficulty as pallet_difficulty;
+
+type Block = frame_system::mocking::MockBlock<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        DifficultyAdjustment: pallet_difficulty,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig as frame_system::DefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+}
+
+parameter_types! {
+    /// The mock wall clock, in milliseconds.
+    pub static Now: u64 = 0;
+    /// Whether the block being finalized was mined with this instance's algorithm.
+    pub static Relevant: bool = true;
+}
+
+/// Reads the mock wall clock.
+pub struct MockTime;
+
+impl Time for MockTime {
+    type Moment = u64;
+
+    fn now() -> u64 {
+        Now::get()
+    }
+}
+
+impl Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type TimeProvider = MockTime;
+
+    fn relevant_to_this_instance() -> bool {
+        Relevant::get()
+    }
+}
+
+const INITIAL_DIFFICULTY: u64 = 1_000_000;
+const TARGET: u64 = 5_000;
+const HOUR: u64 = 60 * 60 * 1_000;
+
+fn new_test_ext_with(params: DifficultyAdjustmentParams) -> sp_io::TestExternalities {
+    let storage = RuntimeGenesisConfig {
+        system: Default::default(),
+        difficulty_adjustment: GenesisConfig {
+            initial_difficulty: INITIAL_DIFFICULTY.into(),
+            target_block_time: params.target_block_time,
+            damp_factor: params.damp_factor,
+            clamp_factor: params.clamp_factor,
+            max_difficulty: params.max_difficulty,
+            min_difficulty: params.min_difficulty,
+            idle_decay_after: params.idle_decay_after,
+            idle_decay_time: params.idle_decay_time,
+            ..Default::default()
+        },
+    }
+    .build_storage()
+    .expect("The mock genesis builds");
+    let mut ext = sp_io::TestExternalities::new(storage);
+    ext.execute_with(|| Now::set(&0));
+    ext
+}
+
+fn new_test_ext() -> sp_io::TestExternalities {
+    new_test_ext_with(DifficultyAdjustmentParams::default())
+}
+
+/// Finalize a block `elapsed` milliseconds after the previous one, mined with this instance's
+/// algorithm if `relevant`.
+fn finalize(elapsed: u64, relevant: bool) {
+    Now::mutate(|now| *now += elapsed);
+    Relevant::set(&relevant);
+    DifficultyAdjustment::on_finalize(System::block_number());
+}
+
+#[test]
+fn genesis_sets_the_params() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(
+            DifficultyAdjustment::params(),
+            DifficultyAdjustmentParams::default()
+        );
+        assert_eq!(
+            DifficultyAdjustment::difficulty(),
+            INITIAL_DIFFICULTY.into()
+        );
+    });
+}
+
+#[test]
+#[should_panic(expected = "The genesis difficulty adjustment params are valid")]
+fn genesis_rejects_invalid_params() {
+    new_test_ext_with(DifficultyAdjustmentParams {
+        damp_factor: 0,
+        ..Default::default()
+    });
+}
+
+#[test]
+fn params_are_checked() {
+    let check = |params: &DifficultyAdjustmentParams| {
+        Pallet::<Test>::ensure_params_valid(params).map_err(DispatchError::from)
+    };
+    let defaults = DifficultyAdjustmentParams::default();
+
+    assert_ok!(check(&defaults));
+    assert_eq!(
+        check(&DifficultyAdjustmentParams {
+            target_block_time: MIN_TARGET_BLOCK_TIME - 1,
+            ..defaults
+        }),
+        Err(Error::<Test>::TargetBlockTimeOutOfRange.into())
+    );
+    assert_eq!(
+        check(&DifficultyAdjustmentParams {
+            clamp_factor: MAX_FACTOR + 1,
+            ..defaults
+        }),
+        Err(Error::<Test>::ClampFactorOutOfRange.into())
+    );
+    assert_eq!(
+        check(&DifficultyAdjustmentParams {
+            min_difficulty: 2.into(),
+            ..defaults
+        }),
+        Err(Error::<Test>::MinDifficultyTooLow.into())
+    );
+    assert_eq!(
+        check(&DifficultyAdjustmentParams {
+            idle_decay_after: MAX_IDLE_DECAY + 1,
+            ..defaults
+        }),
+        Err(Error::<Test>::IdleDecayOutOfRange.into())
+    );
+    // The decay delay doesn't matter when the decay is off.
+    assert_ok!(check(&DifficultyAdjustmentParams {
+        idle_decay_after: MAX_IDLE_DECAY + 1,
+        idle_decay_time: 0,
+        ..defaults
+    }));
+}
+
+#[test]
+fn root_sets_the_params() {
+    new_test_ext().execute_with(|| {
+        // Events aren't deposited in the genesis block.
+        System::set_block_number(1);
+        let params = DifficultyAdjustmentParams {
+            target_block_time: 10_000,
+            ..Default::default()
+        };
+
+        assert_ok!(DifficultyAdjustment::set_params(
+            RuntimeOrigin::root(),
+            params
+        ));
+        assert_eq!(DifficultyAdjustment::params(), params);
+        System::assert_last_event(Event::ParamsSet { params }.into());
+    });
+}
+
+#[test]
+fn only_root_sets_the_params() {
+    new_test_ext().execute_with(|| {
+        let params = DifficultyAdjustmentParams {
+            target_block_time: 10_000,
+            ..Default::default()
+        };
+
+        assert_noop!(
+            DifficultyAdjustment::set_params(RuntimeOrigin::signed(1), params),
+            DispatchError::BadOrigin
+        );
+    });
+}
+
+#[test]
+fn set_params_rejects_out_of_bounds_params() {
+    new_test_ext().execute_with(|| {
+        System::set_block_number(1);
+
+        assert_noop!(
+            DifficultyAdjustment::set_params(
+                RuntimeOrigin::root(),
+                DifficultyAdjustmentParams {
+                    target_block_time: MAX_TARGET_BLOCK_TIME + 1,
+                    ..Default::default()
+                }
+            ),
+            Error::<Test>::TargetBlockTimeOutOfRange
+        );
+        assert_noop!(
+            DifficultyAdjustment::set_params(
+                RuntimeOrigin::root(),
+                DifficultyAdjustmentParams {
+                    damp_factor: 0,
+                    ..Default::default()
+                }
+            ),
+            Error::<Test>::DampFactorOutOfRange
+        );
+        assert_eq!(
+            DifficultyAdjustment::params(),
+            DifficultyAdjustmentParams::default()
+        );
+        assert!(System::events().is_empty());
+    });
+}
+
+#[test]
+fn idle_algorithms_decay_without_blocks() {
+    new_test_ext().execute_with(|| {
+        finalize(TARGET, true);
+        let mined = DifficultyAdjustment::difficulty();
+
+        // Not idle for long enough yet.
+        Now::mutate(|now| *now += HOUR);
+        assert_eq!(DifficultyAdjustment::difficulty(), mined);
+
+        // No block is finalized, yet an hour past the decay delay the difficulty has halved.
+        Now::mutate(|now| *now += HOUR);
+        assert_eq!(DifficultyAdjustment::difficulty(), mined / 2);
+
+        // Blocks of other algorithms don't change that.
+        finalize(0, false);
+        assert_eq!(DifficultyAdjustment::difficulty(), mined / 2);
+        assert_eq!(CurrentDifficulty::<Test>::get(), mined);
+    });
+}
+
+#[test]
+fn mining_ends_the_decay_and_records_the_decayed_difficulty() {
+    new_test_ext().execute_with(|| {
+        finalize(TARGET, true);
+        let mined = DifficultyAdjustment::difficulty();
+
+        finalize(2 * HOUR, true);
+        let window = PastDifficultiesAndTimestamps::<Test>::get();
+        let last = window[window.len() - 1].expect("A block was just mined");
+        assert_eq!(last.difficulty, mined / 2);
+        assert_eq!(LastMined::<Test>::get(), Some(Now::get()));
+        assert_eq!(
+            DifficultyAdjustment::difficulty(),
+            CurrentDifficulty::<Test>::get()
+        );
+    });
+}
+
+#[test]
+fn decay_stops_at_the_minimum_difficulty() {
+    new_test_ext().execute_with(|| {
+        finalize(TARGET, true);
+        Now::mutate(|now| *now += 1_000_000 * HOUR);
+
+        assert_eq!(
+            DifficultyAdjustment::difficulty(),
+            DifficultyAdjustmentParams::default().min_difficulty
+        );
+    });
+}
+
+#[test]
+fn decay_can_be_disabled() {
+    let params = DifficultyAdjustmentParams {
+        idle_decay_time: 0,
+        ..Default::default()
+    };
+    new_test_ext_with(params).execute_with(|| {
+        finalize(TARGET, true);
+        let mined = DifficultyAdjustment::difficulty();
+        Now::mutate(|now| *now += 1_000 * HOUR);
+
+        assert_eq!(DifficultyAdjustment::difficulty(), mined);
+    });
+}
+
+#[test]
+fn the_idle_clock_starts_with_the_first_block() {
+    new_test_ext().execute_with(|| {
+        // An algorithm enabled by a fork long after genesis doesn't decay straight away.
+        Now::set(&(10 * HOUR));
+        assert_eq!(
+            DifficultyAdjustment::difficulty(),
+            INITIAL_DIFFICULTY.into()
+        );
+
+        finalize(TARGET, false);
+        assert_eq!(LastMined::<Test>::get(), Some(Now::get()));
+        assert_eq!(
+            DifficultyAdjustment::difficulty(),
+            INITIAL_DIFFICULTY.into()
+        );
+
+        Now::mutate(|now| *now += 2 * HOUR);
+        assert_eq!(
+            DifficultyAdjustment::difficulty(),
+            (INITIAL_DIFFICULTY / 2).into()
+        );
+    });
+}
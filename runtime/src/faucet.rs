@@ -1,23 +1,93 @@
-//! A simple token faucet that gives the caller 5 tokens per call
+//! A simple token faucet that gives the caller a few tokens per claim.
+//!
+//! The faucet doesn't mint tokens. It pays out of a pot, an account owned by this pallet.
+//! The pot is funded at genesis and topped up by donations and by a share of every block reward.
+//! An event is emitted when the pot runs low, and the `FaucetApi` runtime API reports how much
+//! is left.
+//!
+//! To keep anyone from printing unlimited money, claims are rate limited. Each account must wait
+//! a cooldown period between claims, only so many claims are paid out per block, and accounts
+//! that already hold enough tokens are turned away.
+//!
+//! Successful claims are feeless. Accounts that already exist can claim with a signed `claim`.
+//! Brand-new accounts have no balance to pay fees with, so they claim with an unsigned
+//! `claim_with_pow` instead. Rather than a fee, the claimant solves a small hashcash puzzle:
+//! they search for a nonce such that hashing `(account, recent_block_hash, nonce)` with one of
+//! the multi-pow algorithms meets the faucet's difficulty. The transaction pool checks the
+//! solution through `ValidateUnsigned`, so spamming the faucet costs CPU rather than tokens.
 
-use frame_support::traits::Currency;
+use frame_support::{
+    traits::{Currency, ExistenceRequirement},
+    PalletId,
+};
+use multi_pow::{simple_hash_meets_difficulty, SupportedHashes};
 pub use pallet::*;
+use parity_scale_codec::{Codec, Encode};
+use sp_core::{H256, U256};
+use sp_runtime::traits::AccountIdConversion;
+
+sp_api::decl_runtime_apis! {
+    /// An API to inspect the faucet.
+    pub trait FaucetApi<Balance: Codec> {
+        /// The funds left in the faucet's pot that it can still pay out.
+        fn remaining_funds() -> Balance;
+    }
+}
+
+/// Hash a faucet puzzle attempt.
+///
+/// Clients solving the puzzle should search for a `nonce` for which this hash meets the
+/// faucet's difficulty.
+pub fn claim_pow_hash<AccountId: Encode>(
+    dest: &AccountId,
+    recent_block_hash: &H256,
+    nonce: u64,
+    algo: SupportedHashes,
+) -> H256 {
+    algo.hash(&(dest, recent_block_hash, nonce).encode())
+}
 
 #[frame_support::pallet(dev_mode)]
 pub mod pallet {
     use frame_support::pallet_prelude::*;
     use frame_system::pallet_prelude::*;
+    use sp_runtime::{traits::Saturating, SaturatedConversion};
 
     use super::*;
 
     /// Pallet's configuration trait.
     #[pallet::config]
-    pub trait Config: frame_system::Config {
+    pub trait Config: frame_system::Config<Hash = H256> {
+        /// The overarching event type.
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+
         /// The currency type in which the faucet provides token
         type Currency: Currency<Self::AccountId>;
 
-        /// The amount of tokens that should be created for each call into the faucet
+        /// The faucet's id, used to derive the account that holds its pot
+        #[pallet::constant]
+        type PalletId: Get<PalletId>;
+
+        /// The amount of tokens the faucet pays out for each claim
         type DripAmount: Get<BalanceOf<Self>>;
+
+        /// When the pot drops below this many tokens, the faucet warns that it is running low
+        type LowFundsThreshold: Get<BalanceOf<Self>>;
+
+        /// How many blocks an account must wait between claims
+        type ClaimCooldown: Get<BlockNumberFor<Self>>;
+
+        /// The maximum number of claims paid out in a single block
+        type MaxDripsPerBlock: Get<u32>;
+
+        /// Accounts with a free balance above this may not claim
+        type MaxBalance: Get<BalanceOf<Self>>;
+
+        /// The difficulty of the puzzle that must be solved to claim without a signature
+        type ClaimPowDifficulty: Get<U256>;
+
+        /// How many blocks old the block a puzzle is built on may be
+        type ClaimPowWindow: Get<BlockNumberFor<Self>>;
     }
 
     type BalanceOf<T> =
@@ -26,21 +96,305 @@ pub mod pallet {
     #[pallet::pallet]
     pub struct Pallet<T>(_);
 
+    #[pallet::genesis_config]
+    #[derive(frame_support::DefaultNoBound)]
+    pub struct GenesisConfig<T: Config> {
+        /// The funds the pot starts out with
+        pub initial_funds: BalanceOf<T>,
+    }
+
+    #[pallet::genesis_build]
+    impl<T: Config> BuildGenesisConfig for GenesisConfig<T> {
+        fn build(&self) {
+            let _ = T::Currency::deposit_creating(&Pallet::<T>::account_id(), self.initial_funds);
+        }
+    }
+
+    /// The block in which each account last claimed from the faucet.
+    #[pallet::storage]
+    pub type LastClaim<T: Config> =
+        StorageMap<_, Blake2_128Concat, T::AccountId, BlockNumberFor<T>, OptionQuery>;
+
+    /// The number of claims paid out so far in the current block.
+    #[pallet::storage]
+    pub type DripsThisBlock<T: Config> = StorageValue<_, u32, ValueQuery>;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        /// The faucet paid out tokens.
+        Drip {
+            who: T::AccountId,
+            amount: BalanceOf<T>,
+        },
+        /// Someone donated tokens to the faucet.
+        Donation {
+            who: T::AccountId,
+            amount: BalanceOf<T>,
+        },
+        /// The pot dropped below the low funds threshold.
+        LowFunds { remaining: BalanceOf<T> },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        /// The account claimed too recently and must wait for the cooldown to pass.
+        ClaimTooSoon,
+        /// The faucet has paid out as many claims as it may in this block.
+        BlockCapReached,
+        /// The account already holds more than the faucet's maximum balance.
+        BalanceTooHigh,
+        /// The puzzle is not built on one of the last `ClaimPowWindow` blocks.
+        PuzzleBlockNotRecent,
+        /// The puzzle solution does not meet the faucet's difficulty.
+        InsufficientWork,
+        /// The pot doesn't hold enough to pay out a drip.
+        FaucetEmpty,
+    }
+
     #[pallet::call]
     impl<T: Config> Pallet<T> {
-        /// Claim a few tokens from the faucet
+        /// Claim a few tokens from the faucet. The fee is refunded if the claim succeeds.
         #[pallet::weight(1_000_000)]
-        pub fn claim(origin: OriginFor<T>) -> DispatchResult {
+        pub fn claim(origin: OriginFor<T>) -> DispatchResultWithPostInfo {
             let caller = ensure_signed(origin)?;
 
-            let _ = T::Currency::deposit_creating(&caller, T::DripAmount::get());
+            Self::ensure_can_claim(&caller)?;
+            Self::drip(&caller)?;
+
+            Ok(Pays::No.into())
+        }
+
+        /// Claim a few tokens from the faucet on behalf of an account that may not exist yet,
+        /// proving work instead of paying a fee.
+        ///
+        /// The puzzle is built on the hash of block `block_number`, which must be recent.
+        #[pallet::weight((1_000_000, Pays::No))]
+        pub fn claim_with_pow(
+            origin: OriginFor<T>,
+            dest: T::AccountId,
+            block_number: BlockNumberFor<T>,
+            algo: SupportedHashes,
+            nonce: u64,
+        ) -> DispatchResult {
+            ensure_none(origin)?;
+
+            Self::ensure_puzzle_solved(&dest, block_number, algo, nonce)?;
+            Self::ensure_can_claim(&dest)?;
+            Self::drip(&dest)?;
 
             Ok(())
         }
+
+        /// Donate tokens to the faucet's pot
+        #[pallet::weight(1_000_000)]
+        pub fn donate(origin: OriginFor<T>, amount: BalanceOf<T>) -> DispatchResult {
+            let donor = ensure_signed(origin)?;
+
+            T::Currency::transfer(
+                &donor,
+                &Self::account_id(),
+                amount,
+                ExistenceRequirement::AllowDeath,
+            )?;
+
+            Self::deposit_event(Event::Donation { who: donor, amount });
+
+            Ok(())
+        }
+    }
+
+    #[pallet::hooks]
+    impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+        fn on_initialize(_n: BlockNumberFor<T>) -> Weight {
+            // Every block starts with a fresh drip allowance
+            DripsThisBlock::<T>::kill();
+
+            Weight::zero()
+        }
     }
 
-    // #[pallet::hooks]
-    // impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
-    // 	fn on_finalize(_n: T::BlockNumber) {}
-    // }
+    #[pallet::validate_unsigned]
+    impl<T: Config> ValidateUnsigned for Pallet<T> {
+        type Call = Call<T>;
+
+        fn validate_unsigned(_source: TransactionSource, call: &Self::Call) -> TransactionValidity {
+            let Call::claim_with_pow {
+                dest,
+                block_number,
+                algo,
+                nonce,
+            } = call
+            else {
+                return InvalidTransaction::Call.into();
+            };
+
+            Self::ensure_puzzle_solved(dest, *block_number, *algo, *nonce)
+                .map_err(Self::invalid_transaction)?;
+            Self::ensure_account_may_claim(dest).map_err(Self::invalid_transaction)?;
+
+            // Only one pending claim per account, and it expires along with its puzzle.
+            let age = frame_system::Pallet::<T>::block_number().saturating_sub(*block_number);
+            let longevity = T::ClaimPowWindow::get()
+                .saturating_sub(age)
+                .saturated_into::<u64>()
+                .max(1);
+
+            ValidTransaction::with_tag_prefix("Faucet")
+                .priority(TransactionPriority::min_value())
+                .and_provides(dest)
+                .longevity(longevity)
+                .propagate(true)
+                .build()
+        }
+
+        fn pre_dispatch(call: &Self::Call) -> Result<(), TransactionValidityError> {
+            let Call::claim_with_pow {
+                dest,
+                block_number,
+                algo,
+                nonce,
+            } = call
+            else {
+                return Err(InvalidTransaction::Call.into());
+            };
+
+            // When building a block we also know how many claims it already contains.
+            Self::ensure_puzzle_solved(dest, *block_number, *algo, *nonce)
+                .map_err(Self::invalid_transaction)?;
+            Self::ensure_can_claim(dest).map_err(Self::invalid_transaction)?;
+
+            Ok(())
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        /// The account that holds the faucet's pot.
+        pub fn account_id() -> T::AccountId {
+            T::PalletId::get().into_account_truncating()
+        }
+
+        /// The funds left in the pot that the faucet can pay out.
+        /// The pot always keeps the existential deposit so that it is never reaped.
+        pub fn remaining_funds() -> BalanceOf<T> {
+            T::Currency::free_balance(&Self::account_id())
+                .saturating_sub(T::Currency::minimum_balance())
+        }
+
+        /// Add newly issued tokens, such as a share of the block reward, to the pot.
+        pub fn fund(amount: BalanceOf<T>) {
+            let _ = T::Currency::deposit_creating(&Self::account_id(), amount);
+        }
+
+        /// Check that the puzzle is built on a recent block and that the solution meets the
+        /// faucet's difficulty.
+        fn ensure_puzzle_solved(
+            dest: &T::AccountId,
+            block_number: BlockNumberFor<T>,
+            algo: SupportedHashes,
+            nonce: u64,
+        ) -> Result<(), Error<T>> {
+            let now = frame_system::Pallet::<T>::block_number();
+            ensure!(
+                block_number < now && now.saturating_sub(block_number) <= T::ClaimPowWindow::get(),
+                Error::<T>::PuzzleBlockNotRecent
+            );
+
+            // Block hashes are only kept for a while, after which they read as zero.
+            let recent_block_hash = frame_system::Pallet::<T>::block_hash(block_number);
+            ensure!(
+                recent_block_hash != H256::zero(),
+                Error::<T>::PuzzleBlockNotRecent
+            );
+
+            let hash = claim_pow_hash(dest, &recent_block_hash, nonce, algo);
+            ensure!(
+                simple_hash_meets_difficulty(&hash, T::ClaimPowDifficulty::get()),
+                Error::<T>::InsufficientWork
+            );
+
+            Ok(())
+        }
+
+        /// Check the limits that only depend on the claiming account.
+        fn ensure_account_may_claim(who: &T::AccountId) -> Result<(), Error<T>> {
+            if let Some(last_claim) = LastClaim::<T>::get(who) {
+                let now = frame_system::Pallet::<T>::block_number();
+                ensure!(
+                    now >= last_claim.saturating_add(T::ClaimCooldown::get()),
+                    Error::<T>::ClaimTooSoon
+                );
+            }
+
+            ensure!(
+                T::Currency::free_balance(who) <= T::MaxBalance::get(),
+                Error::<T>::BalanceTooHigh
+            );
+
+            Ok(())
+        }
+
+        /// Check every limit, including the per-block cap and the funds left in the pot.
+        fn ensure_can_claim(who: &T::AccountId) -> Result<(), Error<T>> {
+            Self::ensure_account_may_claim(who)?;
+
+            ensure!(
+                DripsThisBlock::<T>::get() < T::MaxDripsPerBlock::get(),
+                Error::<T>::BlockCapReached
+            );
+            ensure!(
+                Self::remaining_funds() >= T::DripAmount::get(),
+                Error::<T>::FaucetEmpty
+            );
+
+            Ok(())
+        }
+
+        /// Pay out a single drip from the pot and record it against the limits.
+        fn drip(who: &T::AccountId) -> DispatchResult {
+            let amount = T::DripAmount::get();
+            let remaining_before = Self::remaining_funds();
+
+            T::Currency::transfer(
+                &Self::account_id(),
+                who,
+                amount,
+                ExistenceRequirement::KeepAlive,
+            )?;
+
+            LastClaim::<T>::insert(who, frame_system::Pallet::<T>::block_number());
+            DripsThisBlock::<T>::mutate(|drips| *drips = drips.saturating_add(1));
+
+            Self::deposit_event(Event::Drip {
+                who: who.clone(),
+                amount,
+            });
+
+            // Only warn once, when the pot first drops below the threshold.
+            let remaining = Self::remaining_funds();
+            let threshold = T::LowFundsThreshold::get();
+            if remaining < threshold && remaining_before >= threshold {
+                Self::deposit_event(Event::LowFunds { remaining });
+            }
+
+            Ok(())
+        }
+
+        /// Map a claim error to the reason the transaction pool should give.
+        fn invalid_transaction(error: Error<T>) -> TransactionValidityError {
+            match error {
+                // The block is full of claims, but the next one won't be.
+                Error::<T>::BlockCapReached => InvalidTransaction::ExhaustsResources,
+                Error::<T>::ClaimTooSoon => InvalidTransaction::Custom(1),
+                Error::<T>::PuzzleBlockNotRecent => InvalidTransaction::Stale,
+                Error::<T>::InsufficientWork => InvalidTransaction::BadProof,
+                Error::<T>::FaucetEmpty => InvalidTransaction::Payment,
+                _ => InvalidTransaction::Custom(2),
+            }
+            .into()
+        }
+    }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,321 @@
+use frame_support::{
+    derive_impl,
+    dispatch::Pays,
+    parameter_types,
+    traits::{ConstU32, ConstU64, Hooks},
+};
+use sp_runtime::{
+    traits::ValidateUnsigned,
+    transaction_validity::{InvalidTransaction, TransactionSource},
+    BuildStorage,
+};
+
+use super::*;
+use crate::faucet as pallet_faucet;
+
+type Block = frame_system::mocking::MockBlock<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        Balances: pallet_balances,
+        Faucet: pallet_faucet,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig as frame_system::DefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+    type AccountData = pallet_balances::AccountData<u64>;
+}
+
+impl pallet_balances::Config for Test {
+    type MaxLocks = ();
+    type MaxReserves = ();
+    type ReserveIdentifier = [u8; 8];
+    type Balance = u64;
+    type RuntimeEvent = RuntimeEvent;
+    type DustRemoval = ();
+    type ExistentialDeposit = ConstU64<1>;
+    type AccountStore = System;
+    type WeightInfo = ();
+    type FreezeIdentifier = ();
+    type MaxFreezes = ();
+    type MaxHolds = ();
+    type RuntimeHoldReason = RuntimeHoldReason;
+    type RuntimeFreezeReason = RuntimeFreezeReason;
+}
+
+parameter_types! {
+    pub const MockPalletId: PalletId = PalletId(*b"py/fauct");
+    // Low enough that a puzzle is solved within a few tries.
+    pub MockPowDifficulty: U256 = U256::from(4);
+}
+
+impl Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type Currency = Balances;
+    type PalletId = MockPalletId;
+    type DripAmount = ConstU64<10>;
+    type LowFundsThreshold = ConstU64<30>;
+    type ClaimCooldown = ConstU64<5>;
+    type MaxDripsPerBlock = ConstU32<2>;
+    type MaxBalance = ConstU64<50>;
+    type ClaimPowDifficulty = MockPowDifficulty;
+    type ClaimPowWindow = ConstU64<3>;
+}
+
+const ALICE: u64 = 1;
+const BOB: u64 = 2;
+const CHARLIE: u64 = 3;
+/// Holds more than the faucet's maximum balance.
+const RICH: u64 = 4;
+/// Doesn't exist yet, so it claims with a puzzle.
+const NEWCOMER: u64 = 5;
+
+/// The pot starts out with ten drips, plus the existential deposit it always keeps.
+const INITIAL_FUNDS: u64 = 101;
+
+fn new_test_ext() -> sp_io::TestExternalities {
+    new_test_ext_with_pot(INITIAL_FUNDS)
+}
+
+fn new_test_ext_with_pot(initial_funds: u64) -> sp_io::TestExternalities {
+    let storage = RuntimeGenesisConfig {
+        system: Default::default(),
+        balances: pallet_balances::GenesisConfig {
+            balances: vec![(RICH, 100)],
+        },
+        faucet: pallet_faucet::GenesisConfig { initial_funds },
+    }
+    .build_storage()
+    .expect("The mock genesis builds");
+
+    let mut ext = sp_io::TestExternalities::new(storage);
+    // Events are not deposited in the genesis block.
+    ext.execute_with(|| run_to_block(1));
+    ext
+}
+
+fn run_to_block(number: u64) {
+    frame_system::BlockHash::<Test>::insert(number - 1, H256::repeat_byte(number as u8));
+    System::set_block_number(number);
+    Faucet::on_initialize(number);
+}
+
+#[test]
+fn claims_pay_a_drip_without_a_fee() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(
+            Faucet::claim(RuntimeOrigin::signed(ALICE)),
+            Ok(Pays::No.into())
+        );
+
+        assert_eq!(Balances::free_balance(ALICE), 10);
+        System::assert_last_event(
+            Event::<Test>::Drip {
+                who: ALICE,
+                amount: 10,
+            }
+            .into(),
+        );
+    });
+}
+
+#[test]
+fn accounts_wait_out_the_cooldown_between_claims() {
+    new_test_ext().execute_with(|| {
+        assert!(Faucet::claim(RuntimeOrigin::signed(ALICE)).is_ok());
+
+        run_to_block(5);
+        assert_eq!(
+            Faucet::claim(RuntimeOrigin::signed(ALICE)),
+            Err(Error::<Test>::ClaimTooSoon.into())
+        );
+
+        run_to_block(6);
+        assert!(Faucet::claim(RuntimeOrigin::signed(ALICE)).is_ok());
+        assert_eq!(Balances::free_balance(ALICE), 20);
+    });
+}
+
+#[test]
+fn each_block_pays_out_a_limited_number_of_claims() {
+    new_test_ext().execute_with(|| {
+        assert!(Faucet::claim(RuntimeOrigin::signed(ALICE)).is_ok());
+        assert!(Faucet::claim(RuntimeOrigin::signed(BOB)).is_ok());
+        assert_eq!(
+            Faucet::claim(RuntimeOrigin::signed(CHARLIE)),
+            Err(Error::<Test>::BlockCapReached.into())
+        );
+
+        // The allowance is reset at the start of every block.
+        run_to_block(2);
+        assert!(Faucet::claim(RuntimeOrigin::signed(CHARLIE)).is_ok());
+    });
+}
+
+#[test]
+fn accounts_with_enough_tokens_are_turned_away() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(
+            Faucet::claim(RuntimeOrigin::signed(RICH)),
+            Err(Error::<Test>::BalanceTooHigh.into())
+        );
+        assert_eq!(Balances::free_balance(RICH), 100);
+    });
+}
+
+/// Find a nonce for which the puzzle built on `block_number` does or doesn't meet the difficulty.
+fn solve(dest: u64, block_number: u64, solved: bool) -> u64 {
+    let recent_block_hash = System::block_hash(block_number);
+    (0u64..)
+        .find(|nonce| {
+            let hash = claim_pow_hash(&dest, &recent_block_hash, *nonce, SupportedHashes::Md5);
+            simple_hash_meets_difficulty(&hash, MockPowDifficulty::get()) == solved
+        })
+        .expect("Some nonce meets such a low difficulty, and some doesn't")
+}
+
+fn claim_with_pow(dest: u64, block_number: u64, nonce: u64) -> Call<Test> {
+    Call::claim_with_pow {
+        dest,
+        block_number,
+        algo: SupportedHashes::Md5,
+        nonce,
+    }
+}
+
+#[test]
+fn new_accounts_claim_by_solving_a_puzzle() {
+    new_test_ext().execute_with(|| {
+        run_to_block(3);
+        let nonce = solve(NEWCOMER, 2, true);
+
+        assert_eq!(
+            Faucet::claim_with_pow(
+                RuntimeOrigin::none(),
+                NEWCOMER,
+                2,
+                SupportedHashes::Md5,
+                nonce
+            ),
+            Ok(())
+        );
+        assert_eq!(Balances::free_balance(NEWCOMER), 10);
+    });
+}
+
+#[test]
+fn puzzles_must_be_solved_on_a_recent_block() {
+    new_test_ext().execute_with(|| {
+        run_to_block(6);
+
+        let unsolved = solve(NEWCOMER, 5, false);
+        assert_eq!(
+            Faucet::validate_unsigned(
+                TransactionSource::External,
+                &claim_with_pow(NEWCOMER, 5, unsolved)
+            ),
+            Err(InvalidTransaction::BadProof.into())
+        );
+
+        // Four blocks old is one more than the window allows.
+        let stale = solve(NEWCOMER, 2, true);
+        assert_eq!(
+            Faucet::validate_unsigned(
+                TransactionSource::External,
+                &claim_with_pow(NEWCOMER, 2, stale)
+            ),
+            Err(InvalidTransaction::Stale.into())
+        );
+    });
+}
+
+#[test]
+fn pending_claims_expire_with_their_puzzle() {
+    new_test_ext().execute_with(|| {
+        run_to_block(6);
+
+        for (block_number, longevity) in [(5, 2), (3, 1)] {
+            let nonce = solve(NEWCOMER, block_number, true);
+            let valid = Faucet::validate_unsigned(
+                TransactionSource::External,
+                &claim_with_pow(NEWCOMER, block_number, nonce),
+            )
+            .expect("The puzzle is solved on a recent block");
+
+            assert_eq!(valid.longevity, longevity);
+        }
+    });
+}
+
+#[test]
+fn drips_are_paid_from_the_pot() {
+    new_test_ext().execute_with(|| {
+        let issuance = Balances::total_issuance();
+        assert_eq!(Faucet::remaining_funds(), 100);
+
+        assert!(Faucet::claim(RuntimeOrigin::signed(ALICE)).is_ok());
+
+        assert_eq!(Faucet::remaining_funds(), 90);
+        assert_eq!(Balances::total_issuance(), issuance);
+    });
+}
+
+#[test]
+fn donations_and_rewards_top_up_the_pot() {
+    new_test_ext().execute_with(|| {
+        assert_eq!(Faucet::donate(RuntimeOrigin::signed(RICH), 20), Ok(()));
+        assert_eq!(Faucet::remaining_funds(), 120);
+        System::assert_last_event(
+            Event::<Test>::Donation {
+                who: RICH,
+                amount: 20,
+            }
+            .into(),
+        );
+
+        Faucet::fund(5);
+        assert_eq!(Faucet::remaining_funds(), 125);
+    });
+}
+
+#[test]
+fn the_pot_keeps_its_existential_deposit() {
+    new_test_ext_with_pot(20).execute_with(|| {
+        assert!(Faucet::claim(RuntimeOrigin::signed(ALICE)).is_ok());
+        assert_eq!(
+            Faucet::claim(RuntimeOrigin::signed(BOB)),
+            Err(Error::<Test>::FaucetEmpty.into())
+        );
+
+        assert_eq!(Faucet::remaining_funds(), 9);
+        assert_eq!(Balances::free_balance(Faucet::account_id()), 10);
+    });
+}
+
+#[test]
+fn running_low_is_reported_once() {
+    new_test_ext_with_pot(41).execute_with(|| {
+        let low_funds = || {
+            System::events()
+                .into_iter()
+                .filter(|record| {
+                    matches!(record.event, RuntimeEvent::Faucet(Event::LowFunds { .. }))
+                })
+                .count()
+        };
+
+        // Down to the threshold, but not below it.
+        assert!(Faucet::claim(RuntimeOrigin::signed(ALICE)).is_ok());
+        assert_eq!(low_funds(), 0);
+
+        assert!(Faucet::claim(RuntimeOrigin::signed(BOB)).is_ok());
+        System::assert_last_event(Event::<Test>::LowFunds { remaining: 20 }.into());
+
+        run_to_block(2);
+        assert!(Faucet::claim(RuntimeOrigin::signed(CHARLIE)).is_ok());
+        assert_eq!(low_funds(), 1);
+    });
+}
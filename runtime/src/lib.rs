@@ -26,29 +26,30 @@ pub use frame_support::{
 use frame_support::{
     genesis_builder_helper::{build_config, create_default_config},
     sp_runtime::Perquintill,
-    traits::{ConstU128, ConstU32, ConstU8},
+    traits::{ConstU128, ConstU32, ConstU8, FindAuthor, Get},
+    PalletId,
 };
-use multi_pow::SupportedHashes;
+use multi_pow::{SupportedHashes, Threshold};
 pub use pallet_balances::Call as BalancesCall;
 pub use pallet_timestamp::Call as TimestampCall;
 use pallet_transaction_payment::{ConstFeeMultiplier, CurrencyAdapter, Multiplier};
 use parity_scale_codec::Decode;
 use sp_api::impl_runtime_apis;
 use sp_consensus_pow::POW_ENGINE_ID;
-use sp_core::OpaqueMetadata;
+use sp_core::{OpaqueMetadata, U256};
 // A few exports that help ease life for downstream crates.
 #[cfg(any(feature = "std", test))]
 pub use sp_runtime::BuildStorage;
-use sp_runtime::DigestItem;
+use sp_runtime::{ConsensusEngineId, DigestItem};
 use sp_runtime::{
     create_runtime_str, generic,
     traits::{
         AccountIdLookup, BlakeTwo256, Block as BlockT, Bounded, IdentifyAccount, One, Verify,
     },
     transaction_validity::{TransactionSource, TransactionValidity},
-    ApplyExtrinsicResult, MultiSignature,
+    ApplyExtrinsicResult, MultiSignature, RuntimeString,
 };
-pub use sp_runtime::{FixedPointNumber, Perbill, Permill};
+pub use sp_runtime::{FixedPointNumber, FixedU128, Perbill, Percent, Permill};
 use sp_std::prelude::*;
 #[cfg(feature = "std")]
 use sp_version::NativeVersion;
@@ -88,9 +89,15 @@ pub mod block_author;
 // /// The Difficulty Adjustment Algorithm in `./difficulty.rs`
 pub mod difficulty;
 
+/// The shared difficulty adjustment in `./shared_difficulty.rs`
+pub mod shared_difficulty;
+
 /// The faucet to allow users to claim free tokens
 pub mod faucet;
 
+/// Uncle (ommer) inclusion and rewards in `./uncles.rs`
+pub mod uncles;
+
 /// Opaque types. These are used by the CLI to instantiate machinery that don't need to know
 /// the specifics of the runtime. They can then be made to be agnostic over specific formats
 /// of data like extrinsics, allowing for them to continue syncing the network through upgrades
@@ -114,7 +121,7 @@ pub const VERSION: RuntimeVersion = RuntimeVersion {
     spec_name: create_runtime_str!("academy-pow"),
     impl_name: create_runtime_str!("academy-pow"),
     authoring_version: 1,
-    spec_version: 1,
+    spec_version: 3,
     impl_version: 1,
     apis: RUNTIME_API_VERSIONS,
     transaction_version: 1,
@@ -200,6 +207,13 @@ parameter_types! {
     pub const MinimumPeriod: u64 = 1000;
 }
 
+/// How far ahead of the timestamp in the inherent data the timestamp pallet accepts a block's
+/// timestamp, in milliseconds.
+///
+/// The pallet hard-codes this without exporting it. The node needs it to shift the allowance, so
+/// it is repeated here, and a test makes sure it still matches the pallet.
+pub const MAX_TIMESTAMP_DRIFT_MILLIS: u64 = 30 * 1000;
+
 impl pallet_timestamp::Config for Runtime {
     /// A timestamp: milliseconds since the unix epoch.
     type Moment = u64;
@@ -227,12 +241,21 @@ impl pallet_balances::Config for Runtime {
     type RuntimeFreezeReason = RuntimeFreezeReason;
 }
 
-parameter_types! {
-    pub const TargetBlockTime: u128 = 5_000;
-    // Setting min difficulty to damp factor per recommendation
-    pub const DampFactor: u128 = 3;
-    pub const ClampFactor: u128 = 2;
-    pub const MaxDifficulty: u128 = u128::max_value();
+/// The current difficulty threshold for each of the supported hashing algorithms.
+pub struct CurrentThreshold;
+
+impl Get<Threshold> for CurrentThreshold {
+    fn get() -> Threshold {
+        if SharedDifficulty::enabled() {
+            return SharedDifficulty::threshold()
+        }
+
+        Threshold {
+            md5: Md5DifficultyAdjustment::difficulty(),
+            sha3: Sha3DifficultyAdjustment::difficulty(),
+            keccak: KeccakDifficultyAdjustment::difficulty(),
+        }
+    }
 }
 
 // Helper function to get the current blocks PoW algo from the predigest
@@ -251,12 +274,8 @@ fn current_blocks_mining_algo() -> SupportedHashes {
 }
 
 impl difficulty::Config<Instance1> for Runtime {
+    type RuntimeEvent = RuntimeEvent;
     type TimeProvider = Timestamp;
-    type TargetBlockTime = TargetBlockTime;
-    type DampFactor = DampFactor;
-    type ClampFactor = ClampFactor;
-    type MaxDifficulty = MaxDifficulty;
-    type MinDifficulty = DampFactor;
 
     fn relevant_to_this_instance() -> bool {
         current_blocks_mining_algo() == SupportedHashes::Md5
@@ -264,12 +283,8 @@ impl difficulty::Config<Instance1> for Runtime {
 }
 
 impl difficulty::Config<Instance2> for Runtime {
+    type RuntimeEvent = RuntimeEvent;
     type TimeProvider = Timestamp;
-    type TargetBlockTime = TargetBlockTime;
-    type DampFactor = DampFactor;
-    type ClampFactor = ClampFactor;
-    type MaxDifficulty = MaxDifficulty;
-    type MinDifficulty = DampFactor;
 
     fn relevant_to_this_instance() -> bool {
         current_blocks_mining_algo() == SupportedHashes::Sha3
@@ -277,34 +292,171 @@ impl difficulty::Config<Instance2> for Runtime {
 }
 
 impl difficulty::Config<Instance3> for Runtime {
+    type RuntimeEvent = RuntimeEvent;
     type TimeProvider = Timestamp;
-    type TargetBlockTime = TargetBlockTime;
-    type DampFactor = DampFactor;
-    type ClampFactor = ClampFactor;
-    type MaxDifficulty = MaxDifficulty;
-    type MinDifficulty = DampFactor;
 
     fn relevant_to_this_instance() -> bool {
         current_blocks_mining_algo() == SupportedHashes::Keccak
     }
 }
 
+parameter_types! {
+    // Same as each algorithm's own DAA, but never easier than sixty hashes.
+    pub SharedDifficultyParams: difficulty::DifficultyAdjustmentParams =
+        difficulty::DifficultyAdjustmentParams {
+            min_difficulty: 60.into(),
+            ..Default::default()
+        };
+    pub const SharedAdjustmentWindow: u128 = 60;
+    pub SharedMinMultiplier: FixedU128 = FixedU128::from_rational(1, 1_000);
+    pub SharedMaxMultiplier: FixedU128 = FixedU128::from_u32(1_000);
+}
+
+impl shared_difficulty::Config for Runtime {
+    type TimeProvider = Timestamp;
+    type Params = SharedDifficultyParams;
+    type AdjustmentWindow = SharedAdjustmentWindow;
+    type MinMultiplier = SharedMinMultiplier;
+    type MaxMultiplier = SharedMaxMultiplier;
+
+    fn current_algo() -> SupportedHashes {
+        current_blocks_mining_algo()
+    }
+}
+
+parameter_types! {
+    pub const FaucetPalletId: PalletId = PalletId(*b"py/fauct");
+    // Warn when the faucet can only pay out another hundred drips
+    pub const FaucetLowFundsThreshold: Balance = 100 * 5 * TOKEN;
+    // Each account may claim from the faucet about once an hour
+    pub const FaucetClaimCooldown: BlockNumber = 720;
+    pub const MaxDripsPerBlock: u32 = 10;
+    // Accounts holding more than four drips are turned away
+    pub const FaucetMaxBalance: Balance = 20 * TOKEN;
+    // Unsigned claims take about a million hashes to solve
+    pub FaucetPowDifficulty: U256 = U256::from(1_000_000u64);
+    // Unsigned claims must be built on one of the last 10 blocks
+    pub const FaucetPowWindow: BlockNumber = 10;
+}
+
 impl faucet::Config for Runtime {
-    // type Event = Event;
+    type RuntimeEvent = RuntimeEvent;
     type Currency = Balances;
+    type PalletId = FaucetPalletId;
 
     // Each drip of the faucet gives 5 tokens (with 12 decimals)
     type DripAmount = ConstU128<{ 5 * TOKEN }>;
+    type LowFundsThreshold = FaucetLowFundsThreshold;
+    type ClaimCooldown = FaucetClaimCooldown;
+    type MaxDripsPerBlock = MaxDripsPerBlock;
+    type MaxBalance = FaucetMaxBalance;
+    type ClaimPowDifficulty = FaucetPowDifficulty;
+    type ClaimPowWindow = FaucetPowWindow;
 }
 
-impl block_author::Config for Runtime {
+parameter_types! {
     // Each block mined issues 50 new tokens to the miner
+    pub const BlockReward: Balance = 50 * TOKEN;
+    // Of which a tenth goes to the faucet instead
+    pub FaucetRewardShare: Perbill = Perbill::from_percent(10);
+}
+
+/// The part of the block reward that is paid to miners, after the faucet's share.
+pub struct MinerReward;
+
+impl Get<Balance> for MinerReward {
+    fn get() -> Balance {
+        BlockReward::get() - FaucetRewardShare::get() * BlockReward::get()
+    }
+}
+
+/// Finds the block author in the PoW pre-runtime digest.
+pub struct PowFindAuthor;
+
+impl FindAuthor<AccountId> for PowFindAuthor {
+    fn find_author<'a, I>(digests: I) -> Option<AccountId>
+    where
+        I: 'a + IntoIterator<Item = (ConsensusEngineId, &'a [u8])>,
+    {
+        digests.into_iter().find_map(|(id, pre_digest)| {
+            if id == POW_ENGINE_ID {
+                PreDigest::decode(&mut &pre_digest[..]).map(|d| d.0).ok()
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Make sure the author in the block's `set_author` inherent is the one named in its PoW pre-digest.
+///
+/// The block author pallet checks this too when the block is executed. Checking it here as well
+/// lets the node reject such blocks during import with a clear error.
+fn check_author_inherent(block: &Block) -> Result<(), block_author::InherentError> {
+    use block_author::InherentError;
+
+    let pre_runtime_digests = block
+        .header
+        .digest
+        .logs
+        .iter()
+        .filter_map(|d| d.as_pre_runtime());
+    let digest_author =
+        PowFindAuthor::find_author(pre_runtime_digests).ok_or(InherentError::Other(
+            RuntimeString::Borrowed("Block has no PoW pre-digest naming its author"),
+        ))?;
+
+    let inherent_author = block
+        .extrinsics
+        .iter()
+        .find_map(|extrinsic| match &extrinsic.function {
+            RuntimeCall::BlockAuthor(block_author::Call::set_author { author }) => Some(*author),
+            _ => None,
+        })
+        .ok_or(InherentError::Other(RuntimeString::Borrowed(
+            "Block has no set_author inherent",
+        )))?;
+
+    if AccountId::from(inherent_author) != digest_author {
+        return Err(InherentError::Other(RuntimeString::Borrowed(
+            "Author in the set_author inherent does not match the author in the PoW pre-digest",
+        )));
+    }
+
+    Ok(())
+}
+
+impl block_author::Config for Runtime {
+    type FindAuthor = PowFindAuthor;
+
     fn on_author_set(author_account: Self::AccountId) {
-        let issuance = 50 * TOKEN;
-        let _ = Balances::deposit_creating(&author_account, issuance);
+        let _ = Balances::deposit_creating(&author_account, MinerReward::get());
+        Faucet::fund(BlockReward::get() - MinerReward::get());
     }
 }
 
+parameter_types! {
+    // Same as Ethereum: uncles may be up to six generations old, and each block may include two.
+    pub const UncleGenerations: u32 = 6;
+    pub const MaxUncles: u32 = 2;
+    // The block author gets an extra 1/32 of the block reward for each uncle they include.
+    pub InclusionReward: Perbill = Perbill::from_rational(1u32, 32u32);
+    // Uncles may have been sealed at up to half the current difficulty.
+    pub const UncleDifficultyTolerance: Percent = Percent::from_percent(50);
+}
+
+impl uncles::Config for Runtime {
+    type RuntimeEvent = RuntimeEvent;
+    type Currency = Balances;
+    type Difficulty = CurrentThreshold;
+    // Uncle rewards are cut by the faucet's share just like regular block rewards.
+    type BlockReward = MinerReward;
+    type UncleGenerations = UncleGenerations;
+    type MaxUncles = MaxUncles;
+    type InclusionReward = InclusionReward;
+    type DifficultyTolerance = UncleDifficultyTolerance;
+}
+
 parameter_types! {
     // This value increases the priority of `Operational` transactions by adding
     // a "virtual tip" that's equal to the `OperationalFeeMultiplier * final_fee`.
@@ -334,17 +486,26 @@ impl pallet_transaction_payment::Config for Runtime {
     type FeeMultiplierUpdate = ConstFeeMultiplier<FeeMultiplier>;
 }
 
+impl pallet_sudo::Config for Runtime {
+    type RuntimeEvent = RuntimeEvent;
+    type RuntimeCall = RuntimeCall;
+    type WeightInfo = pallet_sudo::weights::SubstrateWeight<Runtime>;
+}
+
 construct_runtime!(
     pub struct Runtime {
         System: frame_system,
         Timestamp: pallet_timestamp,
         Balances: pallet_balances,
         TransactionPayment: pallet_transaction_payment,
+        Sudo: pallet_sudo,
         Md5DifficultyAdjustment: difficulty::<Instance1>,
         Sha3DifficultyAdjustment: difficulty::<Instance2>,
         KeccakDifficultyAdjustment: difficulty::<Instance3>,
+        SharedDifficulty: shared_difficulty,
         BlockAuthor: block_author,
         Faucet: faucet,
+        Uncles: uncles,
     }
 );
 
@@ -425,7 +586,16 @@ impl_runtime_apis! {
             block: Block,
             data: sp_inherents::InherentData,
         ) -> sp_inherents::CheckInherentsResult {
-            data.check_extrinsics(&block)
+            let mut result = data.check_extrinsics(&block);
+
+            // The pallets only see the inherent data, not the header, so the author check
+            // that needs both happens here.
+            // If another inherent already reported a fatal error, the block is rejected anyway.
+            if let Err(e) = check_author_inherent(&block) {
+                let _ = result.put_error(block_author::INHERENT_IDENTIFIER, &e);
+            }
+
+            result
         }
     }
 
@@ -459,14 +629,45 @@ impl_runtime_apis! {
 
     impl sp_consensus_pow::DifficultyApi<Block, multi_pow::Threshold> for Runtime {
         fn difficulty() -> multi_pow::Threshold {
-            multi_pow::Threshold {
-                md5: Md5DifficultyAdjustment::difficulty(),
-                sha3: Sha3DifficultyAdjustment::difficulty(),
-                keccak: KeccakDifficultyAdjustment::difficulty(),
+            CurrentThreshold::get()
+        }
+    }
+
+    impl difficulty::DifficultyAdjustmentApi<Block> for Runtime {
+        fn params(algo: SupportedHashes) -> difficulty::DifficultyAdjustmentParams {
+            if SharedDifficulty::enabled() {
+                return SharedDifficultyParams::get()
+            }
+
+            match algo {
+                SupportedHashes::Md5 => Md5DifficultyAdjustment::params(),
+                SupportedHashes::Sha3 => Sha3DifficultyAdjustment::params(),
+                SupportedHashes::Keccak => KeccakDifficultyAdjustment::params(),
             }
         }
     }
 
+    impl difficulty::DifficultyHistoryApi<Block, u64> for Runtime {
+        fn difficulty_history(algo: SupportedHashes) -> difficulty::DifficultyHistory<u64> {
+            // Describe whichever DAA `CurrentThreshold` takes the difficulty from.
+            if SharedDifficulty::enabled() {
+                return SharedDifficulty::history(algo)
+            }
+
+            match algo {
+                SupportedHashes::Md5 => Md5DifficultyAdjustment::history(),
+                SupportedHashes::Sha3 => Sha3DifficultyAdjustment::history(),
+                SupportedHashes::Keccak => KeccakDifficultyAdjustment::history(),
+            }
+        }
+    }
+
+    impl faucet::FaucetApi<Block, Balance> for Runtime {
+        fn remaining_funds() -> Balance {
+            Faucet::remaining_funds()
+        }
+    }
+
     impl frame_system_rpc_runtime_api::AccountNonceApi<Block, AccountId, Index> for Runtime {
         fn account_nonce(account: AccountId) -> Index {
             System::account_nonce(account)
@@ -527,3 +728,6 @@ impl pallet_transaction_payment_rpc_runtime_api::TransactionPaymentCallApi<Block
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
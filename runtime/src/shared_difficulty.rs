@@ -0,0 +1,289 @@
+//! An alternative difficulty adjustment with a single global target shared by every algorithm.
+//!
+//! The `difficulty` pallet runs one independent DAA per algorithm. An algorithm that nobody
+//! mines keeps whatever difficulty it had last, so miners who switch to it get a burst of
+//! nearly free blocks. This pallet instead keeps one global difficulty, tuned from the block
+//! times of all blocks, and derives each algorithm's difficulty from it through a multiplier
+//! that reflects how expensive that algorithm's hashes are relative to the others.
+//!
+//! The multipliers are recalibrated from which algorithms mined the recent blocks. Every
+//! algorithm that mined any of them is expected to mine an equal share. When an algorithm mines
+//! more than its share, its hashes are cheaper than its multiplier assumes, so the multiplier
+//! goes up, and vice versa. Block arrivals are random, so the recent shares are noisy, but the
+//! noise averages out, because a multiplier that went too high makes its algorithm mine fewer
+//! blocks and comes back down.
+//!
+//! The global difficulty is retargeted from the difficulties and timestamps of the last blocks,
+//! with the same DAA and parameters as the `difficulty` pallet. An algorithm that hasn't been
+//! mined for a while has its difficulty relaxed by the same idle decay, so that it doesn't stay
+//! stuck.
+//!
+//! Whether the runtime uses these difficulties or the per-algorithm ones is decided at genesis
+//! by the `enabled` flag. This pallet keeps tracking block times either way.
+
+use frame_support::traits::Time;
+use multi_pow::{SupportedHashes, Threshold};
+use parity_scale_codec::{Decode, Encode, MaxEncodedLen};
+use scale_info::TypeInfo;
+use sp_core::U256;
+use sp_runtime::{
+    traits::{Saturating, UniqueSaturatedInto},
+    FixedPointNumber, FixedU128,
+};
+use sp_std::vec::Vec;
+
+use crate::difficulty::{
+    decay, retarget, DifficultyAdjustmentParams, DifficultyAndTimestamp, DifficultyHistory,
+    DIFFICULTY_ADJUST_WINDOW,
+};
+
+pub use pallet::*;
+
+/// What this pallet knows about a single algorithm.
+#[derive(Encode, Decode, Clone, Copy, Eq, PartialEq, Debug, MaxEncodedLen, TypeInfo)]
+pub struct AlgoStats<Moment> {
+    /// This algorithm's difficulty, relative to the global difficulty, before any idle decay.
+    pub multiplier: FixedU128,
+    /// When a block was last mined with this algorithm, or when we started watching it.
+    pub last_seen: Option<Moment>,
+}
+
+impl<Moment> Default for AlgoStats<Moment> {
+    fn default() -> Self {
+        AlgoStats {
+            multiplier: FixedU128::one(),
+            last_seen: None,
+        }
+    }
+}
+
+const ALGOS: [SupportedHashes; 3] = [
+    SupportedHashes::Md5,
+    SupportedHashes::Sha3,
+    SupportedHashes::Keccak,
+];
+
+#[frame_support::pallet(dev_mode)]
+pub mod pallet {
+    use frame_support::pallet_prelude::*;
+    use frame_system::pallet_prelude::*;
+
+    use super::*;
+
+    pub(super) type MomentOf<T> = <<T as Config>::TimeProvider as Time>::Moment;
+
+    type DifficultyList<T> =
+        [Option<DifficultyAndTimestamp<MomentOf<T>>>; DIFFICULTY_ADJUST_WINDOW as usize];
+
+    /// Pallet's configuration trait.
+    #[pallet::config]
+    pub trait Config: frame_system::Config {
+        /// A Source for timestamp data
+        type TimeProvider: Time;
+        /// The parameters the global difficulty is retargeted with, and the bounds and idle
+        /// decay of each algorithm's difficulty
+        type Params: Get<DifficultyAdjustmentParams>;
+        /// How many recent blocks the algorithms' shares are counted over. Also used as the
+        /// dampening factor when recalibrating the multipliers.
+        type AdjustmentWindow: Get<u128>;
+        /// The lowest an algorithm's multiplier may go
+        type MinMultiplier: Get<FixedU128>;
+        /// The highest an algorithm's multiplier may go
+        type MaxMultiplier: Get<FixedU128>;
+
+        /// The algorithm the current block was mined with.
+        fn current_algo() -> SupportedHashes;
+    }
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    /// Whether the runtime uses the shared difficulty instead of the per-algorithm ones.
+    #[pallet::storage]
+    #[pallet::getter(fn enabled)]
+    pub type Enabled<T: Config> = StorageValue<_, bool, ValueQuery>;
+
+    /// The global difficulty that each algorithm's difficulty is derived from.
+    #[pallet::storage]
+    #[pallet::getter(fn difficulty)]
+    pub type GlobalDifficulty<T: Config> = StorageValue<_, U256, ValueQuery>;
+
+    /// The global difficulty at genesis, assumed for blocks before the window filled up.
+    #[pallet::storage]
+    pub type InitialDifficulty<T: Config> = StorageValue<_, U256, ValueQuery>;
+
+    /// The global difficulties and timestamps of the last blocks, from earliest to latest.
+    #[pallet::storage]
+    pub type PastDifficultiesAndTimestamps<T: Config> =
+        StorageValue<_, DifficultyList<T>, ValueQuery, EmptyList<T>>;
+
+    pub struct EmptyList<T: Config>(PhantomData<T>);
+    impl<T: Config> Get<DifficultyList<T>> for EmptyList<T> {
+        fn get() -> DifficultyList<T> {
+            [None; DIFFICULTY_ADJUST_WINDOW as usize]
+        }
+    }
+
+    /// The algorithms the last blocks were mined with, from earliest to latest.
+    #[pallet::storage]
+    pub type RecentAlgos<T: Config> = StorageValue<_, Vec<SupportedHashes>, ValueQuery>;
+
+    /// Per-algorithm multipliers and when each algorithm was last mined.
+    #[pallet::storage]
+    pub type Algos<T: Config> =
+        StorageMap<_, Twox64Concat, SupportedHashes, AlgoStats<MomentOf<T>>, ValueQuery>;
+
+    #[pallet::genesis_config]
+    pub struct GenesisConfig<T: Config> {
+        pub _ph_data: PhantomData<T>,
+        pub enabled: bool,
+        pub initial_difficulty: U256,
+    }
+
+    impl<T: Config> Default for GenesisConfig<T> {
+        fn default() -> Self {
+            GenesisConfig {
+                _ph_data: Default::default(),
+                enabled: false,
+                initial_difficulty: 4_000_000.into(),
+            }
+        }
+    }
+
+    #[pallet::genesis_build]
+    impl<T: Config> BuildGenesisConfig for GenesisConfig<T> {
+        fn build(&self) {
+            Enabled::<T>::put(self.enabled);
+            GlobalDifficulty::<T>::put(self.initial_difficulty);
+            InitialDifficulty::<T>::put(self.initial_difficulty);
+        }
+    }
+
+    #[pallet::hooks]
+    impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+        fn on_finalize(_n: BlockNumberFor<T>) {
+            let params = T::Params::get();
+            let now = T::TimeProvider::now();
+
+            // Retarget the global difficulty from the last blocks of every algorithm, just like
+            // the per-algorithm DAA does from the blocks of one.
+            let mut data = PastDifficultiesAndTimestamps::<T>::get();
+            data.rotate_left(1);
+            data[data.len() - 1] = Some(DifficultyAndTimestamp {
+                timestamp: now,
+                difficulty: GlobalDifficulty::<T>::get(),
+            });
+            let difficulty = retarget(&data, InitialDifficulty::<T>::get(), &params).difficulty;
+            PastDifficultiesAndTimestamps::<T>::put(data);
+            GlobalDifficulty::<T>::put(difficulty);
+
+            let mined = T::current_algo();
+            let recent = RecentAlgos::<T>::mutate(|recent| {
+                recent.push(mined);
+                let window = T::AdjustmentWindow::get().unique_saturated_into();
+                recent.drain(..recent.len().saturating_sub(window));
+                recent.clone()
+            });
+            for algo in ALGOS {
+                Algos::<T>::mutate(algo, |stats| {
+                    if algo == mined {
+                        Self::recalibrate(algo, stats, &recent, now);
+                    } else if stats.last_seen.is_none() {
+                        // Start the clock the first time we see the algorithm.
+                        stats.last_seen = Some(now);
+                    }
+                });
+            }
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        /// Each algorithm's current difficulty, relaxed if it has been idle for a while.
+        pub fn threshold() -> Threshold {
+            let params = T::Params::get();
+            let now: u128 = T::TimeProvider::now().unique_saturated_into();
+            let global = GlobalDifficulty::<T>::get();
+            let for_algo = |algo| {
+                let stats = Algos::<T>::get(algo);
+                let idle = stats.last_seen.map_or(0, |last_seen| {
+                    now.saturating_sub(last_seen.unique_saturated_into())
+                });
+                decay(
+                    Self::scaled(global, stats.multiplier, &params),
+                    idle,
+                    &params,
+                )
+            };
+
+            Threshold {
+                md5: for_algo(SupportedHashes::Md5),
+                sha3: for_algo(SupportedHashes::Sha3),
+                keccak: for_algo(SupportedHashes::Keccak),
+            }
+        }
+
+        /// The global difficulty adjustment window and what the DAA makes of it, with the
+        /// current and next difficulties being those of the given algorithm.
+        pub fn history(algo: SupportedHashes) -> DifficultyHistory<MomentOf<T>> {
+            let params = T::Params::get();
+            let mut history = DifficultyHistory::new(
+                &PastDifficultiesAndTimestamps::<T>::get(),
+                InitialDifficulty::<T>::get(),
+                GlobalDifficulty::<T>::get(),
+                &params,
+            );
+            history.current_difficulty = Self::threshold().for_algo(algo);
+            let multiplier = Algos::<T>::get(algo).multiplier;
+            history.next_difficulty = Self::scaled(history.next_difficulty, multiplier, &params);
+            history
+        }
+
+        /// What a global difficulty amounts to for an algorithm with the given multiplier, before
+        /// any idle decay.
+        fn scaled(
+            global: U256,
+            multiplier: FixedU128,
+            params: &DifficultyAdjustmentParams,
+        ) -> U256 {
+            let difficulty = global.saturating_mul(U256::from(multiplier.into_inner()))
+                / U256::from(FixedU128::DIV);
+            difficulty.clamp(params.min_difficulty, params.max_difficulty)
+        }
+
+        fn bounded_multiplier(multiplier: FixedU128) -> FixedU128 {
+            multiplier.clamp(T::MinMultiplier::get(), T::MaxMultiplier::get())
+        }
+
+        /// An algorithm just mined a block. If it mined more than its share of the recent
+        /// blocks, its hashes are cheaper than we thought, so make it harder, and vice versa.
+        ///
+        /// Each algorithm that mined any of the recent blocks is expected to mine an equal share
+        /// of them. Algorithms that nobody mines are left alone, the idle decay takes care of
+        /// them.
+        fn recalibrate(
+            algo: SupportedHashes,
+            stats: &mut AlgoStats<MomentOf<T>>,
+            recent: &[SupportedHashes],
+            now: MomentOf<T>,
+        ) {
+            let window = T::AdjustmentWindow::get();
+            let blocks = recent.len() as u128;
+            let mined = recent.iter().filter(|mined| **mined == algo).count() as u128;
+            let active = ALGOS.iter().filter(|algo| recent.contains(algo)).count() as u128;
+
+            // The share `mined / blocks` over the expected `1 / active`, moved only a `window`th
+            // of the way from one, so that a single block doesn't swing the multiplier.
+            let ratio = FixedU128::saturating_from_rational(
+                mined
+                    .saturating_mul(active)
+                    .saturating_add(window.saturating_sub(1).saturating_mul(blocks)),
+                window.saturating_mul(blocks).max(1),
+            );
+            stats.multiplier = Self::bounded_multiplier(stats.multiplier.saturating_mul(ratio));
+            stats.last_seen = Some(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
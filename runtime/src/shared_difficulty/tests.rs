@@ -0,0 +1,315 @@
+use frame_support::{derive_impl, parameter_types, traits::Hooks};
+use sp_runtime::BuildStorage;
+
+use super::*;
+use crate::shared_difficulty as pallet_shared_difficulty;
+
+type Block = frame_system::mocking::MockBlock<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        SharedDifficulty: pallet_shared_difficulty,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig as frame_system::DefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+}
+
+parameter_types! {
+    /// The mock wall clock, in milliseconds.
+    pub static Now: u64 = 0;
+    /// The algorithm the block being finalized was mined with.
+    pub static MinedWith: SupportedHashes = SupportedHashes::Md5;
+    pub MockParams: DifficultyAdjustmentParams = DifficultyAdjustmentParams {
+        min_difficulty: MIN_DIFFICULTY.into(),
+        ..Default::default()
+    };
+    pub const MockAdjustmentWindow: u128 = 60;
+    pub MockMinMultiplier: FixedU128 = FixedU128::from_rational(1, 1_000);
+    pub MockMaxMultiplier: FixedU128 = FixedU128::from_u32(1_000);
+}
+
+/// Reads the mock wall clock.
+pub struct MockTime;
+
+impl Time for MockTime {
+    type Moment = u64;
+
+    fn now() -> u64 {
+        Now::get()
+    }
+}
+
+impl Config for Test {
+    type TimeProvider = MockTime;
+    type Params = MockParams;
+    type AdjustmentWindow = MockAdjustmentWindow;
+    type MinMultiplier = MockMinMultiplier;
+    type MaxMultiplier = MockMaxMultiplier;
+
+    fn current_algo() -> SupportedHashes {
+        MinedWith::get()
+    }
+}
+
+const INITIAL_DIFFICULTY: u64 = 1_000_000;
+const MIN_DIFFICULTY: u64 = 60;
+const TARGET: u64 = 5_000;
+const HOUR: u64 = 60 * 60 * 1_000;
+
+fn new_test_ext() -> sp_io::TestExternalities {
+    let storage = RuntimeGenesisConfig {
+        system: Default::default(),
+        shared_difficulty: GenesisConfig {
+            enabled: true,
+            initial_difficulty: INITIAL_DIFFICULTY.into(),
+            ..Default::default()
+        },
+    }
+    .build_storage()
+    .expect("The mock genesis builds");
+    let mut ext = sp_io::TestExternalities::new(storage);
+    ext.execute_with(|| Now::set(&0));
+    ext
+}
+
+/// Finalize a block mined with `algo`, `solve_time` milliseconds after the previous one.
+fn mine(algo: SupportedHashes, solve_time: u64) {
+    Now::mutate(|now| *now += solve_time);
+    MinedWith::set(&algo);
+    System::set_block_number(System::block_number() + 1);
+    SharedDifficulty::on_finalize(System::block_number());
+}
+
+/// The average of the global difficulties in the window.
+fn window_average() -> U256 {
+    let window = PastDifficultiesAndTimestamps::<Test>::get();
+    let sum = window
+        .iter()
+        .map(|item| item.map_or(INITIAL_DIFFICULTY.into(), |d| d.difficulty))
+        .fold(U256::zero(), |sum, difficulty| sum + difficulty);
+    sum / U256::from(window.len())
+}
+
+#[test]
+fn genesis_sets_the_mode_and_difficulty() {
+    new_test_ext().execute_with(|| {
+        assert!(SharedDifficulty::enabled());
+        assert_eq!(SharedDifficulty::difficulty(), INITIAL_DIFFICULTY.into());
+        assert_eq!(
+            SharedDifficulty::threshold(),
+            Threshold {
+                md5: INITIAL_DIFFICULTY.into(),
+                sha3: INITIAL_DIFFICULTY.into(),
+                keccak: INITIAL_DIFFICULTY.into(),
+            }
+        );
+    });
+}
+
+#[test]
+fn disabled_by_default() {
+    assert!(!GenesisConfig::<Test>::default().enabled);
+}
+
+#[test]
+fn fast_blocks_raise_the_difficulty() {
+    new_test_ext().execute_with(|| {
+        for _ in 0..60 {
+            mine(SupportedHashes::Md5, TARGET / 4);
+        }
+        assert!(SharedDifficulty::difficulty() > INITIAL_DIFFICULTY.into());
+    });
+}
+
+#[test]
+fn slow_blocks_lower_the_difficulty() {
+    new_test_ext().execute_with(|| {
+        for _ in 0..60 {
+            mine(SupportedHashes::Md5, TARGET * 4);
+        }
+        assert!(SharedDifficulty::difficulty() < INITIAL_DIFFICULTY.into());
+    });
+}
+
+#[test]
+fn one_slow_block_moves_the_difficulty_by_at_most_the_clamp_factor() {
+    new_test_ext().execute_with(|| {
+        for _ in 0..60 {
+            mine(SupportedHashes::Md5, TARGET);
+        }
+        mine(SupportedHashes::Md5, 24 * HOUR);
+
+        // The difficulty is retargeted from the whole window rather than compounded block by
+        // block, so it stays within the clamp factor of the window's average.
+        let clamp_factor = MockParams::get().clamp_factor;
+        assert!(SharedDifficulty::difficulty() >= window_average() / U256::from(clamp_factor));
+    });
+}
+
+/// A deterministic xorshift generator, so that the simulated block arrivals are repeatable.
+struct Rng(u64);
+
+impl Rng {
+    /// A uniform sample from the open interval (0, 1).
+    fn unit(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        ((self.0 >> 11) + 1) as f64 / ((1u64 << 53) + 1) as f64
+    }
+}
+
+/// Mine `blocks` blocks with miners of the given hashrates, in hashes per millisecond, racing
+/// each other. Each algorithm's blocks arrive as a Poisson process at its hashrate over its
+/// current difficulty, so the next block is the earliest of one exponential sample per
+/// algorithm. Returns the algorithms the blocks were mined with.
+fn race(
+    rng: &mut Rng,
+    hashrates: &[(SupportedHashes, f64)],
+    blocks: usize,
+) -> Vec<SupportedHashes> {
+    (0..blocks)
+        .map(|_| {
+            let threshold = SharedDifficulty::threshold();
+            let (solve_time, algo) = hashrates
+                .iter()
+                .map(|(algo, hashrate)| {
+                    let difficulty = threshold.for_algo(*algo).low_u128() as f64;
+                    (-rng.unit().ln() * difficulty / hashrate, *algo)
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0))
+                .expect("Some algorithm is mined");
+            mine(algo, (solve_time as u64).max(1));
+            algo
+        })
+        .collect()
+}
+
+#[test]
+fn algorithms_with_more_hashrate_get_harder_until_they_mine_their_share() {
+    new_test_ext().execute_with(|| {
+        // Md5 has four times sha3's hashrate, and together they mine at about the target.
+        let mut rng = Rng(42);
+        let mined = race(
+            &mut rng,
+            &[(SupportedHashes::Md5, 160.0), (SupportedHashes::Sha3, 40.0)],
+            2_000,
+        );
+
+        let threshold = SharedDifficulty::threshold();
+        assert!(threshold.md5 > threshold.sha3 * U256::from(2));
+        assert!(threshold.md5 < threshold.sha3 * U256::from(8));
+        let md5_blocks = mined[mined.len() - 600..]
+            .iter()
+            .filter(|algo| **algo == SupportedHashes::Md5)
+            .count();
+        assert!(
+            (240..=360).contains(&md5_blocks),
+            "md5 mined {md5_blocks} of 600"
+        );
+        assert_eq!(
+            Algos::<Test>::get(SupportedHashes::Keccak).multiplier,
+            FixedU128::one()
+        );
+    });
+}
+
+#[test]
+fn multipliers_stay_put_with_equal_hashrates() {
+    new_test_ext().execute_with(|| {
+        let mut rng = Rng(7);
+        race(
+            &mut rng,
+            &[
+                (SupportedHashes::Md5, 100.0),
+                (SupportedHashes::Sha3, 100.0),
+            ],
+            3_000,
+        );
+
+        // The arrivals are random, but the multipliers don't wander off.
+        for algo in [SupportedHashes::Md5, SupportedHashes::Sha3] {
+            let multiplier = Algos::<Test>::get(algo).multiplier;
+            assert!(
+                multiplier > FixedU128::from_rational(2, 3),
+                "{algo:?}: {multiplier:?}"
+            );
+            assert!(
+                multiplier < FixedU128::from_rational(3, 2),
+                "{algo:?}: {multiplier:?}"
+            );
+        }
+    });
+}
+
+#[test]
+fn idle_algorithms_decay_without_blocks() {
+    new_test_ext().execute_with(|| {
+        mine(SupportedHashes::Md5, TARGET);
+        let before = SharedDifficulty::threshold();
+
+        // No block is finalized, yet after an hour past the decay delay every algorithm is
+        // down to half its difficulty.
+        Now::mutate(|now| *now += 2 * HOUR);
+        let after = SharedDifficulty::threshold();
+        assert_eq!(after.md5, before.md5 / 2);
+        assert_eq!(after.sha3, before.sha3 / 2);
+        assert_eq!(after.keccak, before.keccak / 2);
+
+        // Mining md5 again ends its decay but leaves the others relaxed.
+        mine(SupportedHashes::Md5, 0);
+        let mined = SharedDifficulty::threshold();
+        assert_eq!(mined.md5, SharedDifficulty::difficulty());
+        assert_eq!(mined.sha3, SharedDifficulty::difficulty() / 2);
+    });
+}
+
+#[test]
+fn decay_stops_at_the_minimum_difficulty() {
+    new_test_ext().execute_with(|| {
+        mine(SupportedHashes::Md5, TARGET);
+        Now::mutate(|now| *now += 1_000_000 * HOUR);
+
+        assert_eq!(SharedDifficulty::threshold().sha3, MIN_DIFFICULTY.into());
+    });
+}
+
+#[test]
+fn unseen_algorithms_do_not_decay() {
+    new_test_ext().execute_with(|| {
+        Now::set(&(10 * HOUR));
+
+        assert_eq!(
+            SharedDifficulty::threshold().keccak,
+            INITIAL_DIFFICULTY.into()
+        );
+    });
+}
+
+#[test]
+fn history_is_given_in_the_algorithms_terms() {
+    new_test_ext().execute_with(|| {
+        // Md5 mines two out of every three blocks.
+        for _ in 0..20 {
+            mine(SupportedHashes::Md5, TARGET / 2);
+            mine(SupportedHashes::Md5, TARGET / 2);
+            mine(SupportedHashes::Sha3, TARGET * 2);
+        }
+
+        let history = SharedDifficulty::history(SupportedHashes::Md5);
+        assert_eq!(history.window.len(), 60);
+        assert_eq!(
+            history.current_difficulty,
+            SharedDifficulty::threshold().md5
+        );
+        assert_eq!(history.initial_difficulty, INITIAL_DIFFICULTY.into());
+        assert!(
+            history.next_difficulty
+                > SharedDifficulty::history(SupportedHashes::Sha3).next_difficulty
+        );
+    });
+}
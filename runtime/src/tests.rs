@@ -0,0 +1,20 @@
+use frame_support::inherent::ProvideInherent;
+use sp_inherents::InherentData;
+
+use super::*;
+
+#[test]
+fn timestamp_drift_matches_the_timestamp_pallet() {
+    let now = 1_000_000u64;
+    let mut data = InherentData::new();
+    data.put_data(<Timestamp as ProvideInherent>::INHERENT_IDENTIFIER, &now)
+        .expect("Timestamps encode");
+
+    sp_io::TestExternalities::default().execute_with(|| {
+        let check =
+            |timestamp| Timestamp::check_inherent(&TimestampCall::set { now: timestamp }, &data);
+
+        assert!(check(now + MAX_TIMESTAMP_DRIFT_MILLIS).is_ok());
+        assert!(check(now + MAX_TIMESTAMP_DRIFT_MILLIS + 1).is_err());
+    });
+}
@@ -0,0 +1,338 @@
+//! This pallet lets block authors include recently orphaned blocks as uncles (aka ommers).
+//!
+//! When many miners compete, plenty of perfectly valid blocks lose the race and go stale.
+//! As in Ethereum, the author of a block may reference the headers of such stale blocks.
+//! The miner of each uncle is paid a portion of the block reward, and the author who
+//! includes it gets a small bonus on top of the regular reward.
+//!
+//! An uncle must be a sibling of one of the last few blocks in this chain. That is, its parent
+//! is in our chain, but the uncle itself is not. Its seal must be a valid multi-pow seal.
+//!
+//! The fork rules (which algorithms are allowed at which heights) are client-side configuration
+//! so they are not checked here.
+
+use frame_support::traits::Currency;
+use multi_pow::{check_seal, Seal, SupportedHashes, Threshold};
+pub use pallet::*;
+use parity_scale_codec::{Decode, Encode};
+use sp_consensus_pow::POW_ENGINE_ID;
+use sp_core::{H256, U256};
+#[cfg(feature = "std")]
+use sp_inherents::InherentData;
+use sp_inherents::InherentIdentifier;
+use sp_runtime::{
+    traits::{Header as HeaderT, Saturating},
+    Digest, DigestItem, Perbill, Percent,
+};
+use sp_std::vec::Vec;
+
+#[frame_support::pallet(dev_mode)]
+pub mod pallet {
+    use frame_support::pallet_prelude::*;
+    use frame_system::pallet_prelude::*;
+
+    use super::*;
+
+    /// The pallet's configuration trait.
+    #[pallet::config]
+    pub trait Config: frame_system::Config<Hash = H256> {
+        /// The overarching event type.
+        type RuntimeEvent: From<Event<Self>> + IsType<<Self as frame_system::Config>::RuntimeEvent>;
+
+        /// The currency in which uncle rewards are paid
+        type Currency: Currency<Self::AccountId>;
+
+        /// The current difficulty threshold for each hashing algorithm
+        type Difficulty: Get<Threshold>;
+
+        /// The reward a miner receives for a block. Uncle rewards are a fraction of this.
+        type BlockReward: Get<BalanceOf<Self>>;
+
+        /// How many generations back an uncle may branch off of this chain
+        type UncleGenerations: Get<u32>;
+
+        /// The maximum number of uncles a single block may include
+        type MaxUncles: Get<u32>;
+
+        /// The fraction of the block reward paid to the block author for each uncle they include
+        type InclusionReward: Get<Perbill>;
+
+        /// How far below the current difficulty an uncle's sealed difficulty may be.
+        /// Difficulty drifts a little from block to block, so an uncle that is a few blocks old
+        /// may legitimately have been mined at a slightly lower difficulty.
+        type DifficultyTolerance: Get<Percent>;
+    }
+
+    type BalanceOf<T> =
+        <<T as Config>::Currency as Currency<<T as frame_system::Config>::AccountId>>::Balance;
+
+    #[pallet::pallet]
+    pub struct Pallet<T>(_);
+
+    /// The hashes of the uncles included in the current block.
+    #[pallet::storage]
+    pub type Uncles<T: Config> = StorageValue<_, Vec<T::Hash>, OptionQuery>;
+
+    /// Uncles that have been included recently, along with their block numbers.
+    /// This prevents the same uncle from being rewarded twice.
+    /// Entries are pruned once they are too old to be included again anyway.
+    #[pallet::storage]
+    pub type RecentUncles<T: Config> =
+        StorageValue<_, Vec<(BlockNumberFor<T>, T::Hash)>, ValueQuery>;
+
+    #[pallet::event]
+    #[pallet::generate_deposit(pub(super) fn deposit_event)]
+    pub enum Event<T: Config> {
+        /// An uncle was included and its miner rewarded.
+        UncleIncluded {
+            hash: T::Hash,
+            miner: T::AccountId,
+            reward: BalanceOf<T>,
+        },
+    }
+
+    #[pallet::error]
+    pub enum Error<T> {
+        /// Uncles already set in block.
+        UnclesAlreadySet,
+        /// More uncles than `MaxUncles` were supplied.
+        TooManyUncles,
+        /// The uncle is not older than the block including it.
+        UncleTooNew,
+        /// The uncle branches off more than `UncleGenerations` blocks ago.
+        UncleTooOld,
+        /// The uncle's parent is not part of this chain.
+        UncleParentNotInChain,
+        /// The uncle is part of this chain, so it is an ancestor, not an uncle.
+        UncleIsAncestor,
+        /// The uncle has already been included.
+        DuplicateUncle,
+        /// The uncle has no PoW pre-digest identifying its miner and algorithm.
+        MissingPreDigest,
+        /// The uncle has no PoW seal, or the seal could not be decoded.
+        MissingSeal,
+        /// The uncle's seal is not a valid solution.
+        InvalidSeal,
+        /// The uncle was sealed at a difficulty too far below the current one.
+        DifficultyTooLow,
+    }
+
+    #[pallet::call]
+    impl<T: Config> Pallet<T> {
+        /// Inherent to include the headers of recently orphaned blocks
+        #[pallet::weight((1_000_000, DispatchClass::Mandatory))]
+        pub fn set_uncles(origin: OriginFor<T>, uncles: Vec<HeaderFor<T>>) -> DispatchResult {
+            ensure_none(origin)?;
+            ensure!(Uncles::<T>::get().is_none(), Error::<T>::UnclesAlreadySet);
+            ensure!(
+                uncles.len() <= T::MaxUncles::get() as usize,
+                Error::<T>::TooManyUncles
+            );
+
+            let author =
+                Self::decode_pre_digest(&frame_system::Pallet::<T>::digest()).map(|(a, _)| a);
+            let block_reward = T::BlockReward::get();
+            let mut recent = RecentUncles::<T>::get();
+            let mut included = Vec::with_capacity(uncles.len());
+
+            for uncle in uncles.iter() {
+                let (hash, miner, depth) = Self::verify_uncle(uncle, &recent)?;
+
+                // As in Ethereum, the closer the uncle is to the including block
+                // the larger the share of the block reward its miner receives.
+                let generations = T::UncleGenerations::get() + 1;
+                let reward =
+                    Perbill::from_rational(generations - depth, generations) * block_reward;
+                let _ = T::Currency::deposit_creating(&miner, reward);
+
+                if let Some(author) = &author {
+                    let _ = T::Currency::deposit_creating(
+                        author,
+                        T::InclusionReward::get() * block_reward,
+                    );
+                }
+
+                recent.push((*uncle.number(), hash));
+                included.push(hash);
+                Self::deposit_event(Event::UncleIncluded {
+                    hash,
+                    miner,
+                    reward,
+                });
+            }
+
+            RecentUncles::<T>::put(recent);
+            Uncles::<T>::put(included);
+
+            Ok(())
+        }
+    }
+
+    #[pallet::hooks]
+    impl<T: Config> Hooks<BlockNumberFor<T>> for Pallet<T> {
+        fn on_initialize(n: BlockNumberFor<T>) -> Weight {
+            // Reset the uncles to None at the beginning of the block
+            Uncles::<T>::kill();
+
+            // Forget about uncles that are too old to be included again
+            let oldest = n.saturating_sub(T::UncleGenerations::get().into());
+            RecentUncles::<T>::mutate(|recent| recent.retain(|(number, _)| *number >= oldest));
+
+            Weight::zero()
+        }
+    }
+
+    #[pallet::inherent]
+    impl<T: Config> ProvideInherent for Pallet<T> {
+        type Call = Call<T>;
+        type Error = sp_inherents::MakeFatalError<()>;
+        const INHERENT_IDENTIFIER: InherentIdentifier = INHERENT_IDENTIFIER;
+
+        fn create_inherent(data: &InherentData) -> Option<Self::Call> {
+            // The node supplies every stale sibling it knows about. Here we keep only
+            // the ones that would actually be accepted, so a bad candidate never
+            // invalidates the block.
+            let candidates = data
+                .get_data::<InherentType<HeaderFor<T>>>(&INHERENT_IDENTIFIER)
+                .ok()
+                .flatten()?;
+
+            let mut recent = RecentUncles::<T>::get();
+            let mut uncles = Vec::new();
+            for candidate in candidates {
+                if uncles.len() >= T::MaxUncles::get() as usize {
+                    break;
+                }
+                if let Ok((hash, _, _)) = Self::verify_uncle(&candidate, &recent) {
+                    recent.push((*candidate.number(), hash));
+                    uncles.push(candidate);
+                }
+            }
+
+            if uncles.is_empty() {
+                None
+            } else {
+                Some(Call::set_uncles { uncles })
+            }
+        }
+
+        fn is_inherent(call: &Self::Call) -> bool {
+            matches!(call, Call::set_uncles { .. })
+        }
+    }
+
+    impl<T: Config> Pallet<T> {
+        /// Check that the given header is a valid uncle for the current block.
+        ///
+        /// Returns the uncle's hash, the account that mined it, and how many
+        /// generations older than the current block it is.
+        pub fn verify_uncle(
+            uncle: &HeaderFor<T>,
+            recent: &[(BlockNumberFor<T>, T::Hash)],
+        ) -> Result<(T::Hash, T::AccountId, u32), Error<T>> {
+            let now = frame_system::Pallet::<T>::block_number();
+            let number = *uncle.number();
+            ensure!(number < now, Error::<T>::UncleTooNew);
+
+            let depth: u32 = (now - number)
+                .try_into()
+                .map_err(|_| Error::<T>::UncleTooOld)?;
+            ensure!(depth <= T::UncleGenerations::get(), Error::<T>::UncleTooOld);
+
+            // The uncle must branch off of our chain, but not be part of it.
+            let parent_number = number.saturating_sub(1u32.into());
+            ensure!(
+                number > 0u32.into()
+                    && frame_system::Pallet::<T>::block_hash(parent_number) == *uncle.parent_hash(),
+                Error::<T>::UncleParentNotInChain
+            );
+            let hash = uncle.hash();
+            ensure!(
+                frame_system::Pallet::<T>::block_hash(number) != hash,
+                Error::<T>::UncleIsAncestor
+            );
+            ensure!(
+                !recent.iter().any(|(_, h)| *h == hash),
+                Error::<T>::DuplicateUncle
+            );
+
+            let (miner, algo) =
+                Self::decode_pre_digest(uncle.digest()).ok_or(Error::<T>::MissingPreDigest)?;
+
+            // The seal is the last digest item, and the pre-hash is the hash of the header without it.
+            let mut pre_header = uncle.clone();
+            let seal = match pre_header.digest_mut().pop() {
+                Some(DigestItem::Seal(POW_ENGINE_ID, seal)) => {
+                    Seal::decode(&mut &seal[..]).map_err(|_| Error::<T>::MissingSeal)?
+                }
+                _ => return Err(Error::<T>::MissingSeal),
+            };
+            let pre_hash = pre_header.hash();
+
+            // The seal carries the difficulty it was mined at. Make sure that is not
+            // far below what we require of blocks today.
+            let current = T::Difficulty::get().for_algo(algo);
+            let minimum =
+                current / U256::from(100) * U256::from(T::DifficultyTolerance::get().deconstruct());
+            ensure!(
+                seal.difficulty.for_algo(algo) >= minimum,
+                Error::<T>::DifficultyTooLow
+            );
+
+            check_seal(&pre_hash, algo, &seal, seal.difficulty)
+                .map_err(|_| Error::<T>::InvalidSeal)?;
+
+            Ok((hash, miner, depth))
+        }
+
+        /// Find and decode the PoW pre-runtime digest, which names the miner and the algorithm.
+        fn decode_pre_digest(digest: &Digest) -> Option<(T::AccountId, SupportedHashes)> {
+            digest.logs.iter().find_map(|item| match item {
+                DigestItem::PreRuntime(POW_ENGINE_ID, pre_digest) => {
+                    <(T::AccountId, SupportedHashes)>::decode(&mut &pre_digest[..]).ok()
+                }
+                _ => None,
+            })
+        }
+    }
+}
+
+pub const INHERENT_IDENTIFIER: InherentIdentifier = *b"uncles__";
+
+/// The type of data that the inherent will contain.
+/// Candidate uncle headers. The runtime decides which of them to actually include.
+pub type InherentType<Header> = Vec<Header>;
+
+#[cfg(feature = "std")]
+pub struct InherentDataProvider<Header>(pub InherentType<Header>);
+
+#[cfg(feature = "std")]
+#[async_trait::async_trait]
+impl<Header: Encode + Send + Sync> sp_inherents::InherentDataProvider
+    for InherentDataProvider<Header>
+{
+    async fn provide_inherent_data(
+        &self,
+        inherent_data: &mut InherentData,
+    ) -> Result<(), sp_inherents::Error> {
+        inherent_data.put_data(INHERENT_IDENTIFIER, &self.0)
+    }
+
+    async fn try_handle_error(
+        &self,
+        identifier: &InherentIdentifier,
+        _error: &[u8],
+    ) -> Option<Result<(), sp_inherents::Error>> {
+        // Dont' process modules from other inherents
+        if *identifier != INHERENT_IDENTIFIER {
+            return None;
+        }
+
+        Some(Err(sp_inherents::Error::Application(Box::from(
+            String::from("Error processing uncles inherent"),
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests;
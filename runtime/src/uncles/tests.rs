@@ -0,0 +1,234 @@
+use frame_support::{
+    derive_impl,
+    inherent::ProvideInherent,
+    parameter_types,
+    traits::{ConstU64, Hooks},
+};
+use multi_pow::{multi_hash_meets_difficulty, Compute};
+use sp_runtime::{BuildStorage, DispatchResult};
+
+use super::*;
+use crate::uncles as pallet_uncles;
+
+type Block = frame_system::mocking::MockBlock<Test>;
+type Header = frame_system::pallet_prelude::HeaderFor<Test>;
+
+frame_support::construct_runtime!(
+    pub enum Test {
+        System: frame_system,
+        Balances: pallet_balances,
+        Uncles: pallet_uncles,
+    }
+);
+
+#[derive_impl(frame_system::config_preludes::TestDefaultConfig as frame_system::DefaultConfig)]
+impl frame_system::Config for Test {
+    type Block = Block;
+    type AccountData = pallet_balances::AccountData<u64>;
+}
+
+impl pallet_balances::Config for Test {
+    type MaxLocks = ();
+    type MaxReserves = ();
+    type ReserveIdentifier = [u8; 8];
+    type Balance = u64;
+    type RuntimeEvent = RuntimeEvent;
+    type DustRemoval = ();
+    type ExistentialDeposit = ConstU64<1>;
+    type AccountStore = System;
+    type WeightInfo = ();
+    type FreezeIdentifier = ();
+    type MaxFreezes = ();
+    type MaxHolds = ();
+    type RuntimeHoldReason = RuntimeHoldReason;
+    type RuntimeFreezeReason = RuntimeFreezeReason;
+}
+
+parameter_types! {
+    // Low enough that a seal is found within a few tries.
+    pub MockDifficulty: Threshold = Threshold {
+        md5: 4.into(),
+        sha3: 4.into(),
+        keccak: 4.into(),
+    };
+    pub const MockBlockReward: u64 = 400;
+    pub const MockUncleGenerations: u32 = 3;
+    pub const MockMaxUncles: u32 = 2;
+    pub MockInclusionReward: Perbill = Perbill::from_percent(10);
+    pub const MockDifficultyTolerance: Percent = Percent::from_percent(50);
+}
+
+impl Config for Test {
+    type RuntimeEvent = RuntimeEvent;
+    type Currency = Balances;
+    type Difficulty = MockDifficulty;
+    type BlockReward = MockBlockReward;
+    type UncleGenerations = MockUncleGenerations;
+    type MaxUncles = MockMaxUncles;
+    type InclusionReward = MockInclusionReward;
+    type DifficultyTolerance = MockDifficultyTolerance;
+}
+
+const AUTHOR: u64 = 1;
+const MINER: u64 = 2;
+
+/// A chain whose current block is `now`, authored by `AUTHOR`.
+fn new_test_ext(now: u64) -> sp_io::TestExternalities {
+    let storage = frame_system::GenesisConfig::<Test>::default()
+        .build_storage()
+        .expect("The mock genesis builds");
+    let mut ext = sp_io::TestExternalities::new(storage);
+    ext.execute_with(|| {
+        for number in 0..now {
+            frame_system::BlockHash::<Test>::insert(number, H256::repeat_byte(number as u8 + 1));
+        }
+        System::set_block_number(now);
+        System::deposit_log(DigestItem::PreRuntime(
+            POW_ENGINE_ID,
+            (AUTHOR, SupportedHashes::Md5).encode(),
+        ));
+    });
+    ext
+}
+
+/// Mine a sibling of block `number` of the current chain.
+fn uncle(number: u64) -> Header {
+    let mut header = Header::new(
+        number,
+        Default::default(),
+        Default::default(),
+        System::block_hash(number - 1),
+        Default::default(),
+    );
+    header.digest_mut().push(DigestItem::PreRuntime(
+        POW_ENGINE_ID,
+        (MINER, SupportedHashes::Md5).encode(),
+    ));
+
+    let difficulty = MockDifficulty::get();
+    let pre_hash = header.hash();
+    let seal = (0u64..)
+        .map(|nonce| {
+            Compute {
+                difficulty,
+                pre_hash,
+                nonce: nonce.into(),
+                extra_nonce: 0,
+            }
+            .compute(SupportedHashes::Md5)
+        })
+        .find(|seal| multi_hash_meets_difficulty(&seal.work, difficulty))
+        .expect("Some nonce meets such a low difficulty");
+    header
+        .digest_mut()
+        .push(DigestItem::Seal(POW_ENGINE_ID, seal.encode()));
+
+    header
+}
+
+fn include(uncles: Vec<Header>) -> DispatchResult {
+    Uncles::set_uncles(RuntimeOrigin::none(), uncles)
+}
+
+#[test]
+fn including_an_uncle_rewards_its_miner_and_the_author() {
+    new_test_ext(3).execute_with(|| {
+        let uncle = uncle(2);
+        assert_eq!(include(vec![uncle.clone()]), Ok(()));
+
+        // One generation old, so the miner gets 3/4 of the reward.
+        assert_eq!(Balances::free_balance(MINER), 300);
+        assert_eq!(Balances::free_balance(AUTHOR), 40);
+        System::assert_has_event(
+            Event::<Test>::UncleIncluded {
+                hash: uncle.hash(),
+                miner: MINER,
+                reward: 300,
+            }
+            .into(),
+        );
+    });
+}
+
+#[test]
+fn older_uncles_earn_less() {
+    new_test_ext(4).execute_with(|| {
+        assert_eq!(include(vec![uncle(2)]), Ok(()));
+
+        assert_eq!(Balances::free_balance(MINER), 200);
+    });
+}
+
+#[test]
+fn uncles_are_rewarded_only_once() {
+    new_test_ext(3).execute_with(|| {
+        let uncle = uncle(2);
+        assert_eq!(include(vec![uncle.clone()]), Ok(()));
+
+        System::set_block_number(4);
+        Uncles::on_initialize(4);
+        assert_eq!(
+            include(vec![uncle]),
+            Err(Error::<Test>::DuplicateUncle.into())
+        );
+    });
+}
+
+#[test]
+fn uncles_must_branch_off_recently() {
+    new_test_ext(6).execute_with(|| {
+        assert_eq!(
+            include(vec![uncle(2)]),
+            Err(Error::<Test>::UncleTooOld.into())
+        );
+    });
+}
+
+#[test]
+fn uncles_must_branch_off_this_chain() {
+    new_test_ext(3).execute_with(|| {
+        let mut stranger = uncle(2);
+        stranger.set_parent_hash(H256::repeat_byte(42));
+        assert_eq!(
+            include(vec![stranger]),
+            Err(Error::<Test>::UncleParentNotInChain.into())
+        );
+
+        let ancestor = uncle(2);
+        frame_system::BlockHash::<Test>::insert(2, ancestor.hash());
+        assert_eq!(
+            include(vec![ancestor]),
+            Err(Error::<Test>::UncleIsAncestor.into())
+        );
+    });
+}
+
+#[test]
+fn uncles_need_a_valid_seal() {
+    new_test_ext(3).execute_with(|| {
+        let mut tampered = uncle(2);
+        tampered.set_state_root(H256::repeat_byte(42));
+
+        assert_eq!(
+            include(vec![tampered]),
+            Err(Error::<Test>::InvalidSeal.into())
+        );
+    });
+}
+
+#[test]
+fn the_inherent_only_includes_valid_candidates() {
+    new_test_ext(6).execute_with(|| {
+        let valid = uncle(4);
+        let mut data = InherentData::new();
+        data.put_data(INHERENT_IDENTIFIER, &vec![uncle(1), valid.clone()])
+            .expect("Uncles encode");
+
+        assert_eq!(
+            Uncles::create_inherent(&data),
+            Some(Call::set_uncles {
+                uncles: vec![valid]
+            })
+        );
+    });
+}